@@ -0,0 +1,193 @@
+//! Members of zip and tar archives, indexed as virtual files (`scan --archives`).
+//!
+//! A member is stored under the path of its archive, `!/` and its name in the
+//! archive, like `/data/a.zip!/DCIM/1.jpg`. Virtual files can be found as
+//! duplicates, but never deleted.
+
+use crate::hashing::{self, StreamHashes};
+use crate::path_utils;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path};
+
+pub const SEPARATOR: &str = "!/";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// The kind of archive `path` is, going by its name.
+pub fn kind_of(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// The ways `path` can be split into an archive path and a member name,
+/// shortest archive path first.
+///
+/// The path alone does not tell: a real file can be under a directory named
+/// like `a.zip!`, and a member name can have `!/` in it. Whether a path is a
+/// member is recorded with it (`FileMeta::member`).
+pub fn archive_prefixes(path: &str) -> impl Iterator<Item = (&str, &str)> {
+    path.match_indices(SEPARATOR).filter_map(|(i, _)| {
+        let archive = &path[..i];
+        kind_of(Path::new(archive)).map(|_| (archive, &path[i + SEPARATOR.len()..]))
+    })
+}
+
+/// Split the path of an archive member into the path of its archive, the
+/// first prefix that `is_archive` accepts, and the member name.
+pub fn split_virtual(path: &str, is_archive: impl Fn(&str) -> bool) -> Option<(&str, &str)> {
+    archive_prefixes(path).find(|(a, _)| is_archive(a))
+}
+
+pub fn virtual_path(archive: &str, member: &str) -> String {
+    format!("{archive}{SEPARATOR}{member}")
+}
+
+/// The path prefix shared by all members of `archive`.
+pub fn member_prefix(archive: &str) -> String {
+    format!("{archive}{SEPARATOR}")
+}
+
+/// Hash each regular file in the archive, and pass it to `on_member` with its
+/// name. Members that cannot be read are passed with the error, and skipped.
+///
+/// Symlinks, directories and members with names that would escape the
+/// archive (absolute, or with `..`) are left out. Archives inside the
+/// archive are hashed as files, but not opened.
+pub fn hash_members(
+    path: &Path,
+    kind: ArchiveKind,
+    mut on_member: impl FnMut(String, Result<StreamHashes>),
+) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(file))
+                .with_context(|| format!("read zip directory of {}", path.display()))?;
+            for i in 0..zip.len() {
+                let mut member = match zip.by_index(i) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        on_member(format!("#{i}"), Err(e.into()));
+                        continue;
+                    }
+                };
+                if !member.is_file() {
+                    continue;
+                }
+                let Some(name) = member.enclosed_name().and_then(|n| member_name(&n)) else {
+                    continue;
+                };
+                let r = hashing::hash_reader(&mut member).with_context(|| format!("read member {name}"));
+                on_member(name, r);
+            }
+        }
+        ArchiveKind::Tar => hash_tar_members(BufReader::new(file), &mut on_member)
+            .with_context(|| format!("read tar {}", path.display()))?,
+        ArchiveKind::TarGz => {
+            let gz = flate2::read::GzDecoder::new(BufReader::new(file));
+            hash_tar_members(gz, &mut on_member).with_context(|| format!("read tar.gz {}", path.display()))?
+        }
+    }
+    Ok(())
+}
+
+/// A tar is read front to back, so an error in one member ends the archive.
+fn hash_tar_members(r: impl Read, on_member: &mut impl FnMut(String, Result<StreamHashes>)) -> Result<()> {
+    let mut tar = tar::Archive::new(r);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Some(name) = member_name(&entry.path()?) else {
+            continue;
+        };
+        let hashes = hashing::hash_reader(&mut entry).with_context(|| format!("read member {name}"))?;
+        on_member(name, Ok(hashes));
+    }
+    Ok(())
+}
+
+/// The member's name as used in its virtual path, or None if it is not a
+/// plain relative path. Names are escaped like the paths of files.
+fn member_name(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(p) => parts.push(path_utils::path_to_string(Path::new(p))),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_prefixes_shortest_first() {
+        let p = "/d/backup.zip!/x/a.tar.gz!/m.txt";
+        let got: Vec<_> = archive_prefixes(p).collect();
+        assert_eq!(
+            got,
+            [("/d/backup.zip", "x/a.tar.gz!/m.txt"), ("/d/backup.zip!/x/a.tar.gz", "m.txt")]
+        );
+        assert_eq!(archive_prefixes("/d/notes!/m.txt").count(), 0);
+        assert_eq!(archive_prefixes("/d/a.zip").count(), 0);
+    }
+
+    #[test]
+    fn split_virtual_takes_the_indexed_archive() {
+        // A real archive under a directory named like a member prefix
+        let p = "/d/backup.zip!/a.tgz!/m.txt";
+        assert_eq!(
+            split_virtual(p, |a| a == "/d/backup.zip!/a.tgz"),
+            Some(("/d/backup.zip!/a.tgz", "m.txt"))
+        );
+        // A member with `!/` in its name
+        assert_eq!(split_virtual(p, |a| a == "/d/backup.zip"), Some(("/d/backup.zip", "a.tgz!/m.txt")));
+        assert_eq!(split_virtual(p, |_| false), None);
+    }
+
+    #[test]
+    fn virtual_paths_split_back() {
+        let v = virtual_path("/d/a.zip", "DCIM/1.jpg");
+        assert!(v.starts_with(&member_prefix("/d/a.zip")));
+        assert_eq!(split_virtual(&v, |a| a == "/d/a.zip"), Some(("/d/a.zip", "DCIM/1.jpg")));
+    }
+
+    #[test]
+    fn kind_of_goes_by_name() {
+        assert_eq!(kind_of(Path::new("/d/A.ZIP")), Some(ArchiveKind::Zip));
+        assert_eq!(kind_of(Path::new("/d/a.tar")), Some(ArchiveKind::Tar));
+        assert_eq!(kind_of(Path::new("/d/a.tar.gz")), Some(ArchiveKind::TarGz));
+        assert_eq!(kind_of(Path::new("/d/a.tgz")), Some(ArchiveKind::TarGz));
+        assert_eq!(kind_of(Path::new("/d/a.gz")), None);
+    }
+
+    #[test]
+    fn member_names_stay_inside_the_archive() {
+        assert_eq!(member_name(Path::new("./DCIM/1.jpg")).as_deref(), Some("DCIM/1.jpg"));
+        assert_eq!(member_name(Path::new("../etc/passwd")), None);
+        assert_eq!(member_name(Path::new("/etc/passwd")), None);
+        assert_eq!(member_name(Path::new(".")), None);
+    }
+}
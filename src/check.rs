@@ -1,17 +1,18 @@
-use crate::db::DbHandle;
-use crate::file_meta::FileState;
+use crate::db::{CurrentByPath, DbHandle};
+use crate::file_meta::{FileMeta, FileStat, FileState};
 use crate::hashing;
 use crate::path_utils;
 use anyhow::{Context, Result};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
 use crate::types::Hash256;
-use chrono::{DateTime, Local, TimeZone};
+use crate::util::format_timestamp;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum Status {
     Exists,
     KnownRemoved,
+    /// Gone from this path, but found at another one by a later scan
+    Moved,
     NotFound,
 }
 
@@ -28,9 +29,10 @@ pub fn run_check(db: &DbHandle, paths: &[PathBuf], quiet: bool) -> Result<()> {
             let token = match st {
                 Status::Exists => "EXISTS",
                 Status::KnownRemoved => "KNOWN_REMOVED",
+                Status::Moved => "MOVED",
                 Status::NotFound => "NOT_FOUND",
             };
-            println!("{token} {}", p.display());
+            println!("{token} {}", path_utils::path_to_string(p));
         } else {
             println!();
         }
@@ -42,12 +44,19 @@ pub fn run_check(db: &DbHandle, paths: &[PathBuf], quiet: bool) -> Result<()> {
 fn check_one(db: &DbHandle, input_path: &Path, quiet: bool) -> Result<Status> {
     let norm = path_utils::normalize_path(input_path)
     .with_context(|| format!("Failed to normalize path: {}", input_path.display()))?;
-    let norm_s = norm.to_string_lossy();
+    let norm_s = path_utils::path_to_string(&norm);
 
     if !quiet {
         println!("PATH {}", norm_s);
     }
 
+    // A file inside an archive is only known from the DB
+    if let Some(cur) = db.get_current_by_path(&norm_s)?
+        && cur.meta.member
+    {
+        return check_member(db, &cur, quiet);
+    }
+
     // Try disk metadata first. If disk is missing/unreadable, we can still report
     // whether the path is known in DB, and if it is marked Missing.
     let md = match std::fs::metadata(&norm) {
@@ -61,10 +70,24 @@ fn check_one(db: &DbHandle, input_path: &Path, quiet: bool) -> Result<Status> {
                 if !quiet {
                     println!(
                         "  DB   found current: file_id={} state={:?} size={} mtime={}",
-                        cur.file_id, cur.state, cur.meta.size, format_mtime(cur.meta.mtime_secs)
+                        cur.file_id, cur.state, size_text(&cur.meta), format_timestamp(cur.meta.mtime_secs)
                     );
                 }
 
+                if cur.state == FileState::Missing
+                    && let Some(to) = last_move(db, cur.file_id)?
+                {
+                    if !quiet {
+                        println!("  MOVED_TO {to}");
+                        println!("  RESULT MOVED (DB knows it was moved or renamed)");
+                    }
+                    return Ok(Status::Moved);
+                }
+
+                if cur.state == FileState::Live && cur.meta.catalog {
+                    return check_catalog(db, &cur, quiet);
+                }
+
                 // "known removed" via path knowledge
                 if cur.state == FileState::Missing {
                     if !quiet {
@@ -97,37 +120,48 @@ fn check_one(db: &DbHandle, input_path: &Path, quiet: bool) -> Result<Status> {
         return Ok(Status::NotFound);
     }
 
-    let size = md.len();
-    let mtime = crate::codec::systemtime_to_unix_secs(md.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+    let stat = FileStat::from_metadata(&md);
 
     if !quiet {
-        println!("  DISK size={} mtime={}", size, format_mtime(mtime));
+        println!("  DISK size={} mtime={}", stat.size, format_timestamp(stat.mtime_secs));
     }
 
-    // 1) Try direct path->current match, then compare (size,mtime)
+    // 1) Try direct path->current match, then compare stat() identity
     if let Some(cur) = db.get_current_by_path(&norm_s)? {
         if !quiet {
             println!(
                 "  DB   found current: file_id={} state={:?} size={} mtime={}",
-                cur.file_id, cur.state, cur.meta.size, format_mtime(cur.meta.mtime_secs)
+                cur.file_id, cur.state, size_text(&cur.meta), format_timestamp(cur.meta.mtime_secs)
             );
         }
 
-        if cur.state == FileState::Live && cur.meta.size == size && cur.meta.mtime_secs == mtime {
+        if !quiet && let Some((_, from)) = db.moved_from(cur.file_id)? {
+            println!("  MOVED_FROM {from}");
+        }
+
+        if cur.state == FileState::Live && cur.meta.matches_stat(&stat) {
             // Matched identity — we know the sha without hashing.
             if !quiet {
-                println!("  RESULT SAME (matched by path + (size,mtime))");
-                println!("  Blake256 {}", hex::encode(cur.meta.hash256));
+                println!("  RESULT SAME (matched by path + stat identity)");
             }
 
             // Always show duplicates list (unless quiet)
             if !quiet {
-                print_dupes_for_sha(db, &cur.meta.hash256, Some(cur.file_id))?;
+                let hash256 = match cur.meta.hash256 {
+                    Some(h) => h,
+                    None => {
+                        println!("  HASH_PENDING (no other file had this size when scanned)");
+                        hashing::hash_full_hash256(&norm)
+                        .with_context(|| format!("Failed to hash {}", norm_s))?
+                    }
+                };
+                println!("  Blake256 {}", hex::encode(hash256));
+                print_dupes_for_sha(db, &hash256, Some(stat.size), Some(cur.file_id))?;
             }
 
             return Ok(Status::Exists);
         } else if !quiet {
-            println!("  RESULT DIFF (path exists but (size,mtime) differs or not Live) -> hashing");
+            println!("  RESULT DIFF (path exists but stat identity differs or not Live) -> hashing");
         }
     } else if !quiet {
         println!("  DB   no current entry for this path -> hashing");
@@ -143,7 +177,8 @@ fn check_one(db: &DbHandle, input_path: &Path, quiet: bool) -> Result<Status> {
         println!("  Blake256 {}", sha_hex);
     }
 
-    let entries = db.lookup_files_by_hash256(&hash256)?;
+    let mut entries = db.lookup_files_by_hash256(&hash256)?;
+    entries.extend(pending_matches(db, stat.size, &hash256)?);
 
     if entries.is_empty() {
         if !quiet {
@@ -172,12 +207,94 @@ fn check_one(db: &DbHandle, input_path: &Path, quiet: bool) -> Result<Status> {
     }
 }
 
-fn print_dupes_for_sha(db: &DbHandle, hash256: &Hash256, exclude_file_id: Option<u64>) -> Result<()> {
-    let entries = db.lookup_files_by_hash256(&hash256)?;
+/// Where a moved file ended up, following later moves.
+fn last_move(db: &DbHandle, file_id: u64) -> Result<Option<String>> {
+    let mut last = None;
+    let mut fid = file_id;
+    // A file can be moved back to where it was; stop going in circles
+    for _ in 0..64 {
+        let Some((next, path)) = db.moved_to(fid)? else {
+            break;
+        };
+        last = Some(path);
+        fid = next;
+    }
+    Ok(last)
+}
+
+fn check_member(db: &DbHandle, cur: &CurrentByPath, quiet: bool) -> Result<Status> {
+    if !quiet {
+        println!(
+            "  DB   found current: file_id={} state={:?} size={} mtime={}",
+            cur.file_id, cur.state, cur.meta.size, format_timestamp(cur.meta.mtime_secs)
+        );
+    }
+    if cur.state == FileState::Missing {
+        if !quiet {
+            println!("  RESULT KNOWN_MISSING_BY_PATH (DB knows it was removed)");
+        }
+        return Ok(Status::KnownRemoved);
+    }
+
+    if !quiet {
+        println!("  RESULT ARCHIVE_MEMBER (as of the last scan of the archive)");
+        if let Some(hash256) = cur.meta.hash256 {
+            println!("  Blake256 {}", hex::encode(hash256));
+            print_dupes_for_sha(db, &hash256, cur.meta.known_size(), Some(cur.file_id))?;
+        }
+    }
+    Ok(Status::Exists)
+}
+
+/// A path imported with `import-manifest`, on a drive that is not here.
+fn check_catalog(db: &DbHandle, cur: &CurrentByPath, quiet: bool) -> Result<Status> {
+    if !quiet {
+        println!("  RESULT CATALOG (imported from a manifest)");
+        if let Some(hash256) = cur.meta.hash256 {
+            println!("  Blake256 {}", hex::encode(hash256));
+            print_dupes_for_sha(db, &hash256, cur.meta.known_size(), Some(cur.file_id))?;
+        }
+    }
+    Ok(Status::Exists)
+}
+
+/// With the size, files of that size with a pending hash are hashed too.
+fn print_dupes_for_sha(db: &DbHandle, hash256: &Hash256, size: Option<u64>, exclude_file_id: Option<u64>) -> Result<()> {
+    let mut entries = db.lookup_files_by_hash256(hash256)?;
+    if let Some(size) = size {
+        entries.extend(pending_matches(db, size, hash256)?);
+    }
     print_hash_peers(&entries, exclude_file_id);
     Ok(())
 }
 
+/// Live files of this size whose hash is pending, and which turn out to have
+/// this hash when hashed now. Nothing is written to the DB.
+fn pending_matches(db: &DbHandle, size: u64, hash256: &Hash256) -> Result<Vec<crate::db::ShaEntry>> {
+    let mut out = Vec::new();
+    for e in db.lookup_live_by_size(size)? {
+        if e.meta.hash256.is_some() {
+            continue;
+        }
+
+        // Only if the file is still what was recorded
+        let path = path_utils::path_from_string(&e.path);
+        let Ok(md) = std::fs::metadata(&path) else {
+            continue;
+        };
+        if !e.meta.matches_stat(&FileStat::from_metadata(&md)) {
+            continue;
+        }
+
+        if let Ok(h) = hashing::hash_full_hash256(&path)
+            && h == *hash256
+        {
+            out.push(e);
+        }
+    }
+    Ok(out)
+}
+
 pub fn run_check_hashes(db: &DbHandle, inputs: &[String], quiet: bool) -> Result<()> {
     if inputs.is_empty() {
         anyhow::bail!("check-hash requires at least one hash");
@@ -193,6 +310,7 @@ pub fn run_check_hashes(db: &DbHandle, inputs: &[String], quiet: bool) -> Result
             let token = match st {
                 Status::Exists => "EXISTS",
                 Status::KnownRemoved => "KNOWN_REMOVED",
+                Status::Moved => "MOVED",
                 Status::NotFound => "NOT_FOUND",
             };
             // keep the original token (first field) for traceability
@@ -242,7 +360,7 @@ fn check_by_sha(db: &DbHandle, hash256: &Hash256, sha_hex: &str, quiet: bool) ->
 /// - "64hex  filename"
 /// - "64hex *filename"
 /// - (any extra whitespace)
-fn parse_blake256sum_line(s: &str) -> Result<(Hash256, String)> {
+pub fn parse_blake256sum_line(s: &str) -> Result<(Hash256, String)> {
     let first = s
         .split_whitespace()
         .next()
@@ -283,7 +401,7 @@ fn decode_hex_32(hex: &str, out: &mut [u8; 32]) -> Result<()> {
 fn print_hash_peers(entries: &[crate::db::ShaEntry], exclude_file_id: Option<u64>) {
     let mut peers: Vec<_> = entries
         .iter()
-        .filter(|e| exclude_file_id.map_or(true, |id| e.file_id != id))
+        .filter(|e| exclude_file_id != Some(e.file_id))
         .cloned()
         .collect();
 
@@ -302,35 +420,32 @@ fn print_hash_peers(entries: &[crate::db::ShaEntry], exclude_file_id: Option<u64
         // (Because the original is also Live, that means >=2 live in total.)
         println!("  DUPES ({} other live, {} other total)", live, peers.len());
         for e in &peers {
-            println!(
-                "    [{:?}] file_id={} size={} mtime={} path={}",
-                e.state, e.file_id, e.meta.size, format_mtime(e.meta.mtime_secs), e.path
-            );
+            print_peer(e);
         }
     } else {
         println!("  UNIQUE ({} historical entry/entries)", peers.len());
         for e in &peers {
-            println!(
-                "    [{:?}] file_id={} size={} mtime={} path={}",
-                e.state, e.file_id, e.meta.size, format_mtime(e.meta.mtime_secs), e.path
-            );
+            print_peer(e);
         }
     }
 }
 
-fn format_mtime(mtime_secs: u64) -> String {
-    // Clamp invalid values defensively
-    let secs = i64::try_from(mtime_secs).unwrap_or(0);
-
-    let epoch = Local
-        .timestamp_opt(0, 0)
-        .single()
-        .expect("Local epoch timestamp should be valid");
-
-    let dt: DateTime<Local> = Local
-        .timestamp_opt(secs, 0)
-        .single()
-        .unwrap_or(epoch);
+fn print_peer(e: &crate::db::ShaEntry) {
+    if e.meta.catalog {
+        // Only the hash (and maybe the size) is known
+        println!(
+            "    [{:?}] file_id={} size={} catalog path={}",
+            e.state, e.file_id, size_text(&e.meta), e.path
+        );
+    } else {
+        println!(
+            "    [{:?}] file_id={} size={} mtime={} path={}",
+            e.state, e.file_id, e.meta.size, format_timestamp(e.meta.mtime_secs), e.path
+        );
+    }
+}
 
-    dt.format("%Y-%m-%d %H:%M:%S").to_string()
-}
\ No newline at end of file
+/// The size, or `?` for a catalog entry imported without one.
+fn size_text(meta: &FileMeta) -> String {
+    meta.known_size().map_or_else(|| "?".to_string(), |s| s.to_string())
+}
@@ -0,0 +1,168 @@
+use crate::codec::{str_pack_into, str_unpack};
+use crate::exclude::ExcludeSpec;
+use crate::path_utils;
+use anyhow::{anyhow, Result};
+
+/// Persisted progress of an unfinished scan, so `scan --resume` can pick up
+/// where it stopped.
+///
+/// Roots are walked in order, and each root is walked in sorted order, so
+/// "everything up to `frontier`" is a well-defined part of the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanCheckpoint {
+    /// The session being resumed; roots and flags are taken from it.
+    pub session_id: u64,
+    pub excludes: ExcludeSpec,
+    /// Number of roots (in session order) that were fully walked and had
+    /// missing-file detection applied.
+    pub roots_done: u32,
+    /// Last file handled in root `roots_done`, if the walk of that root started.
+    pub frontier: Option<String>,
+    /// Files that were enqueued for hashing but not yet written.
+    pub pending: Vec<String>,
+}
+
+impl ScanCheckpoint {
+    /// Encode to a stable on-disk format.
+    ///
+    /// Format v1:
+    /// [0]      u8  version = 1
+    /// [1]      u8  flags (bit0 = has_frontier)
+    /// [2..10]  u64 session_id LE
+    /// [10..14] u32 roots_done LE
+    /// then     frontier (length-prefixed, only if has_frontier)
+    /// then     five string lists, each a u32 count LE followed by length-prefixed
+    ///          strings: excludes, includes, exclude_files, always_excluded, pending
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + self.pending.iter().map(|p| p.len() + 4).sum::<usize>());
+        out.push(1u8);
+        out.push(u8::from(self.frontier.is_some()));
+        out.extend_from_slice(&self.session_id.to_le_bytes());
+        out.extend_from_slice(&self.roots_done.to_le_bytes());
+
+        if let Some(f) = &self.frontier {
+            str_pack_into(&mut out, f);
+        }
+
+        let ex = &self.excludes;
+        put_list(&mut out, ex.excludes.iter().map(String::as_str));
+        put_list(&mut out, ex.includes.iter().map(String::as_str));
+        let files: Vec<String> = ex.exclude_files.iter().map(|p| path_utils::path_to_string(p)).collect();
+        put_list(&mut out, files.iter().map(String::as_str));
+        let fixed: Vec<String> = ex.always_excluded.iter().map(|p| path_utils::path_to_string(p)).collect();
+        put_list(&mut out, fixed.iter().map(String::as_str));
+        put_list(&mut out, self.pending.iter().map(String::as_str));
+
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 14 {
+            return Err(anyhow!("scan_checkpoint too short: {} bytes", bytes.len()));
+        }
+
+        let version = bytes[0];
+        if version != 1 {
+            return Err(anyhow!("unknown scan_checkpoint version: {}", version));
+        }
+
+        let has_frontier = (bytes[1] & 1) != 0;
+
+        let mut sid_arr = [0u8; 8];
+        sid_arr.copy_from_slice(&bytes[2..10]);
+        let session_id = u64::from_le_bytes(sid_arr);
+
+        let mut rd_arr = [0u8; 4];
+        rd_arr.copy_from_slice(&bytes[10..14]);
+        let roots_done = u32::from_le_bytes(rd_arr);
+
+        let mut pos = 14;
+        let frontier = if has_frontier {
+            Some(str_unpack(bytes, &mut pos)?)
+        } else {
+            None
+        };
+
+        let excludes = ExcludeSpec {
+            excludes: get_list(bytes, &mut pos)?,
+            includes: get_list(bytes, &mut pos)?,
+            exclude_files: get_list(bytes, &mut pos)?.iter().map(|p| path_utils::path_from_string(p)).collect(),
+            always_excluded: get_list(bytes, &mut pos)?.iter().map(|p| path_utils::path_from_string(p)).collect(),
+        };
+        let pending = get_list(bytes, &mut pos)?;
+
+        Ok(Self {
+            session_id,
+            excludes,
+            roots_done,
+            frontier,
+            pending,
+        })
+    }
+}
+
+fn put_list<'a>(out: &mut Vec<u8>, items: impl ExactSizeIterator<Item = &'a str>) {
+    out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+    for s in items {
+        str_pack_into(out, s);
+    }
+}
+
+fn get_list(bytes: &[u8], pos: &mut usize) -> Result<Vec<String>> {
+    if bytes.len() < *pos + 4 {
+        return Err(anyhow!("scan_checkpoint list truncated at offset {}", *pos));
+    }
+    let mut n_arr = [0u8; 4];
+    n_arr.copy_from_slice(&bytes[*pos..*pos + 4]);
+    *pos += 4;
+
+    let n = u32::from_le_bytes(n_arr);
+    let mut out = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        out.push(str_unpack(bytes, pos)?);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    #[test]
+    fn encode_decode_round_trips() {
+        let cp = ScanCheckpoint {
+            session_id: 3,
+            excludes: ExcludeSpec {
+                excludes: vec!["*.o".to_string(), "node_modules/".to_string()],
+                includes: vec!["keep.o".to_string()],
+                exclude_files: vec![PathBuf::from(std::ffi::OsStr::from_bytes(b"/x/\xff.ignore"))],
+                always_excluded: vec![PathBuf::from("/db")],
+            },
+            roots_done: 1,
+            frontier: Some("/a/b\\\\c".to_string()),
+            pending: vec!["/a/1".to_string(), "/a/\\xfe".to_string()],
+        };
+        assert_eq!(ScanCheckpoint::decode(&cp.encode()).unwrap(), cp);
+
+        let empty = ScanCheckpoint::default();
+        assert_eq!(ScanCheckpoint::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn decode_rejects_bad_records() {
+        let cp = ScanCheckpoint {
+            frontier: Some("/a".to_string()),
+            pending: vec!["/a/1".to_string()],
+            ..Default::default()
+        };
+        let bytes = cp.encode();
+        for len in [0, 13, 20, bytes.len() - 1] {
+            assert!(ScanCheckpoint::decode(&bytes[..len]).is_err(), "{len} bytes");
+        }
+        let mut bytes = bytes;
+        bytes[0] = 2;
+        assert!(ScanCheckpoint::decode(&bytes).is_err());
+    }
+}
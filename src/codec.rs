@@ -1,3 +1,4 @@
+use anyhow::{anyhow, Result};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn u64_list_pack(ids: &[u64]) -> Vec<u8> {
@@ -20,6 +21,30 @@ pub fn u64_list_unpack(bytes: &[u8]) -> Vec<u64> {
     out
 }
 
+/// Append a length-prefixed (u32 LE) string.
+pub fn str_pack_into(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Read a string written by `str_pack_into()` at `*pos`, advancing `*pos`.
+pub fn str_unpack(bytes: &[u8], pos: &mut usize) -> Result<String> {
+    let len_end = *pos + 4;
+    if bytes.len() < len_end {
+        return Err(anyhow!("string length truncated at offset {}", *pos));
+    }
+    let mut len_arr = [0u8; 4];
+    len_arr.copy_from_slice(&bytes[*pos..len_end]);
+    let len = u32::from_le_bytes(len_arr) as usize;
+
+    let end = len_end + len;
+    if bytes.len() < end {
+        return Err(anyhow!("string data truncated at offset {}", len_end));
+    }
+    *pos = end;
+    Ok(String::from_utf8_lossy(&bytes[len_end..end]).to_string())
+}
+
 pub fn systemtime_to_unix_secs(t: SystemTime) -> u64 {
     t.duration_since(UNIX_EPOCH)
     .map(|d| d.as_secs())
@@ -1,20 +1,26 @@
 use crate::dbpath::{self, DbDirState, DB_FILE, LOCK_FILE, META_FILE};
 use anyhow::{anyhow, Context, Result};
 use fs2::FileExt;
-use redb::{Database, ReadableTable};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableHandle};
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use crate::schema;
 use crate::file_meta::{FileMeta, FileState};
+use crate::checkpoint::ScanCheckpoint;
+use crate::scan_errors::ScanError;
+use crate::session::ScanSession;
 use crate::types::Hash256;
+use crate::path_utils::{path_string_from_bytes, path_string_to_bytes};
+use crate::snapshot::SnapshotBackend;
 
 
 pub struct DbHandle {
     pub db_dir: PathBuf,
     pub db: Database,
     // Keep the lock file open for the lifetime of DbHandle, so the lock is held.
-    _lock_file: File,
+    // None for a database that only exists in memory.
+    _lock_file: Option<File>,
 }
 
 pub struct CurrentByPath {
@@ -36,13 +42,11 @@ pub struct ShaEntry {
 /// - initializes if empty (meta + index.redb)
 /// - acquires exclusive lock
 /// - opens redb database
-/// 
-
 pub fn open(db_dir: &Path) -> Result<DbHandle> {
     let state = dbpath::ensure_db_dir_is_valid_or_empty(db_dir)?;
 
     // Acquire lock first (prevents two processes initializing concurrently).
-    let lock_file = open_and_lock(db_dir)?;
+    let lock_file = open_and_lock(db_dir, true)?;
 
     if state == DbDirState::Empty {
         init_db_dir(db_dir)
@@ -66,7 +70,7 @@ pub fn open(db_dir: &Path) -> Result<DbHandle> {
     let handle = DbHandle {
         db_dir: db_dir.to_path_buf(),
         db,
-        _lock_file: lock_file,
+        _lock_file: Some(lock_file),
     };
 
     // Ensure tables exist / schema is initialized.
@@ -75,26 +79,250 @@ pub fn open(db_dir: &Path) -> Result<DbHandle> {
     Ok(handle)
 }
 
+/// Open a deldupes database directory for reading only:
+/// - a directory without a database yet is left alone, and an empty
+///   database is used in memory instead
+/// - acquires a shared lock on the lock file, so no scan writes to it
+///   meanwhile, while other readers can still open it
+/// - never writes to the database file: what redb writes when opening it
+///   (or repairing it after an unclean shutdown) only goes to memory
+/// - fails if the database needs a migration, which only `open()` does
+pub fn open_read_only(db_dir: &Path) -> Result<DbHandle> {
+    if !db_dir.exists() || dbpath::ensure_db_dir_is_valid_or_empty(db_dir)? == DbDirState::Empty {
+        let db = Database::builder()
+            .create_with_backend(redb::backends::InMemoryBackend::new())
+            .context("Failed to create in-memory database")?;
+        let handle = DbHandle {
+            db_dir: db_dir.to_path_buf(),
+            db,
+            _lock_file: None,
+        };
+        handle.ensure_schema()?;
+        return Ok(handle);
+    }
+
+    let lock_file = open_and_lock(db_dir, false)?;
+
+    let db_file_path = db_dir.join(DB_FILE);
+    let backend = SnapshotBackend::open(&db_file_path)
+        .with_context(|| format!("Failed to open redb file {}", db_file_path.display()))?;
+    let db = Database::builder()
+        .create_with_backend(backend)
+        .with_context(|| format!("Failed to open redb file {}", db_file_path.display()))?;
+
+    let handle = DbHandle {
+        db_dir: db_dir.to_path_buf(),
+        db,
+        _lock_file: Some(lock_file),
+    };
+
+    if let Some(what) = handle.pending_migration()? {
+        return Err(anyhow!(
+            "The database in {} needs {what}, which `scan --plan` does not do; any other command, such as `stats`, upgrades it",
+            db_dir.display()
+        ));
+    }
+
+    Ok(handle)
+}
+
 impl DbHandle {
+    /// What `ensure_schema()` would still change in this database, if anything.
+    fn pending_migration(&self) -> Result<Option<&'static str>> {
+        use crate::schema::*;
+
+        let tx = self.db.begin_read().context("begin_read failed")?;
+        let tables: std::collections::HashSet<String> = tx.list_tables()?.map(|t| t.name().to_string()).collect();
+
+        if tables.contains(LEGACY_ID_TO_PATH.name()) || tables.contains(LEGACY_PATH_TO_ID.name()) {
+            return Ok(Some("its stored paths converted to bytes"));
+        }
+        let required = [
+            KV_U64.name(),
+            PATH_TO_ID.name(),
+            ID_TO_PATH.name(),
+            FILE_META.name(),
+            PATH_CURRENT.name(),
+            FILE_TO_PATH.name(),
+            FILE_STATE.name(),
+            HASH256_TO_FILES.name(),
+            SIZE_TO_FILES.name(),
+            SCAN_ERRORS.name(),
+            SCAN_SESSIONS.name(),
+            FILE_SESSION.name(),
+            SCAN_CHECKPOINT.name(),
+            FILE_VERIFIED.name(),
+            FILE_MOVED_FROM.name(),
+            FILE_MOVED_TO.name(),
+        ];
+        if required.iter().any(|t| !tables.contains(*t)) {
+            return Ok(Some("tables added by a newer version"));
+        }
+
+        let version = tx.open_table(KV_U64)?.get(KEY_SCHEMA_VERSION)?.map(|v| v.value()).unwrap_or(0);
+        if version < 1 && tx.open_table(SIZE_TO_FILES)?.is_empty()? {
+            for item in tx.open_table(FILE_STATE)?.iter()? {
+                if item?.1.value() == FileState::Live.as_u8() {
+                    return Ok(Some("the file size index built"));
+                }
+            }
+        }
+        if version < 2 && !tx.open_table(FILE_META)?.is_empty()? {
+            return Ok(Some("its archive members flagged"));
+        }
+        if version < 3 && !tx.open_table(FILE_META)?.is_empty()? {
+            return Ok(Some("its catalog entries without a size flagged"));
+        }
+        Ok(None)
+    }
+
     pub fn ensure_schema(&self) -> anyhow::Result<()> {
         let tx = self.db.begin_write().context("begin_write() failed")?;
         {
-            let _ = tx.open_table(crate::schema::PATH_TO_ID)?;
-            let _ = tx.open_table(crate::schema::ID_TO_PATH)?;
-            let _ = tx.open_table(crate::schema::KV_U64)?;
+            let mut kv = tx.open_table(crate::schema::KV_U64)?;
+            let version = kv.get(crate::schema::KEY_SCHEMA_VERSION)?.map(|v| v.value()).unwrap_or(0);
             let _ = tx.open_table(crate::schema::FILE_META)?;
             let _ = tx.open_table(crate::schema::PATH_CURRENT)?;
             let _ = tx.open_table(crate::schema::FILE_TO_PATH)?;
             let _ = tx.open_table(crate::schema::FILE_STATE)?;
             let _ = tx.open_table(crate::schema::HASH256_TO_FILES)?;
+            let _ = tx.open_table(crate::schema::SCAN_ERRORS)?;
+            let _ = tx.open_table(crate::schema::SCAN_SESSIONS)?;
+            let _ = tx.open_table(crate::schema::FILE_SESSION)?;
+            let _ = tx.open_table(crate::schema::SCAN_CHECKPOINT)?;
+            let _ = tx.open_table(crate::schema::FILE_VERIFIED)?;
+            let _ = tx.open_table(crate::schema::FILE_MOVED_FROM)?;
+            let _ = tx.open_table(crate::schema::FILE_MOVED_TO)?;
+
+            // Databases from before paths were stored as bytes have them as
+            // strings; the path ids stay the same
+            let mut path_to_id = tx.open_table(crate::schema::PATH_TO_ID)?;
+            let mut id_to_path = tx.open_table(crate::schema::ID_TO_PATH)?;
+            let legacy = tx.open_table(crate::schema::LEGACY_ID_TO_PATH)?;
+            let migrate = !legacy.is_empty()?;
+            if migrate {
+                tracing::info!("converting stored paths to bytes");
+                for item in legacy.iter()? {
+                    let (k, v) = item?;
+                    path_to_id.insert(v.value().as_bytes(), k.value())?;
+                    id_to_path.insert(k.value(), v.value().as_bytes())?;
+                }
+            }
+            drop((legacy, path_to_id, id_to_path));
+            tx.delete_table(crate::schema::LEGACY_ID_TO_PATH)?;
+            tx.delete_table(crate::schema::LEGACY_PATH_TO_ID)?;
+
+            // Databases from before the size index get it built once. Going by
+            // the version, not by the index being empty, as it stays empty
+            // while there are no Live files.
+            let mut size_idx = tx.open_table(crate::schema::SIZE_TO_FILES)?;
+            if version < 1 && size_idx.is_empty()? {
+                let file_meta = tx.open_table(crate::schema::FILE_META)?;
+                let file_state = tx.open_table(crate::schema::FILE_STATE)?;
+                if !file_meta.is_empty()? {
+                    tracing::info!("building the file size index");
+                    let mut by_size: std::collections::BTreeMap<u64, Vec<u64>> = Default::default();
+                    for item in file_meta.iter()? {
+                        let (k, v) = item?;
+                        if file_state.get(k.value())?.map(|s| s.value()) != Some(FileState::Live.as_u8()) {
+                            continue;
+                        }
+                        let fm = FileMeta::decode(v.value())
+                            .with_context(|| format!("decode file_meta for file_id={}", k.value()))?;
+                        if let Some(size) = fm.known_size() {
+                            by_size.entry(size).or_default().push(k.value());
+                        }
+                    }
+                    for (size, ids) in by_size {
+                        size_idx.insert(size, crate::codec::u64_list_pack(&ids).as_slice())?;
+                    }
+                }
+            }
+
+            // Archive members used to be told apart by their path alone. Those
+            // of archives that were indexed themselves get the member flag.
+            if version < 2 {
+                let path_to_id = tx.open_table(crate::schema::PATH_TO_ID)?;
+                let id_to_path = tx.open_table(crate::schema::ID_TO_PATH)?;
+                let file_to_path = tx.open_table(crate::schema::FILE_TO_PATH)?;
+                let mut file_meta = tx.open_table(crate::schema::FILE_META)?;
+                let mut flagged: Vec<(u64, Vec<u8>)> = Vec::new();
+                for item in file_to_path.iter()? {
+                    let (fid, pid) = item?;
+                    let Some(path) = id_to_path.get(pid.value())?.map(|p| path_string_from_bytes(p.value())) else {
+                        continue;
+                    };
+                    let mut in_archive = false;
+                    for (archive, _) in crate::archive::archive_prefixes(&path) {
+                        if path_to_id.get(path_string_to_bytes(archive).as_slice())?.is_some() {
+                            in_archive = true;
+                            break;
+                        }
+                    }
+                    if !in_archive {
+                        continue;
+                    }
+                    let Some(blob) = file_meta.get(fid.value())? else {
+                        continue;
+                    };
+                    let mut fm = FileMeta::decode(blob.value())
+                        .with_context(|| format!("decode file_meta for file_id={}", fid.value()))?;
+                    if !fm.member && !fm.catalog && fm.inode.is_none() {
+                        fm.member = true;
+                        flagged.push((fid.value(), fm.encode()));
+                    }
+                }
+                if !flagged.is_empty() {
+                    tracing::info!(count = flagged.len(), "flagging archive members");
+                }
+                for (fid, blob) in flagged {
+                    file_meta.insert(fid, blob.as_slice())?;
+                }
+            }
+
+            // Catalog entries imported without a size used to get 0, and went
+            // into the size index with the empty files
+            if version < 3 {
+                let mut file_meta = tx.open_table(crate::schema::FILE_META)?;
+                let file_state = tx.open_table(crate::schema::FILE_STATE)?;
+                let mut unknown: Vec<(u64, FileMeta)> = Vec::new();
+                for item in file_meta.iter()? {
+                    let (k, v) = item?;
+                    let fm = FileMeta::decode(v.value())
+                        .with_context(|| format!("decode file_meta for file_id={}", k.value()))?;
+                    if fm.catalog
+                        && fm.size == 0
+                        && !fm.size_unknown
+                        && !fm.hash256.is_some_and(|h| crate::hashing::is_empty_hash(&h))
+                    {
+                        unknown.push((k.value(), fm));
+                    }
+                }
+                for (fid, mut fm) in unknown {
+                    if file_state.get(fid)?.map(|s| s.value()) == Some(FileState::Live.as_u8()) {
+                        remove_from_size_index(&file_meta, &mut size_idx, fid)?;
+                    }
+                    fm.size_unknown = true;
+                    file_meta.insert(fid, fm.encode().as_slice())?;
+                }
+            }
+
+            if version < crate::schema::SCHEMA_VERSION {
+                kv.insert(crate::schema::KEY_SCHEMA_VERSION, crate::schema::SCHEMA_VERSION)?;
+            }
         }
         tx.commit().context("commit() failed")?;
         Ok(())
     }
 
+    /// Store a new version for each path.
+    ///
+    /// A hash for a path whose current version is Live, has its hash pending
+    /// and the same stat, completes that version instead.
     pub fn write_batch_versions(
         &self,
-        batch: &[(String, Vec<u8>, Hash256)], // (path, file_meta_blob, hash256)
+        session_id: u64,
+        batch: &[(String, FileMeta)],
     ) -> anyhow::Result<()> {
         use crate::codec::{u64_list_pack, u64_list_unpack};
 
@@ -112,10 +340,13 @@ impl DbHandle {
             let mut file_to_path = tx.open_table(crate::schema::FILE_TO_PATH)?;
             let mut file_state = tx.open_table(crate::schema::FILE_STATE)?;
             let mut idx = tx.open_table(crate::schema::HASH256_TO_FILES)?;
+            let mut size_idx = tx.open_table(crate::schema::SIZE_TO_FILES)?;
+            let mut file_session = tx.open_table(crate::schema::FILE_SESSION)?;
 
-            for (path, meta_blob, hash256) in batch {
+            for (path, meta) in batch {
                 // 1) get-or-create path_id
-                let pid = if let Some(v) = path_to_id.get(path.as_str())? {
+                let path = path_string_to_bytes(path);
+                let pid = if let Some(v) = path_to_id.get(path.as_slice())? {
                     v.value()
                 } else {
                     let next_id = match kv.get(crate::schema::KEY_NEXT_PATH_ID)? {
@@ -124,32 +355,75 @@ impl DbHandle {
                     };
                     let new_id = next_id;
                     kv.insert(crate::schema::KEY_NEXT_PATH_ID, next_id + 1)?;
-                    path_to_id.insert(path.as_str(), new_id)?;
-                    id_to_path.insert(new_id, path.as_str())?;
+                    path_to_id.insert(path.as_slice(), new_id)?;
+                    id_to_path.insert(new_id, path.as_slice())?;
                     new_id
                 };
 
-                // 2) mark previous current as replaced (if any)
-                if let Some(prev) = path_current.get(pid)? {
-                    let prev_fid = prev.value();
-                    file_state.insert(prev_fid, FileState::Replaced.as_u8())?;
+                // 2) does this add to a version recorded without a full hash?
+                let prev_fid = path_current.get(pid)?.map(|v| v.value());
+                let mut completes = None;
+                if let Some(prev_fid) = prev_fid
+                    && file_state.get(prev_fid)?.map(|v| v.value()) == Some(FileState::Live.as_u8())
+                    && let Some(v) = file_meta.get(prev_fid)?
+                {
+                    let prev = FileMeta::decode(v.value())?;
+                    if prev.same_stat(meta) {
+                        if prev.hash256.is_none() {
+                            completes = Some(prev_fid);
+                        } else if meta.hash256.is_none() {
+                            // Head and tail only; the full hash is already known
+                            continue;
+                        }
+                    }
                 }
 
-                // 3) allocate new file_id
-                let next_fid = match kv.get(crate::schema::KEY_NEXT_FILE_ID)? {
-                    Some(v) => v.value(),
-                    None => 1,
+                let fid = match completes {
+                    Some(fid) => fid,
+                    None => {
+                        // 3) mark previous current as replaced (if any)
+                        if let Some(prev_fid) = prev_fid {
+                            file_state.insert(prev_fid, FileState::Replaced.as_u8())?;
+                            remove_from_size_index(&file_meta, &mut size_idx, prev_fid)?;
+                        }
+
+                        // 4) allocate new file_id
+                        let next_fid = match kv.get(crate::schema::KEY_NEXT_FILE_ID)? {
+                            Some(v) => v.value(),
+                            None => 1,
+                        };
+                        let fid = next_fid;
+                        kv.insert(crate::schema::KEY_NEXT_FILE_ID, next_fid + 1)?;
+
+                        file_to_path.insert(fid, pid)?;
+                        file_state.insert(fid, FileState::Live.as_u8())?;
+                        path_current.insert(pid, fid)?;
+                        file_session.insert(fid, session_id)?;
+
+                        // 5) update size -> [file_id] index (sorted unique)
+                        if let Some(size) = meta.known_size() {
+                            let mut ids = match size_idx.get(size)? {
+                                Some(v) => u64_list_unpack(v.value()),
+                                None => Vec::new(),
+                            };
+                            if ids.binary_search(&fid).is_err() {
+                                ids.push(fid);
+                                ids.sort_unstable();
+                                let packed = u64_list_pack(&ids);
+                                size_idx.insert(size, packed.as_slice())?;
+                            }
+                        }
+                        fid
+                    }
                 };
-                let fid = next_fid;
-                kv.insert(crate::schema::KEY_NEXT_FILE_ID, next_fid + 1)?;
 
-                // 4) insert new version record
-                file_meta.insert(fid, meta_blob.as_slice())?;
-                file_to_path.insert(fid, pid)?;
-                file_state.insert(fid, FileState::Live.as_u8())?;
-                path_current.insert(pid, fid)?;
+                // 6) store the version record
+                file_meta.insert(fid, meta.encode().as_slice())?;
 
-                // 5) update hash256 -> [file_id] index (sorted unique)
+                // 7) update hash256 -> [file_id] index (sorted unique)
+                let Some(hash256) = &meta.hash256 else {
+                    continue;
+                };
                 let mut ids = match idx.get(hash256)? {
                     Some(v) => u64_list_unpack(v.value()),
                     None => Vec::new(),
@@ -168,95 +442,272 @@ impl DbHandle {
         Ok(())
     }
 
-    
-    pub fn get_current_size_mtime_by_path(&self, path: &str) -> anyhow::Result<Option<(u64, u64)>> {
+    /// Mark Live files under `roots` as Missing unless they were seen by the scan.
+    /// Catalog entries are left alone.
+    ///
+    /// Paths for which `is_excluded` returns true were deliberately not visited,
+    /// so their state is left untouched.
+    pub fn mark_missing_not_seen(
+        &self,
+        roots: &[String],
+        seen_paths: &std::collections::HashSet<String>,
+        is_excluded: impl Fn(&str, &FileMeta) -> bool,
+    ) -> anyhow::Result<u64> {
+        let ids: Vec<u64> = self
+            .find_missing_not_seen(roots, seen_paths, is_excluded)?
+            .into_iter()
+            .map(|(file_id, _, _)| file_id)
+            .collect();
+        if !ids.is_empty() {
+            self.mark_files_missing(&ids)?;
+        }
+        Ok(ids.len() as u64)
+    }
+
+    /// The Live files `mark_missing_not_seen()` would mark as Missing, as
+    /// (file_id, path, size).
+    pub fn find_missing_not_seen(
+        &self,
+        roots: &[String],
+        seen_paths: &std::collections::HashSet<String>,
+        is_excluded: impl Fn(&str, &FileMeta) -> bool,
+    ) -> anyhow::Result<Vec<(u64, String, u64)>> {
+        use crate::file_meta::FileState;
+        use crate::schema::*;
+
+        let tx = self.db.begin_read()?;
+        let path_current = tx.open_table(PATH_CURRENT)?;
+        let id_to_path = tx.open_table(ID_TO_PATH)?;
+        let file_meta = tx.open_table(FILE_META)?;
+        let file_state = tx.open_table(FILE_STATE)?;
+
+        let mut out = Vec::new();
+        for entry in path_current.iter()? {
+            let (path_id_guard, file_id_guard) = entry?;
+            let path_id: u64 = path_id_guard.value();
+            let file_id: u64 = file_id_guard.value();
+
+            let path = match id_to_path.get(&path_id)? {
+                Some(p) => path_string_from_bytes(p.value()),
+                None => continue,
+            };
+
+            if !is_under_any_root(&path, roots) {
+                continue;
+            }
+            if seen_paths.contains(&path) {
+                continue;
+            }
+
+            let state: u8 = match file_state.get(&file_id)? {
+                Some(s) => s.value(),
+                None => continue,
+            };
+            if state != FileState::Live.as_u8() {
+                continue;
+            }
+
+            let meta = match file_meta.get(&file_id)? {
+                Some(m) => FileMeta::decode(m.value())?,
+                None => continue,
+            };
+            // Catalog entries are on drives no scan sees
+            if meta.catalog {
+                continue;
+            }
+            if is_excluded(&path, &meta) {
+                continue;
+            }
+
+            out.push((file_id, path, meta.size));
+        }
+        Ok(out)
+    }
+
+    /// Allocate an id for `session`, store it and return the id.
+    pub fn begin_scan_session(&self, session: &ScanSession) -> anyhow::Result<u64> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        let id;
+        {
+            let mut kv = tx.open_table(crate::schema::KV_U64)?;
+            let mut tbl = tx.open_table(crate::schema::SCAN_SESSIONS)?;
+
+            id = match kv.get(crate::schema::KEY_NEXT_SESSION_ID)? {
+                Some(v) => v.value(),
+                None => 1,
+            };
+            kv.insert(crate::schema::KEY_NEXT_SESSION_ID, id + 1)?;
+            tbl.insert(id, session.encode().as_slice())?;
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(id)
+    }
+
+    /// Store the session's progress together with the checkpoint to resume it from.
+    pub fn save_scan_checkpoint(&self, session: &ScanSession, cp: &ScanCheckpoint) -> anyhow::Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let mut sessions = tx.open_table(crate::schema::SCAN_SESSIONS)?;
+            sessions.insert(session.id, session.encode().as_slice())?;
+
+            let mut tbl = tx.open_table(crate::schema::SCAN_CHECKPOINT)?;
+            tbl.insert(crate::schema::KEY_SCAN_CHECKPOINT, cp.encode().as_slice())?;
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
+
+    pub fn load_scan_checkpoint(&self) -> anyhow::Result<Option<ScanCheckpoint>> {
         let tx = self.db.begin_read().context("begin_read() failed")?;
-        let path_to_id = tx.open_table(crate::schema::PATH_TO_ID)?;
-        let path_current = tx.open_table(crate::schema::PATH_CURRENT)?;
-        let file_meta = tx.open_table(crate::schema::FILE_META)?;
+        let tbl = tx.open_table(crate::schema::SCAN_CHECKPOINT)?;
 
-        let Some(pid) = path_to_id.get(path)? else {
+        let Some(blob) = tbl.get(crate::schema::KEY_SCAN_CHECKPOINT)? else {
             return Ok(None);
         };
-        let pid = pid.value();
+        let cp = ScanCheckpoint::decode(blob.value()).context("decode scan_checkpoint")?;
+        Ok(Some(cp))
+    }
 
-        let Some(fid) = path_current.get(pid)? else {
-            return Ok(None);
-        };
-        let fid = fid.value();
+    /// Store the final state of `session` and drop the checkpoint, if any.
+    pub fn finish_scan_session(&self, session: &ScanSession) -> anyhow::Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let mut sessions = tx.open_table(crate::schema::SCAN_SESSIONS)?;
+            sessions.insert(session.id, session.encode().as_slice())?;
 
-        let Some(blob) = file_meta.get(fid)? else {
+            let mut tbl = tx.open_table(crate::schema::SCAN_CHECKPOINT)?;
+            tbl.remove(crate::schema::KEY_SCAN_CHECKPOINT)?;
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
+
+    pub fn clear_scan_checkpoint(&self) -> anyhow::Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let mut tbl = tx.open_table(crate::schema::SCAN_CHECKPOINT)?;
+            tbl.remove(crate::schema::KEY_SCAN_CHECKPOINT)?;
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
+
+    pub fn get_scan_session(&self, id: u64) -> anyhow::Result<Option<ScanSession>> {
+        let tx = self.db.begin_read().context("begin_read() failed")?;
+        let tbl = tx.open_table(crate::schema::SCAN_SESSIONS)?;
+
+        let Some(blob) = tbl.get(id)? else {
             return Ok(None);
         };
+        let s = ScanSession::decode(id, blob.value())
+            .with_context(|| format!("decode scan_session id={id}"))?;
+        Ok(Some(s))
+    }
 
-        let fm = crate::file_meta::FileMeta::decode(blob.value())
-        .with_context(|| format!("decode FileMeta for file_id={fid}"))?;
+    pub fn list_scan_sessions(&self) -> anyhow::Result<Vec<ScanSession>> {
+        let tx = self.db.begin_read().context("begin_read() failed")?;
+        let tbl = tx.open_table(crate::schema::SCAN_SESSIONS)?;
 
-        Ok(Some((fm.size, fm.mtime_secs)))
+        let mut out = Vec::new();
+        for item in tbl.iter()? {
+            let (k, v) = item?;
+            let id = k.value();
+            let s = ScanSession::decode(id, v.value())
+                .with_context(|| format!("decode scan_session id={id}"))?;
+            out.push(s);
+        }
+        Ok(out)
     }
 
-    pub fn mark_missing_not_seen(
-        &self,
-        roots: &[String],
-        seen_paths: &std::collections::HashSet<String>,
-    ) -> anyhow::Result<u64> {
-        fn is_under_any_root(path: &str, roots: &[String]) -> bool {
-            for root in roots {
-                if path == root {
-                    return true;
-                }
-                if path.starts_with(root)
-                    && path.len() > root.len()
-                    && path.as_bytes()[root.len()] == b'/'
-                    {
-                        return true;
-                    }
-            }
-            false
-        }
+    pub fn write_scan_errors(&self, errors: &[ScanError]) -> anyhow::Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let mut kv = tx.open_table(crate::schema::KV_U64)?;
+            let mut tbl = tx.open_table(crate::schema::SCAN_ERRORS)?;
 
-        use crate::file_meta::FileState;
-        use crate::schema::*;
+            let mut next_id = match kv.get(crate::schema::KEY_NEXT_ERROR_ID)? {
+                Some(v) => v.value(),
+                None => 1,
+            };
 
-        let write_txn = self.db.begin_write()?;
-        let mut marked = 0u64;
+            for e in errors {
+                tbl.insert(next_id, e.encode().as_slice())?;
+                next_id += 1;
+            }
 
-        {
-            let path_current = write_txn.open_table(PATH_CURRENT)?;
-            let id_to_path = write_txn.open_table(ID_TO_PATH)?;
-            let mut file_state = write_txn.open_table(FILE_STATE)?;
-
-            for entry in path_current.iter()? {
-                let (path_id_guard, file_id_guard) = entry?;
-                let path_id: u64 = path_id_guard.value();
-                let file_id: u64 = file_id_guard.value();
-
-                let path = match id_to_path.get(&path_id)? {
-                    Some(p) => p.value().to_string(),
-                    None => continue,
-                };
+            kv.insert(crate::schema::KEY_NEXT_ERROR_ID, next_id)?;
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
 
-                if !is_under_any_root(&path, roots) {
-                    continue;
-                }
-                if seen_paths.contains(&path) {
-                    continue;
+    /// Forget errors recorded by earlier scans of these roots.
+    pub fn clear_scan_errors_under(&self, roots: &[String]) -> anyhow::Result<u64> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        let mut removed = 0u64;
+        {
+            let mut tbl = tx.open_table(crate::schema::SCAN_ERRORS)?;
+
+            let mut stale: Vec<u64> = Vec::new();
+            for item in tbl.iter()? {
+                let (k, v) = item?;
+                let e = ScanError::decode(v.value())
+                    .with_context(|| format!("decode scan_error id={}", k.value()))?;
+                if is_under_any_root(&e.path, roots) {
+                    stale.push(k.value());
                 }
+            }
 
-                let state: u8 = match file_state.get(&file_id)? {
-                    Some(s) => s.value(),
-                    None => continue,
-                };
+            for id in stale {
+                tbl.remove(id)?;
+                removed += 1;
+            }
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(removed)
+    }
 
-                if state == FileState::Live.as_u8() {
-                    file_state.insert(&file_id, FileState::Missing.as_u8())?;
-                    marked += 1;
+    /// Remove the errors recorded for these paths, and for the members of
+    /// those that are archives.
+    pub fn clear_scan_errors_for(&self, paths: &std::collections::HashSet<String>) -> anyhow::Result<u64> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        let mut removed = 0u64;
+        {
+            let mut tbl = tx.open_table(crate::schema::SCAN_ERRORS)?;
+
+            let mut stale: Vec<u64> = Vec::new();
+            for item in tbl.iter()? {
+                let (k, v) = item?;
+                let e = ScanError::decode(v.value())
+                    .with_context(|| format!("decode scan_error id={}", k.value()))?;
+                if paths.contains(&e.path)
+                    || crate::archive::split_virtual(&e.path, |a| paths.contains(a)).is_some()
+                {
+                    stale.push(k.value());
                 }
             }
-        } // <- tables dropped here, transaction no longer borrowed
 
-        write_txn.commit()?;
-        Ok(marked)
+            for id in stale {
+                tbl.remove(id)?;
+                removed += 1;
+            }
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(removed)
+    }
+
+    pub fn list_scan_errors(&self) -> anyhow::Result<Vec<ScanError>> {
+        let tx = self.db.begin_read().context("begin_read() failed")?;
+        let tbl = tx.open_table(crate::schema::SCAN_ERRORS)?;
+
+        let mut out = Vec::new();
+        for item in tbl.iter()? {
+            let (k, v) = item?;
+            let e = ScanError::decode(v.value())
+                .with_context(|| format!("decode scan_error id={}", k.value()))?;
+            out.push(e);
+        }
+        Ok(out)
     }
 
     pub fn mark_files_missing(&self, file_ids: &[u64]) -> anyhow::Result<()> {
@@ -266,14 +717,15 @@ impl DbHandle {
         let tx = self.db.begin_write().context("begin_write() failed")?;
         {
             let mut file_state = tx.open_table(crate::schema::FILE_STATE)?;
+            let file_meta = tx.open_table(crate::schema::FILE_META)?;
+            let mut size_idx = tx.open_table(crate::schema::SIZE_TO_FILES)?;
             for &fid in file_ids {
                 // Copy the byte out of the AccessGuard so it drops immediately.
                 let state_u8: Option<u8> = file_state.get(fid)?.map(|st| st.value());
 
-                if let Some(v) = state_u8 {
-                    if v == FileState::Live.as_u8() {
-                        file_state.insert(fid, FileState::Missing.as_u8())?;
-                    }
+                if state_u8 == Some(FileState::Live.as_u8()) {
+                    file_state.insert(fid, FileState::Missing.as_u8())?;
+                    remove_from_size_index(&file_meta, &mut size_idx, fid)?;
                 }
             }
         }
@@ -281,11 +733,113 @@ impl DbHandle {
         Ok(())
     }
 
+    /// Link the current version of each new path to the version it was
+    /// moved from, which is marked Missing. Call after the new versions
+    /// were written.
+    pub fn record_moves(&self, moves: &[(String, u64)]) -> Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let path_to_id = tx.open_table(schema::PATH_TO_ID)?;
+            let path_current = tx.open_table(schema::PATH_CURRENT)?;
+            let mut file_state = tx.open_table(schema::FILE_STATE)?;
+            let file_meta = tx.open_table(schema::FILE_META)?;
+            let mut size_idx = tx.open_table(schema::SIZE_TO_FILES)?;
+            let mut moved_from = tx.open_table(schema::FILE_MOVED_FROM)?;
+            let mut moved_to = tx.open_table(schema::FILE_MOVED_TO)?;
+
+            for (path, old_fid) in moves {
+                let Some(pid) = path_to_id.get(path_string_to_bytes(path).as_slice())?.map(|v| v.value()) else {
+                    continue;
+                };
+                let Some(fid) = path_current.get(pid)?.map(|v| v.value()) else {
+                    continue;
+                };
+                file_state.insert(*old_fid, FileState::Missing.as_u8())?;
+                remove_from_size_index(&file_meta, &mut size_idx, *old_fid)?;
+                moved_from.insert(fid, *old_fid)?;
+                moved_to.insert(*old_fid, fid)?;
+            }
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
+
+    /// The version this one was moved from, with its path.
+    pub fn moved_from(&self, file_id: u64) -> Result<Option<(u64, String)>> {
+        self.follow_move(schema::FILE_MOVED_FROM, file_id)
+    }
+
+    /// The version this one was moved to, with its path.
+    pub fn moved_to(&self, file_id: u64) -> Result<Option<(u64, String)>> {
+        self.follow_move(schema::FILE_MOVED_TO, file_id)
+    }
+
+    fn follow_move(&self, table: redb::TableDefinition<u64, u64>, file_id: u64) -> Result<Option<(u64, String)>> {
+        let tx = self.db.begin_read().context("begin_read failed")?;
+        let moves = tx.open_table(table)?;
+        let Some(other) = moves.get(file_id)?.map(|v| v.value()) else {
+            return Ok(None);
+        };
+        let file_to_path = tx.open_table(schema::FILE_TO_PATH)?;
+        let id_to_path = tx.open_table(schema::ID_TO_PATH)?;
+        let path = match file_to_path.get(other)? {
+            Some(pid) => id_to_path.get(pid.value())?.map(|p| path_string_from_bytes(p.value())),
+            None => None,
+        };
+        Ok(Some((other, path.unwrap_or_else(|| "<unknown-path>".to_string()))))
+    }
+
+    /// The members of `archive` whose current version is Live, with that
+    /// version's file_id. Real files under a directory named like the
+    /// archive's member prefix are left out.
+    pub fn live_members(&self, archive: &str) -> Result<Vec<(String, u64)>> {
+        let mut out = self.live_under_prefix(&crate::archive::member_prefix(archive))?;
+        let tx = self.db.begin_read().context("begin_read failed")?;
+        let file_meta = tx.open_table(schema::FILE_META)?;
+        let mut keep = Vec::with_capacity(out.len());
+        for (path, fid) in &out {
+            let member = match file_meta.get(*fid)? {
+                Some(m) => FileMeta::decode(m.value())?.member,
+                None => false,
+            };
+            keep.push(member && crate::archive::split_virtual(path, |a| a == archive).is_some());
+        }
+        let mut keep = keep.into_iter();
+        out.retain(|_| keep.next().unwrap_or(false));
+        Ok(out)
+    }
+
+    /// Paths starting with `prefix` whose current version is Live, with
+    /// that version's file_id.
+    pub fn live_under_prefix(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
+        let tx = self.db.begin_read().context("begin_read failed")?;
+        let path_to_id = tx.open_table(schema::PATH_TO_ID)?;
+        let path_current = tx.open_table(schema::PATH_CURRENT)?;
+        let file_state = tx.open_table(schema::FILE_STATE)?;
+
+        let prefix = path_string_to_bytes(prefix);
+        let mut out = Vec::new();
+        for item in path_to_id.range(prefix.as_slice()..)? {
+            let (k, v) = item?;
+            let path = k.value();
+            if !path.starts_with(&prefix) {
+                break;
+            }
+            let Some(fid) = path_current.get(v.value())?.map(|f| f.value()) else {
+                continue;
+            };
+            if file_state.get(fid)?.map(|s| s.value()) == Some(FileState::Live.as_u8()) {
+                out.push((path_string_from_bytes(path), fid));
+            }
+        }
+        Ok(out)
+    }
+
     pub fn get_current_by_path(&self, norm_path: &str) -> Result<Option<CurrentByPath>> {
         let tx = self.db.begin_read().context("begin_read failed")?;
 
         let path_to_id = tx.open_table(schema::PATH_TO_ID)?;
-        let Some(pid) = path_to_id.get(norm_path)? else {
+        let Some(pid) = path_to_id.get(path_string_to_bytes(norm_path).as_slice())? else {
             return Ok(None);
         };
         let path_id = pid.value();
@@ -327,7 +881,77 @@ impl DbHandle {
         };
 
         let file_ids = crate::codec::u64_list_unpack(fids_blob.value());
+        Self::load_entries(&tx, file_ids)
+    }
+
+    // Read-only: returns the Live files of this size, hashed or not.
+    pub fn lookup_live_by_size(&self, size: u64) -> anyhow::Result<Vec<ShaEntry>> {
+        let tx = self.db.begin_read().context("begin_read failed")?;
+
+        let size_tbl = tx.open_table(crate::schema::SIZE_TO_FILES)?;
+        let Some(fids_blob) = size_tbl.get(size)? else {
+            return Ok(vec![]);
+        };
+
+        let file_ids = crate::codec::u64_list_unpack(fids_blob.value());
+        let mut out = Self::load_entries(&tx, file_ids)?;
+        out.retain(|e| e.state == FileState::Live);
+        Ok(out)
+    }
+
+    // Read-only: returns all Live files, sorted by path.
+    pub fn live_files(&self) -> anyhow::Result<Vec<ShaEntry>> {
+        let tx = self.db.begin_read().context("begin_read failed")?;
+
+        let path_current = tx.open_table(crate::schema::PATH_CURRENT)?;
+        let file_ids = path_current
+            .iter()?
+            .map(|item| item.map(|(_, fid)| fid.value()))
+            .collect::<Result<Vec<u64>, _>>()?;
+
+        let mut out = Self::load_entries(&tx, file_ids)?;
+        out.retain(|e| e.state == FileState::Live);
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(out)
+    }
+
+    // Read-only: returns all Live files, each with the time it was last
+    // verified (0 if never), least recently verified first.
+    pub fn live_by_last_verified(&self) -> anyhow::Result<Vec<(u64, ShaEntry)>> {
+        let tx = self.db.begin_read().context("begin_read failed")?;
+
+        let path_current = tx.open_table(crate::schema::PATH_CURRENT)?;
+        let file_ids = path_current
+            .iter()?
+            .map(|item| item.map(|(_, fid)| fid.value()))
+            .collect::<Result<Vec<u64>, _>>()?;
 
+        let verified = tx.open_table(crate::schema::FILE_VERIFIED)?;
+        let mut out = Vec::new();
+        for e in Self::load_entries(&tx, file_ids)? {
+            if e.state != FileState::Live {
+                continue;
+            }
+            let when = verified.get(e.file_id)?.map_or(0, |v| v.value());
+            out.push((when, e));
+        }
+        out.sort_by(|(a, ea), (b, eb)| (a, &ea.path).cmp(&(b, &eb.path)));
+        Ok(out)
+    }
+
+    pub fn set_verified(&self, file_ids: &[u64], when: u64) -> anyhow::Result<()> {
+        let tx = self.db.begin_write().context("begin_write() failed")?;
+        {
+            let mut verified = tx.open_table(crate::schema::FILE_VERIFIED)?;
+            for &fid in file_ids {
+                verified.insert(fid, when)?;
+            }
+        }
+        tx.commit().context("commit() failed")?;
+        Ok(())
+    }
+
+    fn load_entries(tx: &redb::ReadTransaction, file_ids: Vec<u64>) -> anyhow::Result<Vec<ShaEntry>> {
         let file_state = tx.open_table(crate::schema::FILE_STATE)?;
         let file_meta = tx.open_table(crate::schema::FILE_META)?;
         let file_to_path = tx.open_table(crate::schema::FILE_TO_PATH)?;
@@ -347,7 +971,7 @@ impl DbHandle {
 
             let path = if let Some(pid) = file_to_path.get(fid)? {
                 if let Some(p) = id_to_path.get(pid.value())? {
-                    p.value().to_string()
+                    path_string_from_bytes(p.value())
                 } else {
                     "<unknown-path>".to_string()
                 }
@@ -369,18 +993,71 @@ impl DbHandle {
 }
 
 
-fn open_and_lock(db_dir: &Path) -> Result<File> {
+fn is_under_any_root(path: &str, roots: &[String]) -> bool {
+    for root in roots {
+        if path == root {
+            return true;
+        }
+        if path.starts_with(root.as_str())
+            && path.len() > root.len()
+            && path.as_bytes()[root.len()] == b'/'
+            {
+                return true;
+            }
+    }
+    false
+}
+
+/// Take a version that is no longer Live out of the size index, which only
+/// lists Live files.
+fn remove_from_size_index(
+    file_meta: &impl ReadableTable<u64, &'static [u8]>,
+    size_idx: &mut redb::Table<u64, &'static [u8]>,
+    file_id: u64,
+) -> Result<()> {
+    use crate::codec::{u64_list_pack, u64_list_unpack};
+
+    let Some(size) = file_meta
+        .get(file_id)?
+        .map(|v| FileMeta::decode(v.value()))
+        .transpose()?
+        .and_then(|m| m.known_size())
+    else {
+        return Ok(());
+    };
+    let mut ids = match size_idx.get(size)? {
+        Some(v) => u64_list_unpack(v.value()),
+        None => return Ok(()),
+    };
+    let Ok(i) = ids.binary_search(&file_id) else {
+        return Ok(());
+    };
+    ids.remove(i);
+    if ids.is_empty() {
+        size_idx.remove(size)?;
+    } else {
+        size_idx.insert(size, u64_list_pack(&ids).as_slice())?;
+    }
+    Ok(())
+}
+
+fn open_and_lock(db_dir: &Path, exclusive: bool) -> Result<File> {
     let lock_path = db_dir.join(LOCK_FILE);
     let f = OpenOptions::new()
         .create(true)
+        .truncate(false)
         .read(true)
         .write(true)
         .open(&lock_path)
         .with_context(|| format!("Failed to open lock file {}", lock_path.display()))?;
 
-    // Exclusive lock: one writer process at a time.
-    f.try_lock_exclusive()
-        .with_context(|| format!("Database is locked (in use?): {}", db_dir.display()))?;
+    // Exclusive lock: one writer process at a time. Readers share theirs.
+    if exclusive {
+        FileExt::try_lock_exclusive(&f)
+    } else {
+        FileExt::try_lock_shared(&f)
+    }
+    .with_context(|| format!("Database is locked (in use?): {}", db_dir.display()))?;
 
     Ok(f)
 }
@@ -426,3 +1103,129 @@ hash_prefix = "sha1_4k_if_gt_4k"
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+
+    /// A Live version of `size` bytes, without putting it in the size index.
+    fn add_live(db: &DbHandle, file_id: u64, size: u64) {
+        let tx = db.db.begin_write().unwrap();
+        {
+            let mut meta = tx.open_table(schema::FILE_META).unwrap();
+            meta.insert(file_id, FileMeta::catalog(Some(size), [1u8; 32]).encode().as_slice()).unwrap();
+            let mut state = tx.open_table(schema::FILE_STATE).unwrap();
+            state.insert(file_id, FileState::Live.as_u8()).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    fn set_schema_version(db: &DbHandle, version: Option<u64>) {
+        let tx = db.db.begin_write().unwrap();
+        {
+            let mut kv = tx.open_table(schema::KV_U64).unwrap();
+            match version {
+                Some(v) => kv.insert(schema::KEY_SCHEMA_VERSION, v).unwrap(),
+                None => kv.remove(schema::KEY_SCHEMA_VERSION).unwrap(),
+            };
+        }
+        tx.commit().unwrap();
+    }
+
+    fn sizes_indexed(db: &DbHandle) -> u64 {
+        let tx = db.db.begin_read().unwrap();
+        tx.open_table(schema::SIZE_TO_FILES).unwrap().len().unwrap()
+    }
+
+    #[test]
+    fn size_index_is_built_once() {
+        let dir = TestDir::new();
+        let db = open(dir.path()).unwrap();
+        add_live(&db, 1, 100);
+        drop(db);
+
+        // Up to date: an empty index is left as it is
+        let db = open(dir.path()).unwrap();
+        assert_eq!(sizes_indexed(&db), 0);
+
+        // From before the size index: built when opened
+        set_schema_version(&db, None);
+        drop(db);
+        let db = open(dir.path()).unwrap();
+        assert_eq!(sizes_indexed(&db), 1);
+        assert!(db.pending_migration().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_only_open_reports_pending_migration() {
+        let dir = TestDir::new();
+        let db = open(dir.path()).unwrap();
+        add_live(&db, 1, 100);
+        set_schema_version(&db, None);
+        drop(db);
+
+        assert!(open_read_only(dir.path()).is_err());
+        drop(open(dir.path()).unwrap());
+        assert!(open_read_only(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn members_of_indexed_archives_get_flagged() {
+        let dir = TestDir::new();
+        let db = open(dir.path()).unwrap();
+        // As stored before the member flag: no inode, like any member
+        let meta = |h: u8| FileMeta {
+            catalog: false,
+            ..FileMeta::catalog(Some(1), [h; 32])
+        };
+        db.write_batch_versions(
+            1,
+            &[
+                ("/d/a.zip".to_string(), meta(1)),
+                ("/d/a.zip!/m.txt".to_string(), meta(2)),
+                ("/d/b.zip!/m.txt".to_string(), meta(2)),
+            ],
+        )
+        .unwrap();
+        set_schema_version(&db, Some(1));
+        assert!(db.pending_migration().unwrap().is_some());
+        drop(db);
+
+        let db = open(dir.path()).unwrap();
+        let flagged = |p: &str| db.get_current_by_path(p).unwrap().unwrap().meta.member;
+        assert!(!flagged("/d/a.zip"));
+        assert!(flagged("/d/a.zip!/m.txt"));
+        // No archive of that name was indexed: a real file under a directory `b.zip!`
+        assert!(!flagged("/d/b.zip!/m.txt"));
+        assert_eq!(db.live_members("/d/a.zip").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn catalog_entries_without_a_size_stay_out_of_the_size_index() {
+        let dir = TestDir::new();
+        let db = open(dir.path()).unwrap();
+        db.write_batch_versions(0, &[("/c/a".to_string(), FileMeta::catalog(None, [1; 32]))]).unwrap();
+        assert_eq!(sizes_indexed(&db), 0);
+        assert!(db.lookup_live_by_size(0).unwrap().is_empty());
+
+        // As stored before the flag: size 0, and in the index
+        let empty = *blake3::hash(b"").as_bytes();
+        db.write_batch_versions(
+            0,
+            &[
+                ("/c/b".to_string(), FileMeta::catalog(Some(0), [2; 32])),
+                ("/c/empty".to_string(), FileMeta::catalog(Some(0), empty)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(db.lookup_live_by_size(0).unwrap().len(), 2);
+        set_schema_version(&db, Some(2));
+        drop(db);
+
+        let db = open(dir.path()).unwrap();
+        let live = db.lookup_live_by_size(0).unwrap();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].path, "/c/empty");
+        assert!(db.get_current_by_path("/c/b").unwrap().unwrap().meta.size_unknown);
+    }
+}
@@ -1,9 +1,17 @@
+use crate::archive;
 use crate::db::DbHandle;
 use crate::dupe_groups::{self, DupeEntry, DupeGroup};
+use crate::file_meta::{FileStat, FileState};
 use crate::path_filter::PathFilter;
+use crate::path_utils;
+use crate::progress::{self, Progress, ProgressSample};
+use crate::util::format_size;
 use anyhow::{Context, Result};
 use clap::ValueEnum;
 use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
 pub enum Preserve {
@@ -15,109 +23,281 @@ pub enum Preserve {
     AlphaLast,
 }
 
-pub fn run_delete(db: &DbHandle, filter: &PathFilter, preserve: Preserve, apply: bool) -> Result<()> {
+/// With `unlink_hardlinks`, names whose file keeps another link are deleted
+/// too, although that frees nothing.
+pub fn run_delete(
+    db: &DbHandle,
+    filter: &PathFilter,
+    preserve: Preserve,
+    apply: bool,
+    unlink_hardlinks: bool,
+) -> Result<()> {
     let groups = dupe_groups::load_live_dupe_groups(db, filter)?;
+    let archives = unchanged_archives(db, &groups)?;
+    let plan = |g: &DupeGroup, use_archives: bool| {
+        let mut plan = plan_group(g, filter, preserve, use_archives.then_some(&archives));
+        account_hardlinks(&mut plan, unlink_hardlinks);
+        plan
+    };
 
-    let mut total_delete = 0usize;
-    let mut total_groups = 0usize;
+    // Plan everything first, so progress can be reported against the totals
+    let mut plans: Vec<(&DupeGroup, GroupPlan)> = groups.iter().map(|g| (g, plan(g, true))).collect();
 
-    for g in &groups {
-        let plan = plan_group(g, filter, preserve);
+    // A copy in an archive only counts if the archive is kept. Deleting files
+    // here only makes fewer archives deleted, so one pass is enough.
+    let planned: HashSet<&str> = plans
+        .iter()
+        .flat_map(|(_, p)| p.to_delete.iter().map(|e| e.path.as_str()))
+        .collect();
+    let replan: Vec<usize> = plans
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, p))| {
+            !p.in_archives.is_empty()
+                && p.in_archives
+                    .iter()
+                    .all(|m| archive::split_virtual(&m.path, |a| planned.contains(a)).is_some())
+        })
+        .map(|(i, _)| i)
+        .collect();
+    for i in replan {
+        let g = plans[i].0;
+        plans[i].1 = plan(g, false);
+    }
 
-        if plan.to_delete.is_empty() {
-            continue;
-        }
+    let plans: Vec<(&DupeGroup, GroupPlan)> = plans
+        .into_iter()
+        .filter(|(_, plan)| !plan.to_delete.is_empty())
+        .collect();
+
+    let total_groups = plans.len();
+    let total_delete: usize = plans.iter().map(|(_, p)| p.to_delete.len()).sum();
+    let total_freed: u64 = plans.iter().map(|(_, p)| p.freed_bytes).sum();
 
-        total_groups += 1;
-        total_delete += plan.to_delete.len();
+    let deleted = Arc::new(AtomicU64::new(0));
+    let deleted_bytes = Arc::new(AtomicU64::new(0));
+    let progress = apply.then(|| {
+        let deleted = deleted.clone();
+        let deleted_bytes = deleted_bytes.clone();
+        Progress::start("delete", "deleted", move || ProgressSample {
+            found: total_delete as u64,
+            queued: total_delete as u64,
+            queued_bytes: total_freed,
+            done: deleted.load(Ordering::Relaxed),
+            done_bytes: deleted_bytes.load(Ordering::Relaxed),
+            errors: 0,
+            read_limit: None,
+        })
+    });
 
+    for (g, plan) in &plans {
         // Print plan (always)
-        println!("GROUP {}", hex::encode(g.hash256));
-        if let Some(k) = &plan.keeper {
-            println!("  KEEP {}", k.path);
-        } else {
-            println!("  KEEP (outside selection)");
-        }
-        for d in &plan.to_delete {
-            if apply {
-                println!("  DELETE {}", d.path);
+        progress::suspend(|| {
+            println!("GROUP {}", hex::encode(g.hash256));
+            if !plan.keep.is_empty() {
+                for k in &plan.keep {
+                    println!("  KEEP {}", k.path);
+                }
+            } else if !plan.in_archives.is_empty() {
+                for m in &plan.in_archives {
+                    println!("  KEEP {} (in archive)", m.path);
+                }
             } else {
-                println!("  WOULD_DELETE {}", d.path);
+                println!("  KEEP (outside selection)");
             }
-        }
-        println!();
+            for l in &plan.linked {
+                println!("  KEEP {} (hardlink, deleting it frees nothing)", l.path);
+            }
+            for d in &plan.to_delete {
+                let note = if plan.frees_nothing.contains(&d.file_id) {
+                    " (hardlink, frees nothing)"
+                } else {
+                    ""
+                };
+                if apply {
+                    println!("  DELETE {}{note}", d.path);
+                } else {
+                    println!("  WOULD_DELETE {}{note}", d.path);
+                }
+            }
+            println!();
+        });
 
         if apply {
-            apply_group_plan(db, &plan)
+            apply_group_plan(db, plan)
             .with_context(|| format!("Failed applying delete plan for hash={}", hex::encode(g.hash256)))?;
+
+            deleted.fetch_add(plan.to_delete.len() as u64, Ordering::Relaxed);
+            deleted_bytes.fetch_add(plan.freed_bytes, Ordering::Relaxed);
         }
     }
+    drop(progress);
 
     if apply {
-        println!("Deleted {total_delete} files across {total_groups} duplicate groups.");
+        println!(
+            "Deleted {total_delete} files across {total_groups} duplicate groups, freeing {}.",
+            format_size(total_freed)
+        );
     } else {
-        println!("Dry-run: would delete {total_delete} files across {total_groups} duplicate groups.");
+        println!(
+            "Dry-run: would delete {total_delete} files across {total_groups} duplicate groups, freeing {}.",
+            format_size(total_freed)
+        );
         println!("Run again with --apply to actually delete.");
     }
 
     Ok(())
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct GroupPlan {
-    keeper: Option<DupeEntry>, // only used when we must choose within the selected set
+    keep: Vec<DupeEntry>, // all names of the kept file, when we must choose within the selected set
     to_delete: Vec<DupeEntry>,
+    in_archives: Vec<DupeEntry>, // copies in unchanged archives, when relied on
+    // Filled in by account_hardlinks()
+    freed_bytes: u64,
+    frees_nothing: HashSet<u64>, // file_ids whose file keeps another name
+    linked: Vec<DupeEntry>,      // selected names left alone, as deleting them frees nothing
+}
+
+/// The archives holding a member of any group that are still what the last
+/// scan indexed. A copy in an archive that changed since may not be in it
+/// any more.
+fn unchanged_archives<'a>(db: &DbHandle, groups: &'a [DupeGroup]) -> Result<HashSet<&'a str>> {
+    let mut checked: HashMap<&str, bool> = HashMap::new();
+    for e in groups.iter().flat_map(|g| g.entries()).filter(|e| e.member) {
+        for (a, _) in archive::archive_prefixes(&e.path) {
+            if checked.contains_key(a) {
+                continue;
+            }
+            let unchanged = match std::fs::metadata(path_utils::path_from_string(a)) {
+                Ok(md) if md.is_file() => db.get_current_by_path(a)?.is_some_and(|cur| {
+                    cur.state == FileState::Live
+                        && !cur.meta.member
+                        && cur.meta.matches_stat(&FileStat::from_metadata(&md))
+                }),
+                _ => false,
+            };
+            checked.insert(a, unchanged);
+        }
+    }
+    Ok(checked.into_iter().filter(|(_, ok)| *ok).map(|(a, _)| a).collect())
 }
 
-fn plan_group(group: &DupeGroup, filter: &PathFilter, preserve: Preserve) -> GroupPlan {
+/// Files in archives (`scan --archives`) are never deleted. With
+/// `archives`, a copy in one of those archives is enough to delete all
+/// selected files.
+///
+/// Catalog entries (`import-manifest`) are neither deleted nor relied on:
+/// a copy on a drive that is not connected cannot be checked.
+fn plan_group(group: &DupeGroup, filter: &PathFilter, preserve: Preserve, archives: Option<&HashSet<&str>>) -> GroupPlan {
+    let (in_archives, real): (Vec<DupeEntry>, Vec<DupeEntry>) = group
+        .entries()
+        .filter(|e| !e.catalog)
+        .cloned()
+        .partition(|e| e.member);
+
     // Selected = entries that match the provided path prefixes.
     // If no prefixes were provided, PathFilter matches everything => selected == all.
-    let selected: Vec<DupeEntry> = group
-    .entries
-    .iter()
-    .cloned()
-    .filter(|e| filter.matches(&e.path))
-    .collect();
+    let selected: Vec<DupeEntry> = real.iter().filter(|e| filter.matches(&e.path)).cloned().collect();
+
+    if let Some(archives) = archives {
+        let in_archives: Vec<DupeEntry> = in_archives
+            .into_iter()
+            .filter(|e| archive::split_virtual(&e.path, |a| archives.contains(a)).is_some())
+            .collect();
+        if !in_archives.is_empty() {
+            return GroupPlan {
+                to_delete: selected,
+                in_archives,
+                ..Default::default()
+            };
+        }
+    }
 
     if selected.is_empty() {
         // Shouldn't happen because load_live_dupe_groups() already filters by "any entry matches",
         // but keep it safe.
-        return GroupPlan {
-            keeper: None,
-            to_delete: Vec::new(),
-        };
+        return GroupPlan::default();
     }
 
-    let all_selected = selected.len() == group.entries.len();
+    let all_selected = selected.len() == real.len();
 
     if all_selected {
-        // We are operating on the entire dupe-set, so we MUST keep one.
-        let keeper = choose_keeper(&group.entries, preserve);
-        let to_delete: Vec<DupeEntry> = group
-        .entries
-        .iter()
-        .cloned()
-        .filter(|e| e.file_id != keeper.file_id)
-        .collect();
+        // We are operating on the entire dupe-set, so we MUST keep one physical
+        // file, with every name it has.
+        let keeper = choose_keeper(&real, preserve);
+        let (keep, to_delete): (Vec<DupeEntry>, Vec<DupeEntry>) = real
+        .into_iter()
+        .partition(|e| e.file_id == keeper.file_id || (e.dev_ino.is_some() && e.dev_ino == keeper.dev_ino));
 
         // Absolute rule: never delete all duplicates
-        debug_assert!(to_delete.len() + 1 == group.entries.len());
+        debug_assert!(!keep.is_empty());
 
         GroupPlan {
-            keeper: Some(keeper),
+            keep,
             to_delete,
+            ..Default::default()
         }
     } else {
         // Some duplicates exist outside the selection; rule says:
         // delete all copies in supplied paths (selected), while keeping those outside.
         // Absolute rule satisfied because at least one file remains outside.
         GroupPlan {
-            keeper: None,
             to_delete: selected,
+            ..Default::default()
         }
     }
 }
 
+/// Work out what deleting the plan frees. Removing a name only frees space
+/// once the last link to the file is gone, so this goes by the link count on
+/// disk, which also covers links outside the database.
+///
+/// Names whose file keeps another link are taken out of the plan, unless
+/// `unlink_hardlinks` is set, in which case they are only marked.
+fn account_hardlinks(plan: &mut GroupPlan, unlink_hardlinks: bool) {
+    use std::os::unix::fs::MetadataExt;
+
+    // (dev, ino) -> (link count, size, file_ids planned for deletion)
+    let mut by_inode: HashMap<(u64, u64), (u64, u64, Vec<u64>)> = HashMap::new();
+
+    for e in &plan.to_delete {
+        match std::fs::symlink_metadata(path_utils::path_from_string(&e.path)) {
+            Ok(md) => {
+                by_inode
+                .entry((md.dev(), md.ino()))
+                .or_insert_with(|| (md.nlink(), md.size(), Vec::new()))
+                .2
+                .push(e.file_id);
+            }
+            // Gone or unreadable; --apply will report it
+            Err(_) => {
+                plan.frees_nothing.insert(e.file_id);
+            }
+        }
+    }
+
+    let mut linked: HashSet<u64> = HashSet::new();
+    for (nlink, size, ids) in by_inode.into_values() {
+        if ids.len() as u64 >= nlink {
+            plan.freed_bytes += size;
+        } else if unlink_hardlinks {
+            plan.frees_nothing.extend(ids);
+        } else {
+            linked.extend(ids);
+        }
+    }
+
+    if !linked.is_empty() {
+        let (linked, to_delete) = std::mem::take(&mut plan.to_delete)
+        .into_iter()
+        .partition(|e| linked.contains(&e.file_id));
+        plan.to_delete = to_delete;
+        plan.linked = linked;
+    }
+}
+
 fn choose_keeper(entries: &[DupeEntry], preserve: Preserve) -> DupeEntry {
     // Deterministic tie-breaks always fall back to path compare.
     let mut v: Vec<DupeEntry> = entries.to_vec();
@@ -160,7 +340,7 @@ fn apply_group_plan(db: &DbHandle, plan: &GroupPlan) -> Result<()> {
 
     for e in &plan.to_delete {
         // Safety: only remove files (remove_file removes symlinks too, which is acceptable here).
-        std::fs::remove_file(&e.path)
+        std::fs::remove_file(path_utils::path_from_string(&e.path))
         .with_context(|| format!("remove_file failed for {}", e.path))?;
         deleted_file_ids.push(e.file_id);
     }
@@ -0,0 +1,132 @@
+//! Where files are stored: on which disk, what kind of disk, and where on it,
+//! so hashing can be scheduled to suit the disk.
+
+/// The disk behind an `st_dev`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskInfo {
+    /// Device number of the whole disk, so partitions of one disk share it.
+    /// The `st_dev` itself if the disk cannot be found.
+    pub disk: u64,
+    /// True for spinning disks, which are slowed down by parallel reads
+    pub rotational: bool,
+}
+
+impl DiskInfo {
+    /// `major:minor`, as used by the kernel and `lsblk`.
+    pub fn name(&self) -> String {
+        format!("{}:{}", major(self.disk), minor(self.disk))
+    }
+}
+
+/// Look up the disk behind a file system device number.
+///
+/// Uses `/sys/dev/block`. Devices without an entry there (network and
+/// virtual file systems, btrfs subvolumes) are taken as non-rotational
+/// disks of their own.
+#[cfg(target_os = "linux")]
+pub fn disk_info(dev: u64) -> DiskInfo {
+    use std::path::Path;
+
+    let unknown = DiskInfo {
+        disk: dev,
+        rotational: false,
+    };
+
+    let Ok(mut sys) = Path::new("/sys/dev/block")
+        .join(format!("{}:{}", major(dev), minor(dev)))
+        .canonicalize()
+    else {
+        return unknown;
+    };
+    // A partition's queue settings are those of its parent disk
+    if sys.join("partition").exists() {
+        sys.pop();
+    }
+
+    let read = |name: &str| std::fs::read_to_string(sys.join(name)).map(|s| s.trim().to_string());
+    let disk = read("dev")
+        .ok()
+        .and_then(|s| {
+            let (maj, min) = s.split_once(':')?;
+            Some(libc::makedev(maj.parse().ok()?, min.parse().ok()?))
+        })
+        .unwrap_or(dev);
+    let rotational = read("queue/rotational").is_ok_and(|s| s == "1");
+
+    DiskInfo { disk, rotational }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn disk_info(dev: u64) -> DiskInfo {
+    DiskInfo {
+        disk: dev,
+        rotational: false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn major(dev: u64) -> u32 {
+    libc::major(dev)
+}
+
+#[cfg(target_os = "linux")]
+fn minor(dev: u64) -> u32 {
+    libc::minor(dev)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn major(dev: u64) -> u32 {
+    (dev >> 8) as u32
+}
+
+#[cfg(not(target_os = "linux"))]
+fn minor(dev: u64) -> u32 {
+    (dev & 0xff) as u32
+}
+
+/// Physical position of the start of a file on its disk, from the FIEMAP
+/// ioctl. None if the file system does not support it, or the file has no
+/// data on disk (empty, or stored inline).
+#[cfg(target_os = "linux")]
+pub fn first_extent(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // struct fiemap with room for one struct fiemap_extent, from <linux/fiemap.h>
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        fm_start: u64,
+        fm_length: u64,
+        fm_flags: u32,
+        fm_mapped_extents: u32,
+        fm_extent_count: u32,
+        fm_reserved: u32,
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_reserved64: [u64; 2],
+        fe_flags: u32,
+        fe_reserved: [u32; 3],
+    }
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+    let file = std::fs::File::open(path).ok()?;
+    let mut fm = Fiemap {
+        fm_length: u64::MAX,
+        fm_extent_count: 1,
+        ..Default::default()
+    };
+    // SAFETY: `fm` is a struct fiemap followed by space for the one extent asked for.
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fm as *mut Fiemap) };
+    if rc != 0 || fm.fm_mapped_extents == 0 || fm.fe_flags & FIEMAP_EXTENT_UNKNOWN != 0 {
+        return None;
+    }
+    Some(fm.fe_physical)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn first_extent(_path: &std::path::Path) -> Option<u64> {
+    None
+}
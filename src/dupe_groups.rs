@@ -4,6 +4,7 @@ use crate::file_meta::{FileMeta, FileState};
 use crate::path_filter::PathFilter;
 use anyhow::{Context, Result};
 use redb::ReadableTable;
+use std::collections::HashMap;
 use crate::types::Hash256;
 
 
@@ -12,14 +13,40 @@ pub struct DupeEntry {
     pub file_id: u64,
     pub path: String,
     pub size: u64,
-    pub mtime: u64,
+    pub mtime: i64,
+    /// None for versions stored before inode numbers were recorded
+    pub dev_ino: Option<(u64, u64)>,
+    /// Imported from a manifest; not a file that can be deleted
+    pub catalog: bool,
+    /// In an archive; not a file that can be deleted either
+    pub member: bool,
+}
+
+/// One file on disk, with all of its names (hardlinks) that are in the DB.
+#[derive(Debug, Clone)]
+pub struct PhysicalFile {
+    pub links: Vec<DupeEntry>, // sorted by path; len >= 1
+}
+
+impl PhysicalFile {
+    pub fn path(&self) -> &str {
+        &self.links[0].path
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct DupeGroup {
     pub hash256: Hash256,
-    pub entries: Vec<DupeEntry>, // Live only; len >= 2
-    pub header_path: String,     // derived: shortest path
+    pub size: u64,
+    pub files: Vec<PhysicalFile>, // Live only; len >= 2
+    pub header_path: String,      // derived: shortest path
+}
+
+impl DupeGroup {
+    /// All names in the group, across physical files.
+    pub fn entries(&self) -> impl Iterator<Item = &DupeEntry> {
+        self.files.iter().flat_map(|f| f.links.iter())
+    }
 }
 
 pub fn load_live_dupe_groups(db: &DbHandle, filter: &PathFilter) -> Result<Vec<DupeGroup>> {
@@ -58,7 +85,7 @@ pub fn load_live_dupe_groups(db: &DbHandle, filter: &PathFilter) -> Result<Vec<D
             let pid = pid.value();
 
             let path = match id_to_path.get(pid)? {
-                Some(p) => p.value().to_string(),
+                Some(p) => crate::path_utils::path_string_from_bytes(p.value()),
                 None => continue,
             };
 
@@ -73,6 +100,9 @@ pub fn load_live_dupe_groups(db: &DbHandle, filter: &PathFilter) -> Result<Vec<D
                 path,
                 size: fm.size,
                 mtime: fm.mtime_secs,
+                dev_ino: fm.inode.map(|i| (i.dev, i.ino)),
+                catalog: fm.catalog,
+                member: fm.member,
             });
         }
 
@@ -90,17 +120,32 @@ pub fn load_live_dupe_groups(db: &DbHandle, filter: &PathFilter) -> Result<Vec<D
         // Stable order for determinism
         entries.sort_by(|a, b| a.path.cmp(&b.path));
 
-        // Header path = shortest path; tie-break by lexicographic
+        // Header path = shortest path of a scanned file; tie-break by lexicographic
         let header_path = entries
         .iter()
-        .min_by(|a, b| a.path.len().cmp(&b.path.len()).then_with(|| a.path.cmp(&b.path)))
+        .min_by(|a, b| {
+            a.catalog
+            .cmp(&b.catalog)
+            .then_with(|| a.path.len().cmp(&b.path.len()))
+            .then_with(|| a.path.cmp(&b.path))
+        })
         .unwrap()
         .path
         .clone();
 
+        // Catalog entries imported without a size have 0
+        let size = entries.iter().map(|e| e.size).max().unwrap_or(0);
+        let files = collapse_hardlinks(entries);
+
+        // Hardlinks to one file are not duplicates
+        if files.len() < 2 {
+            continue;
+        }
+
         groups.push(DupeGroup {
             hash256,
-            entries,
+            size,
+            files,
             header_path,
         });
     }
@@ -114,3 +159,25 @@ pub fn load_live_dupe_groups(db: &DbHandle, filter: &PathFilter) -> Result<Vec<D
 
     Ok(groups)
 }
+
+/// Group entries (sorted by path) into physical files by `(dev, ino)`.
+/// Entries without inode information are taken as separate files.
+fn collapse_hardlinks(entries: Vec<DupeEntry>) -> Vec<PhysicalFile> {
+    let mut files: Vec<PhysicalFile> = Vec::new();
+    let mut by_inode: HashMap<(u64, u64), usize> = HashMap::new();
+
+    for e in entries {
+        let Some(key) = e.dev_ino else {
+            files.push(PhysicalFile { links: vec![e] });
+            continue;
+        };
+        match by_inode.get(&key) {
+            Some(&i) => files[i].links.push(e),
+            None => {
+                by_inode.insert(key, files.len());
+                files.push(PhysicalFile { links: vec![e] });
+            }
+        }
+    }
+    files
+}
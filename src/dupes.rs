@@ -7,25 +7,40 @@ use crate::util::{format_size, size_in_range};
 pub fn run_dupes(db: &DbHandle, filter: &PathFilter, min_size: Option<u64>, max_size: Option<u64>) -> Result<()> {
     let mut groups = dupe_groups::load_live_dupe_groups(db, filter)?;
 
-    groups.retain(|g| {
-        let size = g.entries.first().map(|e| e.size).unwrap_or(0);
-        size_in_range(size, min_size, max_size)
-    });
+    groups.retain(|g| size_in_range(g.size, min_size, max_size));
 
     print_groups(&groups);
     Ok(())
 }
 
 
+/// Each physical file is listed once; further names (hardlinks) of the
+/// same file are indented below it.
 pub fn print_groups(groups: &[dupe_groups::DupeGroup]) {
     for g in groups {
-        let size = g.entries.first().map(|e| e.size).unwrap_or(0);
-        println!("{} {}", g.header_path, format_size(size));
-        for e in &g.entries {
-            if e.path == g.header_path {
+        println!("{} {}", g.header_path, format_size(g.size));
+
+        // The file with the header path first
+        let mut files: Vec<&dupe_groups::PhysicalFile> = g.files.iter().collect();
+        files.sort_by_key(|f| !f.links.iter().any(|e| e.path == g.header_path));
+
+        for (i, f) in files.iter().enumerate() {
+            if i == 0 {
+                // Already named by the header line
+                for e in f.links.iter().filter(|e| e.path != g.header_path) {
+                    println!("    {} (hardlink)", e.path);
+                }
+                continue;
+            }
+
+            if f.links[0].catalog {
+                println!("  {} (catalog)", f.path());
                 continue;
             }
-            println!("  {}", e.path);
+            println!("  {}", f.path());
+            for e in &f.links[1..] {
+                println!("    {} (hardlink)", e.path);
+            }
         }
         println!();
     }
@@ -0,0 +1,132 @@
+use crate::path_utils;
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// A directory containing this file is never scanned.
+pub const NODELDUPES_MARKER: &str = ".nodeldupes";
+
+/// Cache directory marker, see https://bford.info/cachedir/
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// The user-supplied inputs for `ExcludeRules`, kept so an interrupted scan
+/// can be resumed with the same rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExcludeSpec {
+    pub excludes: Vec<String>,
+    pub includes: Vec<String>,
+    pub exclude_files: Vec<PathBuf>,
+    pub always_excluded: Vec<PathBuf>,
+}
+
+/// Include/exclude rules applied during traversal.
+///
+/// Patterns use gitignore syntax and are matched against absolute, normalized paths:
+/// - `node_modules/` matches a directory with that name anywhere
+/// - `/data/tmp` is anchored at the filesystem root
+/// - `*.o` matches files by name anywhere
+///
+/// Include patterns are whitelist entries (like `!pattern` in a gitignore file)
+/// and re-include paths that an exclude pattern would otherwise skip.
+#[derive(Debug)]
+pub struct ExcludeRules {
+    spec: ExcludeSpec,
+    matcher: Gitignore,
+    // Always excluded, e.g. the deldupes database directory.
+    fixed: Vec<PathBuf>,
+}
+
+impl ExcludeRules {
+    pub fn new(mut spec: ExcludeSpec) -> Result<Self> {
+        // Absolute, so the spec still works when a scan is resumed from elsewhere
+        spec.exclude_files = spec
+            .exclude_files
+            .iter()
+            .map(|p| path_utils::normalize_path(p))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut b = GitignoreBuilder::new("/");
+
+        for f in &spec.exclude_files {
+            if let Some(e) = b.add(f) {
+                return Err(anyhow!("Failed to read exclude file {}: {e}", f.display()));
+            }
+        }
+
+        for pat in &spec.excludes {
+            b.add_line(None, pat)
+                .with_context(|| format!("Invalid exclude pattern: {pat}"))?;
+        }
+
+        for pat in &spec.includes {
+            let line = format!("!{pat}");
+            b.add_line(None, &line)
+                .with_context(|| format!("Invalid include pattern: {pat}"))?;
+        }
+
+        let matcher = b.build().context("Failed to build exclude rules")?;
+
+        let fixed = spec
+            .always_excluded
+            .iter()
+            .map(|p| path_utils::normalize_path(p))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self { spec, matcher, fixed })
+    }
+
+    pub fn spec(&self) -> &ExcludeSpec {
+        &self.spec
+    }
+
+    /// Decide for a single entry during traversal. Parents are assumed to be
+    /// already accepted (the walker never descends into excluded directories).
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.fixed.iter().any(|f| path == f) {
+            return true;
+        }
+        self.matcher.matched(path, is_dir).is_ignore()
+    }
+
+    /// Like `is_excluded()`, but also true if any parent directory is excluded.
+    /// `path` is treated as a file.
+    ///
+    /// Used where we have a flat list of paths rather than a walk, e.g. when
+    /// deciding which indexed files may be marked as Missing.
+    pub fn is_excluded_or_under(&self, path: &Path) -> bool {
+        if self.fixed.iter().any(|f| path.starts_with(f)) {
+            return true;
+        }
+        if self.matcher.matched(path, false).is_ignore() {
+            return true;
+        }
+        // Mirror the walker: an excluded directory is never entered, so a
+        // whitelist match further down cannot bring its contents back.
+        path.ancestors()
+            .skip(1)
+            .any(|dir| self.matcher.matched(dir, true).is_ignore())
+    }
+}
+
+/// True if `dir` contains a `.nodeldupes` marker or a valid `CACHEDIR.TAG`.
+pub fn has_skip_marker(dir: &Path) -> bool {
+    if dir.join(NODELDUPES_MARKER).exists() {
+        return true;
+    }
+    is_valid_cachedir_tag(&dir.join(CACHEDIR_TAG))
+}
+
+fn is_valid_cachedir_tag(path: &Path) -> bool {
+    let Ok(f) = std::fs::File::open(path) else {
+        return false;
+    };
+
+    // The spec requires the file to start with the exact signature.
+    let mut buf = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    match f.take(buf.len() as u64).read_exact(&mut buf) {
+        Ok(()) => buf == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,66 @@
+//! Lists of files to scan (`scan --files-from`), one path per line or, with
+//! `-0`, separated by NUL bytes like `find -print0` writes them.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+pub struct FileList {
+    reader: Box<dyn BufRead>,
+    separator: u8,
+    /// Where the list is read from, for error messages
+    source: String,
+}
+
+impl FileList {
+    /// Open the list in `source`; `-` is stdin.
+    pub fn open(source: &Path, nul_separated: bool) -> Result<Self> {
+        let reader: Box<dyn BufRead> = if source == Path::new("-") {
+            Box::new(std::io::stdin().lock())
+        } else {
+            let f = File::open(source).with_context(|| format!("open file list {}", source.display()))?;
+            Box::new(BufReader::new(f))
+        };
+        Ok(Self {
+            reader,
+            separator: if nul_separated { 0 } else { b'\n' },
+            source: source.display().to_string(),
+        })
+    }
+}
+
+impl Iterator for FileList {
+    type Item = Result<PathBuf>;
+
+    /// The next path in the list. Empty entries are skipped.
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match self.reader.read_until(self.separator, &mut buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e).with_context(|| format!("read file list {}", self.source))),
+            }
+            if buf.last() == Some(&self.separator) {
+                buf.pop();
+            }
+            if !buf.is_empty() {
+                return Some(Ok(path_from_bytes(buf)));
+            }
+        }
+    }
+}
+
+/// Paths are taken as they are; they need not be valid UTF-8.
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
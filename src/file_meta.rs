@@ -1,6 +1,14 @@
 use anyhow::{anyhow, Result};
 use crate::types::Hash256;
 
+const FLAG_SHA1PREFIX: u8 = 1;
+const FLAG_INODE: u8 = 1 << 1;
+const FLAG_HASH_PENDING: u8 = 1 << 2;
+const FLAG_SHA1TAIL: u8 = 1 << 3;
+const FLAG_ALLOCATED: u8 = 1 << 4;
+const FLAG_CATALOG: u8 = 1 << 5;
+const FLAG_MEMBER: u8 = 1 << 6;
+const FLAG_SIZE_UNKNOWN: u8 = 1 << 7;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -26,6 +34,51 @@ impl FileState {
 }
 
 
+/// What stat() says about a file when it is scanned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mtime_secs: i64,
+    pub mtime_nsec: u32,
+    pub ctime_secs: i64,
+    pub ctime_nsec: u32,
+    pub ino: u64,
+    pub dev: u64,
+    /// Bytes allocated on disk (`st_blocks` * 512)
+    pub allocated: u64,
+}
+
+impl FileStat {
+    pub fn from_metadata(md: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            size: md.size(),
+            mtime_secs: md.mtime(),
+            mtime_nsec: md.mtime_nsec() as u32,
+            ctime_secs: md.ctime(),
+            ctime_nsec: md.ctime_nsec() as u32,
+            ino: md.ino(),
+            dev: md.dev(),
+            allocated: md.blocks() * 512,
+        }
+    }
+
+    /// True if less is allocated on disk than the file's size. That is so
+    /// for files with holes, but also for compressed and inline files.
+    pub fn allocated_below_size(&self) -> bool {
+        self.allocated < self.size
+    }
+}
+
+/// Inode change time, inode number and device of a stored version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InodeInfo {
+    pub ctime_secs: i64,
+    pub ctime_nsec: u32,
+    pub ino: u64,
+    pub dev: u64,
+}
+
 /// In-memory representation of per-path metadata.
 ///
 /// This is what the rest of the program uses.
@@ -33,53 +86,221 @@ impl FileState {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FileMeta {
     pub size: u64,
-    pub mtime_secs: u64,
-    pub hash256: Hash256,
+    /// Signed, so timestamps before 1970 survive
+    pub mtime_secs: i64,
+    pub mtime_nsec: u32,
+    /// None for versions stored in format v1
+    pub inode: Option<InodeInfo>,
+    /// None while the hash is pending: a lazy scan only hashes a file once
+    /// another file of the same size exists
+    pub hash256: Option<Hash256>,
     pub sha1prefix_4k: Option<[u8; 20]>,
+    /// SHA-1 of the last bytes, computed along with the prefix
+    pub sha1tail: Option<[u8; 20]>,
+    /// Bytes allocated on disk, only kept when less than the size
+    pub allocated: Option<u64>,
+    /// Imported from a manifest (`import-manifest`), not found by a scan;
+    /// there is no file to read or delete
+    pub catalog: bool,
+    /// A file inside an archive (`scan --archives`), stored under a
+    /// virtual path; there is no file of its own to read or delete
+    pub member: bool,
+    /// A catalog entry imported without a size; `size` is 0 and means
+    /// nothing, and the entry is not in the size index
+    pub size_unknown: bool,
 }
 
 impl FileMeta {
     pub fn new(
-        size: u64,
-        mtime_secs: u64,
+        stat: &FileStat,
         hash256: Hash256,
         sha1prefix_4k: Option<[u8; 20]>,
+        sha1tail: Option<[u8; 20]>,
     ) -> Self {
         Self {
-            size,
-            mtime_secs,
-            hash256,
+            size: stat.size,
+            mtime_secs: stat.mtime_secs,
+            mtime_nsec: stat.mtime_nsec,
+            inode: Some(InodeInfo {
+                ctime_secs: stat.ctime_secs,
+                ctime_nsec: stat.ctime_nsec,
+                ino: stat.ino,
+                dev: stat.dev,
+            }),
+            hash256: Some(hash256),
             sha1prefix_4k,
+            sha1tail,
+            allocated: stat.allocated_below_size().then_some(stat.allocated),
+            catalog: false,
+            member: false,
+            size_unknown: false,
+        }
+    }
+
+    /// A catalog entry: a file known only by its hash, and its size if the
+    /// manifest had it.
+    pub fn catalog(size: Option<u64>, hash256: Hash256) -> Self {
+        Self {
+            size: size.unwrap_or(0),
+            mtime_secs: 0,
+            mtime_nsec: 0,
+            inode: None,
+            hash256: Some(hash256),
+            sha1prefix_4k: None,
+            sha1tail: None,
+            allocated: None,
+            catalog: true,
+            member: false,
+            size_unknown: size.is_none(),
+        }
+    }
+
+    /// A version recorded without reading the file.
+    pub fn hash_pending(stat: &FileStat) -> Self {
+        Self {
+            hash256: None,
+            ..Self::new(stat, [0u8; 32], None, None)
+        }
+    }
+
+    /// The size, unless this is a catalog entry imported without one.
+    pub fn known_size(&self) -> Option<u64> {
+        (!self.size_unknown).then_some(self.size)
+    }
+
+    /// The prefix and tail hashes, if both were computed. Same-size files
+    /// that differ in these cannot be duplicates.
+    pub fn head_tail(&self) -> Option<([u8; 20], [u8; 20])> {
+        Some((self.sha1prefix_4k?, self.sha1tail?))
+    }
+
+    /// True if the file on disk is assumed to still have this content.
+    ///
+    /// Compares size, mtime (with nanoseconds), ctime and inode. The device
+    /// is left out, as device numbers are not stable across reboots for all
+    /// filesystems. Versions from format v1 only have whole-second mtimes, so
+    /// for them size + mtime seconds must do, rather than rehashing everything.
+    pub fn matches_stat(&self, stat: &FileStat) -> bool {
+        if self.size != stat.size || self.mtime_secs != stat.mtime_secs {
+            return false;
+        }
+        match &self.inode {
+            None => true,
+            Some(i) => {
+                self.mtime_nsec == stat.mtime_nsec
+                    && i.ctime_secs == stat.ctime_secs
+                    && i.ctime_nsec == stat.ctime_nsec
+                    && i.ino == stat.ino
+            }
+        }
+    }
+
+    /// True if the file still has the size and mtime it was hashed with.
+    ///
+    /// Unlike `matches_stat()`, the ctime and inode are not compared: chmod,
+    /// new hardlinks and some backup tools change them without touching the
+    /// content. Used where a changed file is reported rather than rehashed.
+    pub fn matches_size_mtime(&self, stat: &FileStat) -> bool {
+        self.size == stat.size
+            && self.mtime_secs == stat.mtime_secs
+            && (self.inode.is_none() || self.mtime_nsec == stat.mtime_nsec)
+    }
+
+    /// True if both describe the same file state, by the same rules as
+    /// `matches_stat()`.
+    pub fn same_stat(&self, other: &FileMeta) -> bool {
+        if self.size != other.size || self.mtime_secs != other.mtime_secs {
+            return false;
+        }
+        match (&self.inode, &other.inode) {
+            (Some(a), Some(b)) => {
+                self.mtime_nsec == other.mtime_nsec
+                    && a.ctime_secs == b.ctime_secs
+                    && a.ctime_nsec == b.ctime_nsec
+                    && a.ino == b.ino
+            }
+            _ => true,
         }
     }
 
     /// Encode to a stable on-disk format.
     ///
-    /// Format v1:
+    /// Format v2:
+    /// [0]      u8  version = 2
+    /// [1]      u8  flags (bit0 = has_sha1prefix, bit1 = has_inode, bit2 = hash_pending,
+    ///              bit3 = has_sha1tail, bit4 = has_allocated, bit5 = catalog,
+    ///              bit6 = archive member, bit7 = size unknown)
+    /// [2..10]  u64 size LE
+    /// [10..18] i64 mtime_secs LE
+    /// [18..22] u32 mtime_nsec LE
+    /// [22..54] [u8;32] Blake256 (all zero if hash_pending)
+    /// then     [u8;20] sha1prefix (if has_sha1prefix)
+    /// then     i64 ctime_secs, u32 ctime_nsec, u64 ino, u64 dev, all LE (if has_inode)
+    /// then     [u8;20] sha1tail (if has_sha1tail)
+    /// then     u64 allocated bytes LE (if has_allocated)
+    ///
+    /// Format v1 (read only):
     /// [0]      u8  version = 1
     /// [1]      u8  flags (bit0 = has_sha1prefix)
     /// [2..10]  u64 size LE
-    /// [10..18] i64 mtime_secs LE
+    /// [10..18] u64 mtime_secs LE (0 for timestamps before 1970)
     /// [18..50] [u8;32] Blake256
     /// [50..70] [u8;20] sha1prefix (optional)
     pub fn encode(&self) -> Vec<u8> {
-        let mut out = Vec::with_capacity(70);
-        out.push(1u8);
+        let mut out = Vec::with_capacity(130);
+        out.push(2u8);
 
         let mut flags = 0u8;
         if self.sha1prefix_4k.is_some() {
-            flags |= 1;
+            flags |= FLAG_SHA1PREFIX;
+        }
+        if self.inode.is_some() {
+            flags |= FLAG_INODE;
+        }
+        if self.hash256.is_none() {
+            flags |= FLAG_HASH_PENDING;
+        }
+        if self.sha1tail.is_some() {
+            flags |= FLAG_SHA1TAIL;
+        }
+        if self.allocated.is_some() {
+            flags |= FLAG_ALLOCATED;
+        }
+        if self.catalog {
+            flags |= FLAG_CATALOG;
+        }
+        if self.member {
+            flags |= FLAG_MEMBER;
+        }
+        if self.size_unknown {
+            flags |= FLAG_SIZE_UNKNOWN;
         }
         out.push(flags);
 
         out.extend_from_slice(&self.size.to_le_bytes());
         out.extend_from_slice(&self.mtime_secs.to_le_bytes());
-        out.extend_from_slice(&self.hash256);
+        out.extend_from_slice(&self.mtime_nsec.to_le_bytes());
+        out.extend_from_slice(&self.hash256.unwrap_or([0u8; 32]));
 
         if let Some(p) = &self.sha1prefix_4k {
             out.extend_from_slice(p);
         }
 
+        if let Some(i) = &self.inode {
+            out.extend_from_slice(&i.ctime_secs.to_le_bytes());
+            out.extend_from_slice(&i.ctime_nsec.to_le_bytes());
+            out.extend_from_slice(&i.ino.to_le_bytes());
+            out.extend_from_slice(&i.dev.to_le_bytes());
+        }
+
+        if let Some(t) = &self.sha1tail {
+            out.extend_from_slice(t);
+        }
+
+        if let Some(a) = self.allocated {
+            out.extend_from_slice(&a.to_le_bytes());
+        }
+
         out
     }
 
@@ -91,10 +312,100 @@ impl FileMeta {
         let version = bytes[0];
         match version {
             1 => Self::decode_v1(bytes),
+            2 => Self::decode_v2(bytes),
             _ => Err(anyhow!("unknown file_meta version: {}", version)),
         }
     }
 
+    fn decode_v2(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 54 {
+            return Err(anyhow!("file_meta v2 too short: {} bytes", bytes.len()));
+        }
+
+        let flags = bytes[1];
+        let has_prefix = (flags & FLAG_SHA1PREFIX) != 0;
+        let has_inode = (flags & FLAG_INODE) != 0;
+        let hash_pending = (flags & FLAG_HASH_PENDING) != 0;
+        let has_tail = (flags & FLAG_SHA1TAIL) != 0;
+        let has_allocated = (flags & FLAG_ALLOCATED) != 0;
+
+        let expected = 54
+            + if has_prefix { 20 } else { 0 }
+            + if has_inode { 28 } else { 0 }
+            + if has_tail { 20 } else { 0 }
+            + if has_allocated { 8 } else { 0 };
+        if bytes.len() < expected {
+            return Err(anyhow!(
+                "file_meta v2 with flags {flags:#04x} needs {expected} bytes, got {}",
+                bytes.len()
+            ));
+        }
+
+        let u64_at = |pos: usize| {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&bytes[pos..pos + 8]);
+            u64::from_le_bytes(arr)
+        };
+        let u32_at = |pos: usize| {
+            let mut arr = [0u8; 4];
+            arr.copy_from_slice(&bytes[pos..pos + 4]);
+            u32::from_le_bytes(arr)
+        };
+
+        let size = u64_at(2);
+        let mtime_secs = u64_at(10) as i64;
+        let mtime_nsec = u32_at(18);
+
+        let mut hash256 = [0u8; 32];
+        hash256.copy_from_slice(&bytes[22..54]);
+        let hash256 = (!hash_pending).then_some(hash256);
+
+        let mut pos = 54;
+        let sha1prefix_4k = if has_prefix {
+            let mut p = [0u8; 20];
+            p.copy_from_slice(&bytes[pos..pos + 20]);
+            pos += 20;
+            Some(p)
+        } else {
+            None
+        };
+
+        let inode = has_inode.then(|| InodeInfo {
+            ctime_secs: u64_at(pos) as i64,
+            ctime_nsec: u32_at(pos + 8),
+            ino: u64_at(pos + 12),
+            dev: u64_at(pos + 20),
+        });
+        if has_inode {
+            pos += 28;
+        }
+
+        let sha1tail = has_tail.then(|| {
+            let mut t = [0u8; 20];
+            t.copy_from_slice(&bytes[pos..pos + 20]);
+            t
+        });
+        if has_tail {
+            pos += 20;
+        }
+
+        let allocated = has_allocated.then(|| u64_at(pos));
+
+        Ok(Self {
+            size,
+            mtime_secs,
+            mtime_nsec,
+            inode,
+            hash256,
+            sha1prefix_4k,
+            sha1tail,
+            allocated,
+            catalog: (flags & FLAG_CATALOG) != 0,
+            member: (flags & FLAG_MEMBER) != 0,
+            size_unknown: (flags & FLAG_SIZE_UNKNOWN) != 0,
+        })
+    }
+
     fn decode_v1(bytes: &[u8]) -> Result<Self> {
         if bytes.len() < 50 {
             return Err(anyhow!("file_meta v1 too short: {} bytes", bytes.len()));
@@ -111,7 +422,7 @@ impl FileMeta {
         // mtime
         let mut mt_arr = [0u8; 8];
         mt_arr.copy_from_slice(&bytes[10..18]);
-        let mtime_secs = u64::from_le_bytes(mt_arr);
+        let mtime_secs = u64::from_le_bytes(mt_arr) as i64;
 
         // hash
         let mut hash256 = [0u8; 32];
@@ -135,8 +446,65 @@ impl FileMeta {
         Ok(Self {
             size,
             mtime_secs,
-            hash256,
+            mtime_nsec: 0,
+            inode: None,
+            hash256: Some(hash256),
             sha1prefix_4k,
+            sha1tail: None,
+            allocated: None,
+            catalog: false,
+            member: false,
+            size_unknown: false,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat() -> FileStat {
+        FileStat {
+            size: 1 << 20,
+            mtime_secs: -86_400,
+            mtime_nsec: 999_999_999,
+            ctime_secs: 1_700_000_000,
+            ctime_nsec: 5,
+            ino: 42,
+            dev: 7,
+            allocated: 4096,
+        }
+    }
+
+    fn round_trip(m: &FileMeta) {
+        assert_eq!(&FileMeta::decode(&m.encode()).unwrap(), m);
+    }
+
+    #[test]
+    fn encode_decode_round_trips() {
+        let full = FileMeta::new(&stat(), [1; 32], Some([2; 20]), Some([3; 20]));
+        assert_eq!(full.allocated, Some(4096));
+        round_trip(&full);
+        round_trip(&FileMeta::new(&stat(), [1; 32], None, None));
+        round_trip(&FileMeta::hash_pending(&stat()));
+        round_trip(&FileMeta::catalog(Some(10), [4; 32]));
+        let unknown = FileMeta::catalog(None, [4; 32]);
+        assert_eq!(unknown.known_size(), None);
+        round_trip(&unknown);
+        round_trip(&FileMeta {
+            inode: None,
+            member: true,
+            ..FileMeta::new(&stat(), [5; 32], None, None)
+        });
+    }
+
+    #[test]
+    fn decode_rejects_short_records() {
+        let mut bytes = FileMeta::new(&stat(), [1; 32], Some([2; 20]), None).encode();
+        bytes.pop();
+        assert!(FileMeta::decode(&bytes).is_err());
+        assert!(FileMeta::decode(&[2; 10]).is_err());
+        bytes[0] = 9;
+        assert!(FileMeta::decode(&bytes).is_err());
+    }
+}
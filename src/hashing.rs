@@ -1,42 +1,154 @@
-use crate::file_meta::FileMeta;
+use crate::file_meta::{FileMeta, FileStat};
 use crate::types::Hash256;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use memmap2::Mmap;
 use sha1::Digest as Sha1Digest;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
 
 const MMAP_THRESHOLD: u64 = 32 * 1024 * 1024; // 32 MiB
 const READ_BUF_SIZE: usize = 1024 * 1024;     // 1 MiB
 const SHA1_READ_SIZE: usize = 32 * 1024;    // 32 KiB
 
+/// Paces the reads of all hash workers together.
+struct ReadLimit {
+    bytes_per_sec: u64,
+    /// When the next read may start
+    next: Mutex<Instant>,
+}
+
+static READ_LIMIT: OnceLock<ReadLimit> = OnceLock::new();
+
+/// Limit how fast files are read for hashing, in bytes per second, summed
+/// over all threads. Call once, before hashing starts.
+pub fn set_read_limit(bytes_per_sec: u64) {
+    let _ = READ_LIMIT.set(ReadLimit {
+        bytes_per_sec: bytes_per_sec.max(1),
+        next: Mutex::new(Instant::now()),
+    });
+}
+
+pub fn read_limit() -> Option<u64> {
+    READ_LIMIT.get().map(|l| l.bytes_per_sec)
+}
+
+/// Wait until `bytes` more may be read.
+fn throttle(bytes: usize) {
+    let Some(limit) = READ_LIMIT.get() else {
+        return;
+    };
+    let cost = Duration::from_secs_f64(bytes as f64 / limit.bytes_per_sec as f64);
+    let start = {
+        let mut next = limit.next.lock().unwrap_or_else(PoisonError::into_inner);
+        // Time nobody read in is not saved up for later
+        let start = (*next).max(Instant::now());
+        *next = start + cost;
+        start
+    };
+    let now = Instant::now();
+    if start > now {
+        std::thread::sleep(start - now);
+    }
+}
+
+/// Run the calling thread with idle I/O priority and the lowest CPU
+/// priority, so other work on the machine goes first.
+///
+/// The idle I/O class is only honored by I/O schedulers that support
+/// priorities (BFQ, CFQ). Failures are logged and otherwise ignored.
+#[cfg(target_os = "linux")]
+pub fn lower_thread_priority() {
+    // From <linux/ioprio.h>
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+
+    // SAFETY: plain syscalls on the calling thread (0 and gettid() both mean "this thread").
+    unsafe {
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT) != 0 {
+            tracing::debug!("ioprio_set failed: {}", std::io::Error::last_os_error());
+        }
+        if libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, 19) != 0 {
+            tracing::debug!("setpriority failed: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lower_thread_priority() {
+    // SAFETY: plain syscall; affects the calling process.
+    unsafe {
+        let _ = libc::nice(19);
+    }
+}
+
 /// Hash a file and return its FileMeta.
 ///
 /// - hash256: full-file hash (currently BLAKE3-256)
 /// - sha1prefix_32k: SHA-1 of first SHA1_READ_SIZE bytes if size > SHA1_READ_SIZE, else None
+/// - sha1tail: SHA-1 of the last SHA1_READ_SIZE bytes, under the same condition
 ///
-/// `mtime_secs` and `size` are passed in from the caller (which already stat()'d the file).
-pub fn hash_file(path: &Path, mtime_secs: u64, size: u64) -> Result<FileMeta> {
-    let sha1prefix_32k = if size > SHA1_READ_SIZE as u64 {
-        Some(hash_prefix_sha1(path)?)
-    } else {
-        None
+/// `stat` is passed in from the caller (which already stat()'d the file).
+pub fn hash_file(path: &Path, stat: &FileStat) -> Result<FileMeta> {
+    let (sha1prefix_32k, sha1tail) = match has_head_tail(stat.size) {
+        true => {
+            let (head, tail) = hash_head_tail_sha1(path, stat.size)?;
+            (Some(head), Some(tail))
+        }
+        false => (None, None),
     };
 
     let hash256 = hash256_file_hybrid(path, CacheAdvice::SequentialNoReuseAndDrop)?;
-    Ok(FileMeta::new(size, mtime_secs, hash256, sha1prefix_32k))
+    Ok(FileMeta::new(stat, hash256, sha1prefix_32k, sha1tail))
 }
 
-fn hash_prefix_sha1(path: &Path) -> Result<[u8; 20]> {
+/// Only the prefix hash, to tell same-size files apart cheaply; the caller
+/// must check `has_head_tail()` first.
+pub fn hash_head(path: &Path) -> Result<[u8; 20]> {
     let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
-    let mut r = BufReader::new(f);
+    sha1_at(&f, 0).with_context(|| format!("read prefix {}", path.display()))
+}
 
-    let mut buf = [0u8; SHA1_READ_SIZE];
-    let n = r
-        .read(&mut buf)
+/// Only the tail hash, for same-size files whose prefix hashes match.
+pub fn hash_tail(path: &Path, size: u64) -> Result<[u8; 20]> {
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    sha1_at(&f, size.saturating_sub(SHA1_READ_SIZE as u64))
+        .with_context(|| format!("read tail {}", path.display()))
+}
+
+/// True if files of this size get prefix and tail hashes. Smaller files are
+/// read completely in less time than the two extra reads take.
+pub fn has_head_tail(size: u64) -> bool {
+    size > SHA1_READ_SIZE as u64
+}
+
+fn hash_head_tail_sha1(path: &Path, size: u64) -> Result<([u8; 20], [u8; 20])> {
+    let f = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let head = sha1_at(&f, 0)
         .with_context(|| format!("read prefix {}", path.display()))?;
+    let tail = sha1_at(&f, size.saturating_sub(SHA1_READ_SIZE as u64))
+        .with_context(|| format!("read tail {}", path.display()))?;
+    Ok((head, tail))
+}
+
+/// SHA-1 of up to SHA1_READ_SIZE bytes from `offset`.
+fn sha1_at(f: &File, offset: u64) -> std::io::Result<[u8; 20]> {
+    throttle(SHA1_READ_SIZE);
+    let mut buf = [0u8; SHA1_READ_SIZE];
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read_at(&mut buf[n..], offset + n as u64) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
 
     let mut h = sha1::Sha1::new();
     h.update(&buf[..n]);
@@ -47,13 +159,77 @@ fn hash_prefix_sha1(path: &Path) -> Result<[u8; 20]> {
     Ok(out)
 }
 
+/// Hashes of data that is read as a stream, like an archive member.
+pub struct StreamHashes {
+    pub size: u64,
+    pub hash256: Hash256,
+    pub sha1prefix: Option<[u8; 20]>,
+    pub sha1tail: Option<[u8; 20]>,
+}
+
+/// Hash everything `r` yields, with the same prefix and tail hashes as
+/// `hash_file()` computes for a file of that size.
+pub fn hash_reader(mut r: impl Read) -> std::io::Result<StreamHashes> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut head: Vec<u8> = Vec::with_capacity(SHA1_READ_SIZE);
+    let mut tail: Vec<u8> = Vec::new();
+    let mut size = 0u64;
+
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        throttle(n);
+        hasher.update(&buf[..n]);
+        size += n as u64;
+
+        if head.len() < SHA1_READ_SIZE {
+            let take = n.min(SHA1_READ_SIZE - head.len());
+            head.extend_from_slice(&buf[..take]);
+        }
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > 2 * SHA1_READ_SIZE {
+            tail.drain(..tail.len() - SHA1_READ_SIZE);
+        }
+    }
+    if tail.len() > SHA1_READ_SIZE {
+        tail.drain(..tail.len() - SHA1_READ_SIZE);
+    }
+
+    let sha1 = |data: &[u8]| {
+        let mut out = [0u8; 20];
+        out.copy_from_slice(&sha1::Sha1::digest(data)[..]);
+        out
+    };
+    let (sha1prefix, sha1tail) = match has_head_tail(size) {
+        true => (Some(sha1(&head)), Some(sha1(&tail))),
+        false => (None, None),
+    };
+
+    Ok(StreamHashes {
+        size,
+        hash256: *hasher.finalize().as_bytes(),
+        sha1prefix,
+        sha1tail,
+    })
+}
+
+/// True if this is the hash of no bytes at all: a file with it is empty.
+pub fn is_empty_hash(hash256: &Hash256) -> bool {
+    hash256 == blake3::hash(b"").as_bytes()
+}
+
 pub fn hash_full_hash256(path: &Path) -> Result<Hash256> {
     hash256_file_hybrid(path, CacheAdvice::SequentialNoReuseAndDrop)
 }
 
 /// Controls how aggressively we ask the kernel to keep/drop cache.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum CacheAdvice {
     /// Just say "sequential". Lowest risk.
     SequentialOnly,
@@ -66,14 +242,17 @@ pub enum CacheAdvice {
 
 pub fn hash256_file_hybrid(path: &Path, advice: CacheAdvice) -> Result<Hash256> {
     let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
-    let len = file.metadata()?.len();
+    let stat = FileStat::from_metadata(&file.metadata()?);
+    let len = stat.size;
 
     advise_sequential(&file, advice);
 
-    let out = if len >= MMAP_THRESHOLD {
+    let out = if stat.allocated_below_size() && has_holes(&file, len) {
+        hash256_sparse(&file, path, len)
+    } else if len >= MMAP_THRESHOLD {
         hash256_mmap(&file, path)
     } else {
-        hash256_stream(&file, path)
+        hash256_stream(&file, path, len)
     }?;
 
     advise_done(&file, advice);
@@ -86,30 +265,128 @@ fn hash256_mmap(file: &File, path: &Path) -> Result<Hash256> {
     madvise_sequential(&mmap);
 
     let mut hasher = blake3::Hasher::new();
-    hasher.update(&mmap);
+    for chunk in mmap.chunks(READ_BUF_SIZE) {
+        throttle(chunk.len());
+        hasher.update(chunk);
+    }
 
     Ok(*hasher.finalize().as_bytes())
 }
 
-fn hash256_stream(file: &File, path: &Path) -> Result<Hash256> {
+fn hash256_stream(file: &File, path: &Path, len: u64) -> Result<Hash256> {
     // NOTE: this requires BufReader to own the file. Use try_clone() to keep your signature.
     let file2 = file.try_clone().with_context(|| format!("try_clone {}", path.display()))?;
     let mut r = BufReader::with_capacity(READ_BUF_SIZE, file2);
 
     let mut hasher = blake3::Hasher::new();
     let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut hashed = 0u64;
 
     loop {
         let n = r.read(&mut buf).with_context(|| format!("read {}", path.display()))?;
         if n == 0 {
             break;
         }
+        throttle(n);
         hasher.update(&buf[..n]);
+        hashed += n as u64;
+    }
+    if hashed != len {
+        return Err(size_changed(path, hashed, len));
     }
 
     Ok(*hasher.finalize().as_bytes())
 }
 
+/// The file was truncated or grew while it was hashed, so the hash is of
+/// neither the old nor the new content.
+fn size_changed(path: &Path, hashed: u64, len: u64) -> anyhow::Error {
+    anyhow!("{} changed size while hashed: read {hashed} of {len} bytes", path.display())
+}
+
+/// True if the file has a hole before its end. Less allocated than its size
+/// is not enough to tell: compressed and inline files have that too.
+#[cfg(target_os = "linux")]
+fn has_holes(file: &File, len: u64) -> bool {
+    let fd = file.as_raw_fd();
+    // SAFETY: lseek() on a descriptor we own; it only moves the file offset,
+    // which is put back for the readers that use it.
+    let hole = unsafe { libc::lseek(fd, 0, libc::SEEK_HOLE) };
+    unsafe { libc::lseek(fd, 0, libc::SEEK_SET) };
+    hole >= 0 && (hole as u64) < len
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_holes(_file: &File, _len: u64) -> bool {
+    false
+}
+
+/// Hash a file with holes, reading only its data regions. Holes read as
+/// zeros, so they are hashed as zeros, giving the same hash as a full read.
+#[cfg(target_os = "linux")]
+fn hash256_sparse(file: &File, path: &Path, len: u64) -> Result<Hash256> {
+    static ZEROS: [u8; READ_BUF_SIZE] = [0u8; READ_BUF_SIZE];
+
+    let fd = file.as_raw_fd();
+    let seek = |pos: u64, whence: libc::c_int| -> std::io::Result<u64> {
+        // SAFETY: lseek() on a descriptor we own; it only moves the file offset.
+        let r = unsafe { libc::lseek(fd, pos as libc::off_t, whence) };
+        if r < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(r as u64)
+        }
+    };
+
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut pos = 0u64;
+
+    while pos < len {
+        let (data, hole) = match seek(pos, libc::SEEK_DATA) {
+            Ok(data) => (data.min(len), seek(data, libc::SEEK_HOLE).unwrap_or(len).min(len)),
+            // Only holes from here to the end
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => (len, len),
+            // The file system cannot tell; read the rest
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (pos, len),
+            Err(e) => return Err(e).with_context(|| format!("seek {}", path.display())),
+        };
+
+        let mut zeros = data - pos;
+        while zeros > 0 {
+            let n = zeros.min(READ_BUF_SIZE as u64) as usize;
+            hasher.update(&ZEROS[..n]);
+            zeros -= n as u64;
+        }
+
+        pos = data;
+        while pos < hole {
+            let want = (hole - pos).min(READ_BUF_SIZE as u64) as usize;
+            throttle(want);
+            let n = file
+                .read_at(&mut buf[..want], pos)
+                .with_context(|| format!("read {}", path.display()))?;
+            if n == 0 {
+                return Err(size_changed(path, pos, len));
+            }
+            hasher.update(&buf[..n]);
+            pos += n as u64;
+        }
+    }
+    // Cut short in a hole, it would read as holes to the end
+    let now = file.metadata().with_context(|| format!("stat {}", path.display()))?.len();
+    if now != len {
+        return Err(size_changed(path, now.min(len), len));
+    }
+
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hash256_sparse(file: &File, path: &Path, len: u64) -> Result<Hash256> {
+    hash256_stream(file, path, len)
+}
+
 fn advise_sequential(file: &File, advice: CacheAdvice) {
     let fd = file.as_raw_fd();
     unsafe {
@@ -139,3 +416,72 @@ fn madvise_sequential(mmap: &Mmap) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::io::{Seek, SeekFrom, Write};
+
+    /// A file of `len` bytes with `data` at `at`, the rest a hole where
+    /// the filesystem supports them.
+    fn sparse_file(dir: &TestDir, name: &str, len: u64, at: u64, data: &[u8]) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        let mut f = File::create(&path).unwrap();
+        f.set_len(len).unwrap();
+        f.seek(SeekFrom::Start(at)).unwrap();
+        f.write_all(data).unwrap();
+        path
+    }
+
+    #[test]
+    fn sparse_and_dense_copies_hash_the_same() {
+        let dir = TestDir::new();
+        let len = 8 * READ_BUF_SIZE as u64;
+        let at = 3 * READ_BUF_SIZE as u64 + 17;
+        let sparse = sparse_file(&dir, "sparse", len, at, b"data in the middle");
+
+        let mut dense = vec![0u8; len as usize];
+        dense[at as usize..at as usize + 18].copy_from_slice(b"data in the middle");
+        std::fs::write(dir.path().join("dense"), &dense).unwrap();
+
+        let expected = *blake3::hash(&dense).as_bytes();
+        let f = File::open(&sparse).unwrap();
+        assert_eq!(hash256_sparse(&f, &sparse, len).unwrap(), expected);
+        assert_eq!(hash_full_hash256(&sparse).unwrap(), expected);
+        assert_eq!(hash_full_hash256(&dir.path().join("dense")).unwrap(), expected);
+    }
+
+    #[test]
+    fn holes_are_found_and_the_offset_put_back() {
+        let dir = TestDir::new();
+        let len = 4 * READ_BUF_SIZE as u64;
+        let sparse = sparse_file(&dir, "sparse", len, len - 4, b"tail");
+        let f = File::open(&sparse).unwrap();
+        // Not all filesystems have holes; where they do, this one has
+        if FileStat::from_metadata(&f.metadata().unwrap()).allocated_below_size() {
+            assert!(has_holes(&f, len));
+        }
+        let mut head = [1u8; 4];
+        (&f).read_exact(&mut head).unwrap();
+        assert_eq!(head, [0; 4]);
+
+        std::fs::write(dir.path().join("dense"), vec![1u8; 4096]).unwrap();
+        assert!(!has_holes(&File::open(dir.path().join("dense")).unwrap(), 4096));
+    }
+
+    #[test]
+    fn a_size_change_while_hashing_is_an_error() {
+        let dir = TestDir::new();
+        let path = dir.path().join("f");
+        std::fs::write(&path, vec![7u8; 1000]).unwrap();
+        let open = || File::open(&path).unwrap();
+        // As if stat() had seen it before it was truncated or grew
+        assert!(hash256_stream(&open(), &path, 2000).is_err());
+        assert!(hash256_stream(&open(), &path, 500).is_err());
+        assert!(hash256_stream(&open(), &path, 1000).is_ok());
+        assert!(hash256_sparse(&open(), &path, 2000).is_err());
+        assert!(hash256_sparse(&open(), &path, 500).is_err());
+        assert!(hash256_sparse(&open(), &path, 1000).is_ok());
+    }
+}
@@ -1,7 +1,15 @@
 use anyhow::Result;
+use std::fmt::Write as _;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{fmt, EnvFilter};
 
-pub fn init(verbose: u8) -> Result<()> {
+/// With `json`, log records are written as JSON events like the progress
+/// events, so stderr stays one JSON object per line.
+pub fn init(verbose: u8, json: bool) -> Result<()> {
     // Base filter:
     // - if RUST_LOG is set, use it
     // - else default to "info" (or "debug" with -v)
@@ -14,11 +22,132 @@ pub fn init(verbose: u8) -> Result<()> {
     let filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new(default_level));
 
-    fmt()
+    let builder = fmt()
         .with_env_filter(filter)
         .with_target(false) // cleaner output: omit crate/module path
-        .compact()
-        .init();
+        .with_writer(|| crate::progress::LogWriter);
+    if json {
+        builder.with_ansi(false).event_format(JsonLog).init();
+    } else {
+        builder.compact().init();
+    }
 
     Ok(())
 }
+
+/// One log record as a single line of JSON.
+///
+/// Format:
+/// {"event":"log","time":"2024-05-01T12:00:00.000Z","level":"info","message":"...",
+///  "fields":{"name":value,...}}
+///
+/// Numbers and booleans are JSON values; other fields are strings.
+struct JsonLog;
+
+impl<S, N> FormatEvent<S, N> for JsonLog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+
+        let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let level = event.metadata().level().as_str().to_ascii_lowercase();
+        writeln!(
+            writer,
+            "{{\"event\":\"log\",\"time\":\"{time}\",\"level\":\"{level}\",\"message\":{},\"fields\":{{{}}}}}",
+            crate::progress::json_string(&fields.message),
+            fields.rest
+        )
+    }
+}
+
+#[derive(Default)]
+struct JsonFields {
+    message: String,
+    /// `"name":value` pairs, comma separated
+    rest: String,
+}
+
+impl JsonFields {
+    fn add(&mut self, field: &Field, value: &str) {
+        if !self.rest.is_empty() {
+            self.rest.push(',');
+        }
+        let _ = write!(self.rest, "{}:{value}", crate::progress::json_string(field.name()));
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.add(field, &crate::progress::json_string(value));
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.add(field, &value.to_string());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add(field, &value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add(field, &value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_are_one_json_line_each() {
+        let buf = Buf::default();
+        let out = buf.clone();
+        let subscriber = fmt()
+            .with_ansi(false)
+            .with_writer(move || out.clone())
+            .event_format(JsonLog)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(count = 3, ok = true, path = "a \"b\"\n", "skipping {}", "x");
+        });
+
+        let text = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let line = text.strip_suffix('\n').unwrap();
+        assert!(!line.contains('\n'));
+        assert!(line.starts_with("{\"event\":\"log\",\"time\":\""), "{line}");
+        assert!(
+            line.ends_with(
+                "\"level\":\"warn\",\"message\":\"skipping x\",\
+                 \"fields\":{\"count\":3,\"ok\":true,\"path\":\"a \\\"b\\\"\\n\"}}"
+            ),
+            "{line}"
+        );
+    }
+}
@@ -2,16 +2,27 @@ use anyhow::{anyhow, Context, Result};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+mod checkpoint;
 mod codec;
+mod archive;
 mod db;
+mod device;
 mod dbpath;
+mod exclude;
+mod file_list;
 mod file_meta;
 mod hashing;
 mod logging;
+mod manifest;
 mod scan;
+mod scan_errors;
+mod scan_plan;
 mod schema;
+mod session;
+mod snapshot;
 mod dupes;
 mod potential;
+mod progress;
 mod path_filter;
 mod path_utils;
 mod stats;
@@ -20,6 +31,9 @@ mod delete;
 mod check;
 mod types;
 mod util;
+mod verify;
+mod walker;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(name = "deldupes")]
@@ -36,6 +50,13 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// How long-running commands report progress on stderr.
+    ///
+    /// `json` writes one JSON object per line, for use by other programs;
+    /// log messages are then written as JSON objects too.
+    #[arg(long, global = true, value_enum, default_value_t = progress::ProgressMode::Auto)]
+    progress: progress::ProgressMode,
+
     #[command(subcommand)]
     cmd: Command,
 }
@@ -47,10 +68,65 @@ enum Command {
         /// One or more root paths to scan
         paths: Vec<PathBuf>,
 
-        /// Number of hashing worker threads (defaults to CPU count - 1, min 1)
+        /// Continue an interrupted scan, with its roots and options
+        #[arg(long, conflicts_with_all = [
+            "paths", "follow_symlinks", "no_recursive", "detect_deletes", "one_file_system",
+            "exclude", "include", "exclude_from", "watch", "min_size", "max_size", "index_empty",
+            "lazy", "order", "archives", "files_from",
+        ])]
+        resume: bool,
+
+        /// Scan the files listed in FILE (`-` for stdin), one per line,
+        /// instead of walking directories
+        #[arg(long, value_name = "FILE", conflicts_with_all = [
+            "paths", "plan", "watch", "no_recursive", "one_file_system",
+        ])]
+        files_from: Option<PathBuf>,
+
+        /// With --files-from, the paths are separated by NUL bytes (as
+        /// written by `find -print0`)
+        #[arg(short = '0', long = "null", requires = "files_from")]
+        null: bool,
+
+        /// Only walk the roots and report what the scan would hash and mark
+        /// as missing; nothing is hashed or written to the database
+        #[arg(long, conflicts_with_all = ["resume", "watch"])]
+        plan: bool,
+
+        /// With --plan, print the decision for each file and the reason
+        #[arg(long, requires = "plan")]
+        explain: bool,
+
+        /// After scanning, keep watching the roots and index changes as they
+        /// happen, until interrupted (Linux only)
+        #[arg(long, default_value_t = false)]
+        watch: bool,
+
+        /// Number of hashing worker threads per disk (defaults to CPU count - 1, min 1)
         #[arg(long)]
         threads: Option<usize>,
 
+        /// Number of hashing worker threads per rotational disk
+        #[arg(long, default_value_t = 1)]
+        hdd_threads: usize,
+
+        /// Number of threads reading directories during the walk, for
+        /// network filesystems and trees with very many small files. Not
+        /// with --watch, which must watch each directory before it is read,
+        /// while these threads read ahead of the scan
+        #[arg(long, value_name = "N", default_value_t = 1, conflicts_with = "watch")]
+        walk_threads: usize,
+
+        /// Limit how fast files are read for hashing, per second over all
+        /// workers (e.g. 50m)
+        #[arg(long, value_name = "SIZE", value_parser = util::parse_size)]
+        max_read_rate: Option<u64>,
+
+        /// Hash with idle I/O priority and the lowest CPU priority, so other
+        /// work on the machine goes first
+        #[arg(long, default_value_t = false)]
+        idle: bool,
+
         /// Follow symlinks during traversal
         #[arg(long, default_value_t = false)]
         follow_symlinks: bool,
@@ -63,6 +139,48 @@ enum Command {
         #[arg(long = "no-detect-deletes", action = clap::ArgAction::SetFalse, default_value_t = true)]
         detect_deletes: bool,
 
+        /// Stay on the filesystem of each root; do not descend into other mounts
+        #[arg(short = 'x', long, default_value_t = false)]
+        one_file_system: bool,
+
+        /// Only index files of at least this size (e.g. 10m, 1.3g)
+        #[arg(long, value_parser = util::parse_size)]
+        min_size: Option<u64>,
+
+        /// Only index files of at most this size (e.g. 10m, 1.3g)
+        #[arg(long, value_parser = util::parse_size)]
+        max_size: Option<u64>,
+
+        /// Also index empty files, so they can be listed and deleted as duplicates
+        #[arg(long, default_value_t = false)]
+        index_empty: bool,
+
+        /// Only hash a file once another file of the same size is found;
+        /// until then, just record its size and modification time
+        #[arg(long, default_value_t = false)]
+        lazy: bool,
+
+        /// Order in which changed files are hashed; inode or physical turn
+        /// random seeks into mostly sequential reads on spinning disks
+        #[arg(long, value_enum, default_value_t = session::ScanOrder::Walk)]
+        order: session::ScanOrder,
+
+        /// Also index the files inside zip, tar and tar.gz archives, so copies
+        /// of them can be found and deleted (the archives are never changed)
+        #[arg(long, default_value_t = false)]
+        archives: bool,
+
+        /// Exclude paths matching this gitignore-style pattern (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Re-include paths matching this pattern, overriding excludes (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Read exclude patterns from a file in gitignore syntax (repeatable)
+        #[arg(long, value_name = "FILE")]
+        exclude_from: Vec<PathBuf>,
     },
 
     /// List duplicate files (by BLAKE3-256)
@@ -91,6 +209,11 @@ enum Command {
         /// Only show entries with size <= this value (e.g. 10m, 1.3g)
         #[arg(long, value_parser = util::parse_size)]
         max_size: Option<u64>,
+
+        /// Also require the last 32 KiB to match, leaving out files that
+        /// only share a header
+        #[arg(long, default_value_t = false)]
+        match_tail: bool,
     },
 
     /// Safely delete duplicate files (dry-run by default)
@@ -106,11 +229,16 @@ enum Command {
         /// Which file to preserve when we must keep one.
         #[arg(long, value_enum, default_value_t = delete::Preserve::Oldest)]
         preserve: delete::Preserve,
+
+        /// Also delete names whose file keeps another hardlink (frees nothing)
+        #[arg(long, default_value_t = false)]
+        unlink_hardlinks: bool,
     },
 
     /// Check whether files exist in the database:
     /// 1) try path + (size,mtime)
     /// 2) otherwise hash and try hash256 lookup
+    ///
     /// Also prints duplicates for the file's checksum.
     Check {
         /// One or more file paths to check
@@ -132,6 +260,67 @@ enum Command {
         quiet: bool,
     },
 
+    /// Re-read indexed files and report those whose content no longer
+    /// matches their hash, although size and mtime are unchanged
+    Verify {
+        /// Optional path prefixes: only verify files under these paths
+        paths: Vec<PathBuf>,
+
+        /// Read at most about this much (e.g. 200g), starting with the files
+        /// verified longest ago, so repeated runs cover everything in turn
+        #[arg(long, value_parser = util::parse_size)]
+        budget: Option<u64>,
+    },
+
+    /// Import a BLAKE3 manifest (`b3sum` output) of a drive that is not
+    /// connected, so its files count as copies in `dupes` and `check`
+    ImportManifest {
+        /// The manifest file (`-` for stdin)
+        file: PathBuf,
+
+        /// Where the files of the manifest are put in the catalog, like the
+        /// mount point of the drive (e.g. /offsite/disk7)
+        #[arg(long)]
+        prefix: PathBuf,
+
+        /// Lines have the size in bytes between the hash and the path
+        #[arg(long, default_value_t = false)]
+        sizes: bool,
+    },
+
+    /// Write the hashes of indexed files as a manifest that `b3sum --check`
+    /// can verify, without reading the files
+    ExportManifest {
+        /// Optional path prefixes: only export files under these paths
+        paths: Vec<PathBuf>,
+
+        /// Write paths relative to this directory, and leave out files
+        /// outside it
+        #[arg(long, value_name = "DIR", conflicts_with = "sidecars")]
+        relative_to: Option<PathBuf>,
+
+        /// Instead of printing one manifest, write one into each directory
+        /// for the files in it, with this name
+        #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true, default_missing_value = "checksums.b3")]
+        sidecars: Option<String>,
+    },
+
+    /// List files and directories that the last scan could not process
+    Errors {
+        /// Optional path prefixes: only show errors under these paths
+        paths: Vec<PathBuf>,
+
+        /// Only show errors of this kind (repeatable)
+        #[arg(long, value_enum)]
+        kind: Vec<scan_errors::ErrorKind>,
+    },
+
+    /// List past scans, or show one scan session in detail
+    History {
+        /// Session id to show (omit to list all sessions)
+        id: Option<u64>,
+    },
+
     /// Show statistics about files, duplicates and reclaimable space
     Stats,
 
@@ -141,14 +330,15 @@ enum Command {
 
 fn main() {
     if let Err(e) = run() {
-        eprintln!("{:#}", e);
+        progress::print_error(&e);
         std::process::exit(1);
     }
 }
 
 fn run() -> Result<()> {
     let cli = Cli::parse();
-    logging::init(cli.verbose)?;
+    progress::init(cli.progress);
+    logging::init(cli.verbose, progress::is_json())?;
 
     // Resolve the DB directory according to our rules.
     let db_dir = dbpath::resolve_db_dir(&cli.db)
@@ -157,36 +347,118 @@ fn run() -> Result<()> {
     match cli.cmd {
         Command::Scan {
             paths,
+            resume,
+            files_from,
+            null,
+            plan,
+            explain,
+            watch,
             threads,
+            hdd_threads,
+            walk_threads,
+            max_read_rate,
+            idle,
             follow_symlinks,
             no_recursive,
-            detect_deletes
+            detect_deletes,
+            one_file_system,
+            min_size,
+            max_size,
+            index_empty,
+            lazy,
+            order,
+            archives,
+            exclude,
+            include,
+            exclude_from,
         } => {
-            if paths.is_empty() {
+            if paths.is_empty() && !resume && files_from.is_none() {
                 return Err(anyhow!("scan requires at least one path"));
             }
 
+            if let (Some(min), Some(max)) = (min_size, max_size)
+                && min > max
+            {
+                return Err(anyhow!("--min-size cannot be greater than --max-size"));
+            }
+
             let threads = match threads {
                 Some(n) => n.max(1),
                 None => std::thread::available_parallelism()
                     .map(|n| n.get().saturating_sub(1).max(1))
                     .unwrap_or(1),
             };
+            let hdd_threads = hdd_threads.max(1);
+            let walk_threads = walk_threads.max(1);
+            if max_read_rate == Some(0) {
+                return Err(anyhow!("--max-read-rate must be greater than 0"));
+            }
+
+            if resume {
+                let dbh = db::open(&db_dir)
+                    .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+                scan::resume_scan(dbh, threads, hdd_threads, walk_threads, max_read_rate, idle)?;
+                return Ok(());
+            }
 
             tracing::info!(
                 db_dir = %db_dir.display(),
                 threads,
+                hdd_threads,
+                walk_threads,
                 follow_symlinks,
                 recursive = !no_recursive,
                 count = paths.len(),
                 "scan starting"
             );
 
+            // Never index our own database directory.
+            let excludes = exclude::ExcludeRules::new(exclude::ExcludeSpec {
+                excludes: exclude,
+                includes: include,
+                exclude_files: exclude_from,
+                always_excluded: vec![db_dir.clone()],
+            })?;
+
+            let opts = scan::ScanOptions {
+                threads,
+                hdd_threads,
+                walk_threads,
+                max_read_rate,
+                idle,
+                follow_symlinks,
+                recursive: !no_recursive,
+                detect_deletes,
+                one_file_system,
+                watch,
+                lazy,
+                archives,
+                order,
+                size_policy: session::SizePolicy {
+                    min_size,
+                    max_size,
+                    index_empty,
+                },
+                excludes,
+            };
+
+            if plan {
+                // Reading only, so the plan does not change what it reports on
+                let dbh = db::open_read_only(&db_dir)
+                    .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+                scan::plan_scan(dbh, paths, opts, explain)?;
+                return Ok(());
+            }
+
             // Open DB and move it into scan (writer thread owns it).
             let dbh = db::open(&db_dir)
                 .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
-
-            scan::run_scan(dbh, paths, threads, follow_symlinks, !no_recursive, detect_deletes)?;
+            if let Some(source) = files_from {
+                let list = file_list::FileList::open(&source, null)?;
+                scan::run_scan_files(dbh, list, opts)?;
+                return Ok(());
+            }
+            scan::run_scan(dbh, paths, opts)?;
             Ok(())
         }
 
@@ -194,10 +466,10 @@ fn run() -> Result<()> {
             let dbh = db::open(&db_dir)
                 .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
 
-            if let (Some(min), Some(max)) = (min_size, max_size) {
-                if min > max {
-                    return Err(anyhow!("--min-size cannot be greater than --max-size"));
-                }
+            if let (Some(min), Some(max)) = (min_size, max_size)
+                && min > max
+            {
+                return Err(anyhow!("--min-size cannot be greater than --max-size"));
             }
 
             let filter = path_filter::PathFilter::new(&paths)?;
@@ -205,29 +477,29 @@ fn run() -> Result<()> {
             Ok(())
         }
 
-        Command::Potential { paths, min_size, max_size } => {
+        Command::Potential { paths, min_size, max_size, match_tail } => {
             let dbh = db::open(&db_dir)
                 .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
 
-            if let (Some(min), Some(max)) = (min_size, max_size) {
-                if min > max {
-                    return Err(anyhow!("--min-size cannot be greater than --max-size"));
-                }
+            if let (Some(min), Some(max)) = (min_size, max_size)
+                && min > max
+            {
+                return Err(anyhow!("--min-size cannot be greater than --max-size"));
             }
                 
-            let groups = potential::load_groups(&dbh)?;
+            let groups = potential::load_groups(&dbh, match_tail)?;
             let filter = path_filter::PathFilter::new(&paths)?;
             let groups = potential::filter_groups(groups, &filter, min_size, max_size);
 
             potential::print_groups(&groups);
             Ok(())
         }
-        Command::Delete { paths, apply, preserve } => {
+        Command::Delete { paths, apply, preserve, unlink_hardlinks } => {
             let dbh = db::open(&db_dir)
             .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
 
             let filter = path_filter::PathFilter::new(&paths)?;
-            delete::run_delete(&dbh, &filter, preserve, apply)?;
+            delete::run_delete(&dbh, &filter, preserve, apply, unlink_hardlinks)?;
             Ok(())
         }
 
@@ -243,6 +515,55 @@ fn run() -> Result<()> {
             Ok(())
         }
 
+        Command::Verify { paths, budget } => {
+            let dbh = db::open(&db_dir)
+            .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+
+            let filter = path_filter::PathFilter::new(&paths)?;
+            verify::run_verify(&dbh, &filter, budget)?;
+            Ok(())
+        }
+
+        Command::ImportManifest { file, prefix, sizes } => {
+            let dbh = db::open(&db_dir)
+            .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+
+            manifest::run_import_manifest(&dbh, &file, &prefix, sizes)?;
+            Ok(())
+        }
+
+        Command::ExportManifest { paths, relative_to, sidecars } => {
+            if let Some(name) = &sidecars
+                && (name.is_empty() || name.contains('/') || name == "." || name == "..")
+            {
+                return Err(anyhow!("--sidecars must be a file name, not a path"));
+            }
+
+            let dbh = db::open(&db_dir)
+            .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+
+            let filter = path_filter::PathFilter::new(&paths)?;
+            manifest::run_export_manifest(&dbh, &filter, relative_to.as_deref(), sidecars.as_deref())?;
+            Ok(())
+        }
+
+        Command::Errors { paths, kind } => {
+            let dbh = db::open(&db_dir)
+            .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+
+            let filter = path_filter::PathFilter::new(&paths)?;
+            scan_errors::run_errors(&dbh, &filter, &kind)?;
+            Ok(())
+        }
+
+        Command::History { id } => {
+            let dbh = db::open(&db_dir)
+            .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
+
+            session::run_history(&dbh, id)?;
+            Ok(())
+        }
+
         Command::Stats => {
             let dbh = db::open(&db_dir)
             .with_context(|| format!("Failed to open database in {}", db_dir.display()))?;
@@ -0,0 +1,442 @@
+//! BLAKE3 manifests in `b3sum` format.
+//!
+//! `import-manifest` reads the manifest of a drive that is not connected
+//! into an offline catalog. Each line becomes a Live version at
+//! `<prefix>/<path in manifest>`, known only by its hash and, if the manifest
+//! has them, its size. Catalog entries show up in `dupes` and `check` as
+//! copies that exist elsewhere, but are never read, deleted or taken as
+//! missing by a scan.
+//!
+//! `export-manifest` writes the hashes in the DB as manifests that
+//! `b3sum --check` can verify, without reading the files.
+
+use crate::check::parse_blake256sum_line;
+use crate::db::DbHandle;
+use crate::file_meta::{FileMeta, FileStat, FileState};
+use crate::hashing;
+use crate::path_filter::PathFilter;
+use crate::path_utils;
+use crate::types::Hash256;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Entries are written in batches of this many.
+const IMPORT_BATCH: usize = 10_000;
+
+/// One line of a manifest.
+struct ManifestLine {
+    hash256: Hash256,
+    size: Option<u64>,
+    path: String,
+}
+
+pub fn run_import_manifest(db: &DbHandle, source: &Path, prefix: &Path, with_sizes: bool) -> Result<()> {
+    let prefix = path_utils::normalize_path(prefix)?;
+    let prefix_str = path_utils::path_to_string(&prefix);
+
+    let reader: Box<dyn BufRead> = if source == Path::new("-") {
+        Box::new(std::io::stdin().lock())
+    } else {
+        let f = File::open(source).with_context(|| format!("open manifest {}", source.display()))?;
+        Box::new(BufReader::new(f))
+    };
+
+    let mut batch: Vec<(String, FileMeta)> = Vec::new();
+    let mut imported: HashSet<String> = HashSet::new();
+    let (mut added, mut unchanged, mut skipped) = (0u64, 0u64, 0u64);
+
+    for (i, line) in reader.split(b'\n').enumerate() {
+        let line = line.with_context(|| format!("read manifest {}", source.display()))?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        let entry = parse_manifest_line(line, with_sizes)
+            .with_context(|| format!("{}:{}: invalid manifest line", source.display(), i + 1))?;
+
+        let Some(path) = catalog_path(&prefix, &entry.path) else {
+            tracing::warn!(line = i + 1, path = %entry.path, "skipping path that leaves the prefix");
+            skipped += 1;
+            continue;
+        };
+        let path = path_utils::path_to_string(&path);
+        if !imported.insert(path.clone()) {
+            continue;
+        }
+
+        let cur = db.get_current_by_path(&path)?;
+        if let Some(cur) = &cur
+            && cur.state == FileState::Live
+        {
+            if !cur.meta.catalog {
+                tracing::warn!(path, "a scanned file is already at this path; not replacing it");
+                skipped += 1;
+                continue;
+            }
+            if cur.meta.hash256 == Some(entry.hash256)
+                && match entry.size {
+                    Some(s) => cur.meta.known_size() == Some(s),
+                    // Unless a size can be filled in now
+                    None => !cur.meta.size_unknown || known_size(db, &entry.hash256)?.is_none(),
+                }
+            {
+                unchanged += 1;
+                continue;
+            }
+        }
+
+        let size = match entry.size {
+            Some(s) => Some(s),
+            None if hashing::is_empty_hash(&entry.hash256) => Some(0),
+            None => known_size(db, &entry.hash256)?,
+        };
+        batch.push((path, FileMeta::catalog(size, entry.hash256)));
+        added += 1;
+        if batch.len() >= IMPORT_BATCH {
+            db.write_batch_versions(0, &batch)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        db.write_batch_versions(0, &batch)?;
+    }
+
+    // What an earlier import had under this prefix, but this one does not
+    let mut stale = Vec::new();
+    for (path, file_id) in db.live_under_prefix(&format!("{}/", prefix_str.trim_end_matches('/')))? {
+        if !imported.contains(&path)
+            && let Some(cur) = db.get_current_by_path(&path)?
+            && cur.meta.catalog
+        {
+            stale.push(file_id);
+        }
+    }
+    if !stale.is_empty() {
+        db.mark_files_missing(&stale)?;
+    }
+
+    println!(
+        "Imported {added} entries under {prefix_str}; {unchanged} unchanged, {} no longer in the manifest, {skipped} skipped.",
+        stale.len()
+    );
+    Ok(())
+}
+
+/// Write a manifest of the Live files matching `filter` to stdout, with
+/// paths relative to `base` if given. With `sidecar`, write a manifest with
+/// that name into each directory instead, for the files in it.
+///
+/// Files that changed or disappeared since they were scanned, and files
+/// without a hash yet, are left out.
+pub fn run_export_manifest(
+    db: &DbHandle,
+    filter: &PathFilter,
+    base: Option<&Path>,
+    sidecar: Option<&str>,
+) -> Result<()> {
+    let base = base.map(path_utils::normalize_path).transpose()?;
+
+    let (mut pending, mut changed, mut outside) = (0u64, 0u64, 0u64);
+    let mut by_dir: BTreeMap<PathBuf, Vec<(PathBuf, Hash256)>> = BTreeMap::new();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut exported = 0u64;
+
+    for e in db.live_files()? {
+        // Files in archives and catalog entries cannot be checked by b3sum
+        if e.meta.catalog || e.meta.member || !filter.matches(&e.path) {
+            continue;
+        }
+        let Some(hash256) = e.meta.hash256 else {
+            pending += 1;
+            continue;
+        };
+        let path = path_utils::path_from_string(&e.path);
+        let unchanged = std::fs::metadata(&path)
+            .is_ok_and(|md| e.meta.matches_size_mtime(&FileStat::from_metadata(&md)));
+        if !unchanged {
+            tracing::debug!(path = %e.path, "changed since the last scan; left out");
+            changed += 1;
+            continue;
+        }
+
+        if let Some(name) = sidecar {
+            let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            if file_name != name {
+                by_dir.entry(dir.to_path_buf()).or_default().push((file_name.into(), hash256));
+            }
+            continue;
+        }
+
+        let shown = match &base {
+            Some(b) => match path.strip_prefix(b) {
+                Ok(rel) => rel,
+                Err(_) => {
+                    outside += 1;
+                    continue;
+                }
+            },
+            None => &path,
+        };
+        out.write_all(&manifest_line(&hash256, shown))?;
+        exported += 1;
+    }
+    out.flush()?;
+    drop(out);
+
+    let mut failed = 0u64;
+    if let Some(name) = sidecar {
+        for (dir, files) in &by_dir {
+            match write_sidecar(dir, name, files) {
+                Ok(()) => exported += files.len() as u64,
+                Err(e) => {
+                    tracing::warn!("{e:#}");
+                    failed += 1;
+                }
+            }
+        }
+        tracing::info!(dirs = by_dir.len() as u64 - failed, files = exported, "wrote manifests");
+    }
+
+    if pending > 0 {
+        tracing::warn!(files = pending, "left out files that were not hashed yet (scan without --lazy to hash them)");
+    }
+    if changed > 0 {
+        tracing::warn!(files = changed, "left out files that changed since the last scan");
+    }
+    if outside > 0 {
+        tracing::warn!(files = outside, "left out files outside the base directory");
+    }
+    if failed > 0 {
+        bail!("{failed} manifest(s) could not be written");
+    }
+    Ok(())
+}
+
+/// Replace the manifest `name` in `dir`. It is written to a temporary file
+/// first, so a manifest is never left half written.
+fn write_sidecar(dir: &Path, name: &str, files: &[(PathBuf, Hash256)]) -> Result<()> {
+    let target = dir.join(name);
+    let tmp = dir.join(format!(".{name}.tmp"));
+    let write = || -> Result<()> {
+        let mut f = BufWriter::new(File::create(&tmp)?);
+        for (file_name, hash256) in files {
+            f.write_all(&manifest_line(hash256, file_name))?;
+        }
+        f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp, &target)?;
+        Ok(())
+    };
+    write()
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })
+        .with_context(|| format!("write {}", target.display()))
+}
+
+/// A line as `b3sum` writes it: names with a backslash or newline are
+/// escaped, and the line then starts with `\`. Other bytes of the name are
+/// written as they are, whether they are valid UTF-8 or not.
+fn manifest_line(hash256: &Hash256, path: &Path) -> Vec<u8> {
+    let name = path_utils::path_bytes(path);
+    let escaped = name.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r'));
+    let mut line = Vec::with_capacity(name.len() + 68);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(hex::encode(hash256).as_bytes());
+    line.extend_from_slice(b"  ");
+    for &b in name.iter() {
+        match b {
+            b'\\' if escaped => line.extend_from_slice(b"\\\\"),
+            b'\n' => line.extend_from_slice(b"\\n"),
+            b'\r' => line.extend_from_slice(b"\\r"),
+            _ => line.push(b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+/// `<hash>  <path>` as written by `b3sum`, or `<hash>  <size>  <path>` with
+/// `with_sizes`. Lines starting with `\` have `\\` and `\n` escaped in the
+/// path, like `b3sum` writes names with newlines or backslashes. The path
+/// is taken byte for byte, so it need not be valid UTF-8.
+fn parse_manifest_line(line: &[u8], with_sizes: bool) -> Result<ManifestLine> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (hash, rest) = split_field(line.trim_ascii_start());
+    let (hash256, _) = parse_blake256sum_line(std::str::from_utf8(hash)?)?;
+
+    let (size, rest) = if with_sizes {
+        let (size, rest) = split_field(rest.trim_ascii_start());
+        if rest.is_empty() {
+            bail!("missing size or path");
+        }
+        let size = String::from_utf8_lossy(size);
+        let size: u64 = size.parse().with_context(|| format!("invalid size {size:?}"))?;
+        (Some(size), rest)
+    } else {
+        (None, rest)
+    };
+
+    // One separator, then `*` for "binary mode" or a second space
+    let rest = rest.get(1..).unwrap_or_default();
+    let path = rest.strip_prefix(b" ").or_else(|| rest.strip_prefix(b"*")).unwrap_or(rest);
+    if path.is_empty() {
+        bail!("missing path");
+    }
+    let path = if escaped { unescape(path)? } else { path.to_vec() };
+    Ok(ManifestLine {
+        hash256,
+        size,
+        path: path_utils::path_string_from_bytes(&path),
+    })
+}
+
+/// The bytes up to the first whitespace, and the rest starting with it.
+fn split_field(s: &[u8]) -> (&[u8], &[u8]) {
+    s.split_at(s.iter().position(u8::is_ascii_whitespace).unwrap_or(s.len()))
+}
+
+fn unescape(s: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            other => bail!("invalid escape \\{}", other.map(|&b| char::from(b).to_string()).unwrap_or_default()),
+        }
+    }
+    Ok(out)
+}
+
+/// Where a manifest path is put in the catalog. Absolute paths are taken
+/// as relative to the prefix, like the root of the drive they were on.
+/// None for paths with `..`.
+fn catalog_path(prefix: &Path, path: &str) -> Option<PathBuf> {
+    let mut out = prefix.to_path_buf();
+    for c in path_utils::path_from_string(path).components() {
+        match c {
+            Component::Normal(p) => out.push(p),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (out != prefix).then_some(out)
+}
+
+/// The size of a scanned file with this hash, if there is one.
+fn known_size(db: &DbHandle, hash256: &Hash256) -> Result<Option<u64>> {
+    Ok(db
+        .lookup_files_by_hash256(hash256)?
+        .into_iter()
+        .find(|e| !e.meta.catalog)
+        .map(|e| e.meta.size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::os::unix::ffi::OsStrExt;
+
+    const H: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn hash() -> Hash256 {
+        parse_blake256sum_line(H).unwrap().0
+    }
+
+    #[test]
+    fn parses_b3sum_lines() {
+        let e = parse_manifest_line(format!("{H}  dir/a b.txt").as_bytes(), false).unwrap();
+        assert_eq!((e.hash256, e.size, e.path.as_str()), (hash(), None, "dir/a b.txt"));
+
+        // Binary mode marker
+        let e = parse_manifest_line(format!("{H} *a.txt").as_bytes(), false).unwrap();
+        assert_eq!(e.path, "a.txt");
+
+        let e = parse_manifest_line(format!("{H}  1234  a.txt").as_bytes(), true).unwrap();
+        assert_eq!((e.size, e.path.as_str()), (Some(1234), "a.txt"));
+
+        // Escaped names, and bytes that are not UTF-8
+        let e = parse_manifest_line(format!("\\{H}  a\\nb\\\\c").as_bytes(), false).unwrap();
+        assert_eq!(path_utils::path_string_to_bytes(&e.path), b"a\nb\\c");
+        let mut line = format!("{H}  caf").into_bytes();
+        line.push(0xe9);
+        let e = parse_manifest_line(&line, false).unwrap();
+        assert_eq!(e.path, "caf\\xe9");
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        for (line, sizes) in [
+            (H.to_string(), false),
+            (format!("{H}  "), false),
+            (format!("{}  a", &H[1..]), false),
+            (format!("{H}  a.txt"), true),
+            (format!("{H}  x12  a.txt"), true),
+            (format!("\\{H}  a\\tb"), false),
+        ] {
+            assert!(parse_manifest_line(line.as_bytes(), sizes).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn written_lines_parse_back() {
+        for name in [&b"plain.txt"[..], b"new\nline", b"back\\slash", b"cr\rlf", b"caf\xe9"] {
+            let path = Path::new(std::ffi::OsStr::from_bytes(name));
+            let line = manifest_line(&hash(), path);
+            let line = line.strip_suffix(b"\n").unwrap();
+            assert!(!line.contains(&b'\n'));
+            let e = parse_manifest_line(line, false).unwrap();
+            assert_eq!(e.hash256, hash());
+            assert_eq!(path_utils::path_string_to_bytes(&e.path), name);
+        }
+        // Only names that need it are escaped, as b3sum does
+        assert_eq!(manifest_line(&hash(), Path::new("a")), format!("{H}  a\n").into_bytes());
+    }
+
+    #[test]
+    fn catalog_paths_stay_under_the_prefix() {
+        let prefix = Path::new("/off/disk7");
+        assert_eq!(catalog_path(prefix, "a/b"), Some(PathBuf::from("/off/disk7/a/b")));
+        assert_eq!(catalog_path(prefix, "/a/./b"), Some(PathBuf::from("/off/disk7/a/b")));
+        assert_eq!(catalog_path(prefix, "a/../../b"), None);
+        assert_eq!(catalog_path(prefix, "."), None);
+    }
+
+    #[test]
+    fn import_updates_the_catalog() {
+        let dir = TestDir::new();
+        let db = crate::db::open(&dir.path().join("db")).unwrap();
+        let manifest = dir.path().join("m.b3");
+        let other = "f".repeat(64);
+
+        std::fs::write(&manifest, format!("{H}  a\n{other}  b\n")).unwrap();
+        run_import_manifest(&db, &manifest, Path::new("/off"), false).unwrap();
+        let a = db.get_current_by_path("/off/a").unwrap().unwrap();
+        assert!(a.meta.catalog && a.meta.size_unknown);
+
+        // b dropped from the manifest, and a given a size
+        std::fs::write(&manifest, format!("{H}  5  a\n")).unwrap();
+        run_import_manifest(&db, &manifest, Path::new("/off"), true).unwrap();
+        let a = db.get_current_by_path("/off/a").unwrap().unwrap();
+        assert_eq!((a.state, a.meta.known_size()), (FileState::Live, Some(5)));
+        let b = db.get_current_by_path("/off/b").unwrap().unwrap();
+        assert_eq!(b.state, FileState::Missing);
+        assert_eq!(db.lookup_live_by_size(5).unwrap().len(), 1);
+    }
+}
@@ -16,7 +16,7 @@ impl PathFilter {
             let norm = path_utils::normalize_path(p)
                 .with_context(|| format!("Failed to normalize filter path: {}", p.display()))?;
 
-            let mut s = norm.to_string_lossy().to_string();
+            let mut s = path_utils::path_to_string(&norm);
             if s.ends_with('/') {
                 s.pop();
             }
@@ -25,7 +25,7 @@ impl PathFilter {
         }
 
         // longest first, same as before
-        prefixes.sort_by(|a, b| b.len().cmp(&a.len()));
+        prefixes.sort_by_key(|p| std::cmp::Reverse(p.len()));
 
         Ok(Self { prefixes })
     }
@@ -57,8 +57,5 @@ fn starts_with_path_prefix(path: &str, prefix: &str) -> bool {
     }
 
     // boundary check: next char must be a path separator
-    match path.as_bytes().get(prefix.len()) {
-        Some(b'/') | Some(b'\\') => true,
-        _ => false,
-    }
+    matches!(path.as_bytes().get(prefix.len()), Some(b'/') | Some(b'\\'))
 }
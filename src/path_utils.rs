@@ -29,3 +29,129 @@ fn lexical_normalize(path: &Path) -> PathBuf {
 
     out
 }
+
+/// The form paths are kept in as strings, in memory and in records. Paths
+/// that are valid UTF-8 are kept as they are, except that `\` is doubled.
+/// Bytes that are not valid UTF-8 are written as `\xNN`, so any path can be
+/// shown and turned back into the exact same bytes with `path_from_string`.
+pub fn path_to_string(p: &Path) -> String {
+    path_string_from_bytes(&path_bytes(p))
+}
+
+/// The path a string from `path_to_string` stands for.
+pub fn path_from_string(s: &str) -> PathBuf {
+    PathBuf::from(os_from_bytes(path_string_to_bytes(s)))
+}
+
+/// Escape raw path bytes as described for `path_to_string`.
+pub fn path_string_from_bytes(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                out.push_str("\\\\");
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{b:02x}"));
+        }
+    }
+    out
+}
+
+/// The raw bytes of a path string. A `\` that does not start an escape is
+/// taken as it is, so hand written paths need not double it.
+pub fn path_string_to_bytes(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' {
+            if bytes.get(i + 1) == Some(&b'\\') {
+                out.push(b'\\');
+                i += 2;
+                continue;
+            }
+            if bytes.get(i + 1) == Some(&b'x')
+                && let Some(hex) = s.get(i + 2..i + 4)
+                && let Ok(b) = u8::from_str_radix(hex, 16)
+            {
+                out.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+/// The raw bytes of a path, as the filesystem has them.
+#[cfg(unix)]
+pub fn path_bytes(p: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    std::borrow::Cow::Borrowed(p.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(p: &Path) -> std::borrow::Cow<'_, [u8]> {
+    match p.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => std::borrow::Cow::Borrowed(s.as_bytes()),
+        std::borrow::Cow::Owned(s) => std::borrow::Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(unix)]
+fn os_from_bytes(bytes: Vec<u8>) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_from_bytes(bytes: Vec<u8>) -> std::ffi::OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_path_round_trips() {
+        for bytes in [
+            &b"/plain/path.txt"[..],
+            "/ünïcödé/ファイル".as_bytes(),
+            b"/latin1/caf\xe9",
+            b"/back\\slash",
+            // Looks like an escape, but is what the name is
+            b"/a\\x41",
+            b"/\xff\xfe\\xff\\",
+        ] {
+            let s = path_string_from_bytes(bytes);
+            assert_eq!(path_string_to_bytes(&s), bytes, "{s}");
+            let p = path_from_string(&s);
+            assert_eq!(path_bytes(&p).as_ref(), bytes);
+            assert_eq!(path_to_string(&p), s);
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(path_string_from_bytes(b"/caf\xe9"), "/caf\\xe9");
+        assert_eq!(path_string_from_bytes(b"/a\\b"), "/a\\\\b");
+        // A lone backslash that does not start an escape is kept
+        assert_eq!(path_string_to_bytes("/a\\b"), b"/a\\b");
+        assert_eq!(path_string_to_bytes("/a\\xZZ"), b"/a\\xZZ");
+        assert_eq!(path_string_to_bytes("/a\\x4"), b"/a\\x4");
+    }
+
+    #[test]
+    fn normalize_is_lexical() {
+        assert_eq!(lexical_normalize(Path::new("/a/./b/../c/")), PathBuf::from("/a/c"));
+        assert_eq!(lexical_normalize(Path::new("/../a")), PathBuf::from("/a"));
+        assert!(normalize_path(Path::new("x/../y")).unwrap().ends_with("y"));
+    }
+}
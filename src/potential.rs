@@ -12,17 +12,23 @@ use crate::util::{format_size, size_in_range};
 pub struct Entry {
     pub path: String,
     pub size: u64,
-    pub hash256: Hash256,
+    /// None while the full hash is pending
+    pub hash256: Option<Hash256>,
 }
 
+/// sha1prefix bytes, and the sha1tail bytes when matching tails
+type GroupKey = ([u8; 20], Option<[u8; 20]>);
+
 #[derive(Debug, Clone)]
 pub struct PotentialGroup {
-    // key is sha1prefix bytes (20)
-    pub key: [u8; 20],
+    pub key: GroupKey,
     pub entries: Vec<Entry>, // sorted largest-first
 }
 
-pub fn load_groups(db: &DbHandle) -> Result<Vec<PotentialGroup>> {
+/// Group live files by prefix hash. With `match_tail`, files must also have
+/// the same tail hash; files indexed before tail hashes were kept are only
+/// grouped with each other.
+pub fn load_groups(db: &DbHandle, match_tail: bool) -> Result<Vec<PotentialGroup>> {
     let tx = db.db.begin_read().context("begin_read() failed")?;
     let file_meta = tx.open_table(crate::schema::FILE_META)?;
     let file_state = tx.open_table(crate::schema::FILE_STATE)?;
@@ -30,7 +36,7 @@ pub fn load_groups(db: &DbHandle) -> Result<Vec<PotentialGroup>> {
     let id_to_path = tx.open_table(crate::schema::ID_TO_PATH)?;
 
 
-    let mut map: HashMap<[u8; 20], Vec<Entry>> = HashMap::new();
+    let mut map: HashMap<GroupKey, Vec<Entry>> = HashMap::new();
 
     for item in file_meta.iter()? {
         let (k, v) = item?;
@@ -47,15 +53,17 @@ pub fn load_groups(db: &DbHandle) -> Result<Vec<PotentialGroup>> {
         let fm = FileMeta::decode(blob)
         .with_context(|| format!("decode file_meta for file_id={}", file_id))?;
 
+        // Files recorded without reading them have no prefix either
         let Some(prefix) = fm.sha1prefix_4k else { continue; };
+        let tail = if match_tail { fm.sha1tail } else { None };
 
         let Some(pid) = file_to_path.get(file_id)? else { continue; };
         let pid = pid.value();
 
         let Some(p) = id_to_path.get(pid)? else { continue; };
-        let path = p.value().to_string();
+        let path = crate::path_utils::path_string_from_bytes(p.value());
 
-        map.entry(prefix).or_default().push(Entry { path, size: fm.size, hash256: fm.hash256 });
+        map.entry((prefix, tail)).or_default().push(Entry { path, size: fm.size, hash256: fm.hash256 });
     }
 
     // Convert to groups and keep only groups with >= 2 entries
@@ -66,14 +74,18 @@ pub fn load_groups(db: &DbHandle) -> Result<Vec<PotentialGroup>> {
             return None;
         }
 
-        // Group by full hash256 within this prefix group
+        // Group by full hash256 within this prefix group. Entries whose
+        // full hash is pending have no exact duplicate.
         let mut by_sha: HashMap<Hash256, Vec<Entry>> = HashMap::new();
+        let mut filtered: Vec<Entry> = Vec::new();
         for e in entries {
-            by_sha.entry(e.hash256).or_default().push(e);
+            match e.hash256 {
+                Some(h) => by_sha.entry(h).or_default().push(e),
+                None => filtered.push(e),
+            }
         }
 
         // Keep only hash256 buckets that have exactly 1 file (i.e., not exact dupes)
-        let mut filtered: Vec<Entry> = Vec::new();
         for (_sha, mut bucket) in by_sha {
             if bucket.len() == 1 {
                 filtered.push(bucket.pop().unwrap());
@@ -0,0 +1,271 @@
+use crate::util::format_size;
+use clap::ValueEnum;
+use crossbeam_channel as chan;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const BAR_TICK: Duration = Duration::from_millis(200);
+const JSON_TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ProgressMode {
+    /// Progress bars if stderr is a terminal, otherwise nothing
+    #[default]
+    Auto,
+    /// Progress bars on stderr
+    Bars,
+    /// Progress events on stderr, one JSON object per line
+    Json,
+    /// No progress output
+    None,
+}
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+// The bars being drawn, if any, so other output can be written around them.
+static ACTIVE_BARS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Select how long-running commands report progress. Call once, at startup.
+pub fn init(mode: ProgressMode) {
+    let mode = match mode {
+        ProgressMode::Auto if io::stderr().is_terminal() => ProgressMode::Bars,
+        ProgressMode::Auto => ProgressMode::None,
+        m => m,
+    };
+    let _ = MODE.set(mode);
+}
+
+fn mode() -> ProgressMode {
+    MODE.get().copied().unwrap_or(ProgressMode::None)
+}
+
+/// True if progress is written as JSON events, which other output on
+/// stderr must then be too.
+pub fn is_json() -> bool {
+    mode() == ProgressMode::Json
+}
+
+/// Write the error that ends the program to stderr.
+pub fn print_error(e: &anyhow::Error) {
+    if is_json() {
+        let line = format!("{{\"event\":\"error\",\"message\":{}}}\n", json_string(&format!("{e:#}")));
+        let _ = io::stderr().write_all(line.as_bytes());
+    } else {
+        eprintln!("{e:#}");
+    }
+}
+
+/// `s` as a JSON string, quoted and escaped.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Run `f` with the progress bars (if any) hidden, so it can write to the terminal.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let bars = ACTIVE_BARS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    match bars {
+        Some(mp) => mp.suspend(f),
+        None => f(),
+    }
+}
+
+/// stderr writer for log output that does not tear up the progress bars.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        suspend(|| io::stderr().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Where a long-running operation is. Items are found, some of them are
+/// queued for work, and the queued items are eventually done.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgressSample {
+    pub found: u64,
+    pub queued: u64,
+    pub queued_bytes: u64,
+    pub done: u64,
+    pub done_bytes: u64,
+    pub errors: u64,
+    /// Bytes per second the work is limited to, if it is
+    pub read_limit: Option<u64>,
+}
+
+/// Reports progress from a background thread until dropped.
+///
+/// The thread calls `sample` periodically and renders the result as bars
+/// or JSON events, depending on the selected `ProgressMode`.
+pub struct Progress {
+    // Dropping the sender stops the reporter thread
+    stop_tx: Option<chan::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    /// `task` names the operation (e.g. "scan"), and `verb` what is done to
+    /// queued items (e.g. "hashed").
+    pub fn start<F>(task: &'static str, verb: &'static str, sample: F) -> Self
+    where
+        F: Fn() -> ProgressSample + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = chan::bounded::<()>(0);
+        let handle = match mode() {
+            ProgressMode::Bars => Some(thread::spawn(move || run_bars(task, verb, stop_rx, sample))),
+            ProgressMode::Json => Some(thread::spawn(move || run_json(task, stop_rx, sample))),
+            ProgressMode::Auto | ProgressMode::None => None,
+        };
+
+        Self {
+            stop_tx: Some(stop_tx),
+            handle,
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.stop_tx.take();
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+fn run_bars(
+    task: &'static str,
+    verb: &'static str,
+    stop_rx: chan::Receiver<()>,
+    sample: impl Fn() -> ProgressSample,
+) {
+    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+
+    let found = mp.add(ProgressBar::new_spinner());
+    found.set_style(ProgressStyle::with_template("{spinner} {msg}").unwrap_or_else(|_| ProgressStyle::default_spinner()));
+
+    let work = mp.add(ProgressBar::new(0));
+    work.set_style(
+        ProgressStyle::with_template(
+            "  [{bar:40}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {msg}",
+        )
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> "),
+    );
+
+    *ACTIVE_BARS.lock().unwrap_or_else(PoisonError::into_inner) = Some(mp.clone());
+
+    loop {
+        let s = sample();
+
+        let limit = match s.read_limit {
+            Some(l) => format!(", reads limited to {}/s", format_size(l)),
+            None => String::new(),
+        };
+        found.set_message(format!(
+            "{task}: {} found, {} queued ({}){limit}",
+            s.found,
+            s.queued,
+            format_size(s.queued_bytes)
+        ));
+        found.tick();
+
+        work.set_length(s.queued_bytes);
+        work.set_position(s.done_bytes);
+        if s.errors > 0 {
+            work.set_message(format!("{}/{} {verb}, {} errors", s.done, s.queued, s.errors));
+        } else {
+            work.set_message(format!("{}/{} {verb}", s.done, s.queued));
+        }
+
+        if let Err(chan::RecvTimeoutError::Disconnected) = stop_rx.recv_timeout(BAR_TICK) {
+            break;
+        }
+    }
+
+    // The commands print their own summary
+    found.finish_and_clear();
+    work.finish_and_clear();
+    *ACTIVE_BARS.lock().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+fn run_json(task: &'static str, stop_rx: chan::Receiver<()>, sample: impl Fn() -> ProgressSample) {
+    let t0 = Instant::now();
+    let start = ProgressSample {
+        read_limit: sample().read_limit,
+        ..Default::default()
+    };
+    emit_json("start", task, &start, Duration::ZERO);
+
+    loop {
+        if let Err(chan::RecvTimeoutError::Disconnected) = stop_rx.recv_timeout(JSON_TICK) {
+            break;
+        }
+        emit_json("progress", task, &sample(), t0.elapsed());
+    }
+
+    emit_json("finish", task, &sample(), t0.elapsed());
+}
+
+/// Write one event as a single line of JSON.
+///
+/// Format:
+/// {"event":"start"|"progress"|"finish","task":"scan","elapsed_ms":N,"found":N,"queued":N,
+///  "queued_bytes":N,"done":N,"done_bytes":N,"errors":N,"bytes_per_sec":N,"eta_secs":N|null,
+///  "read_limit":N|null}
+fn emit_json(event: &str, task: &str, s: &ProgressSample, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    let bytes_per_sec = if secs > 0.0 {
+        (s.done_bytes as f64 / secs) as u64
+    } else {
+        0
+    };
+    let eta = match bytes_per_sec {
+        0 => "null".to_string(),
+        rate => (s.queued_bytes.saturating_sub(s.done_bytes) / rate).to_string(),
+    };
+
+    let read_limit = match s.read_limit {
+        Some(l) => l.to_string(),
+        None => "null".to_string(),
+    };
+
+    let line = format!(
+        "{{\"event\":\"{event}\",\"task\":\"{task}\",\"elapsed_ms\":{},\"found\":{},\"queued\":{},\"queued_bytes\":{},\"done\":{},\"done_bytes\":{},\"errors\":{},\"bytes_per_sec\":{},\"eta_secs\":{},\"read_limit\":{}}}\n",
+        elapsed.as_millis(),
+        s.found,
+        s.queued,
+        s.queued_bytes,
+        s.done,
+        s.done_bytes,
+        s.errors,
+        bytes_per_sec,
+        eta,
+        read_limit
+    );
+    let _ = io::stderr().write_all(line.as_bytes());
+}
@@ -1,131 +1,858 @@
+use crate::archive::{self, ArchiveKind};
+use crate::checkpoint::ScanCheckpoint;
 use crate::codec::systemtime_to_unix_secs;
-use crate::db::DbHandle;
-use crate::file_meta::FileMeta;
+use crate::db::{DbHandle, ShaEntry};
+use crate::device::{self, DiskInfo};
+use crate::exclude::{self, ExcludeRules};
+use crate::file_list::FileList;
+use crate::file_meta::{FileMeta, FileState, FileStat};
 use crate::hashing;
 use crate::path_utils;
-use anyhow::{Context, Result};
+use crate::progress::{Progress, ProgressSample};
+use crate::scan_errors::{ErrorCounts, ErrorPhase, ScanError};
+use crate::scan_plan::{self, PlanKind, ScanPlan};
+use crate::session::{ScanCounters, ScanOrder, ScanSession, SizePolicy};
+use crate::types::Hash256;
+use crate::walker;
+use crate::watch::{self, WatchEvent, Watcher};
+use anyhow::{anyhow, bail, Context, Result};
 use crossbeam_channel as chan;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::thread;
-use std::sync::Arc;
-use crate::types::Hash256;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
 
 
 #[derive(Debug)]
 struct HashJob {
     path: PathBuf,
-    mtime: u64,
-    size: u64,
+    stat: FileStat,
+    tiering: Tiering,
+    /// Also hash the members of this archive
+    archive: Option<ArchiveKind>,
+    /// A Live file that is gone from its path and had this size and mtime,
+    /// but no inode recorded: (file_id, hash256). The file is taken as
+    /// moved from there if the hashes match.
+    moved_from: Option<(u64, Hash256)>,
 }
 
 
+/// What a hash job does with the prefix and tail hashes of same-size files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tiering {
+    /// Nothing; files of this size are not compared by them
+    Off,
+    /// Compare the prefix hash with the other files of this size first, then
+    /// the tail hash, and only hash the whole file if both match one of them
+    Compare,
+    /// Hash the whole file, and keep its prefix and tail hashes to compare
+    /// the other files of its size with
+    Record,
+}
+
 #[derive(Debug)]
 struct HashResult {
     path: String,
     meta: FileMeta,
 }
 
+/// Everything the writer thread persists.
+#[derive(Debug)]
+enum WriterMsg {
+    Hashed(HashResult),
+    /// Recorded without a hash by a lazy scan
+    Recorded(HashResult),
+    /// Only prefix (and tail) hashes: no other file of its size has the same
+    Compared(HashResult),
+    /// A file inside an archive, hashed along with it
+    Member(HashResult),
+    /// All members of an archive were sent; members it no longer has are missing
+    ArchiveDone { archive: String, members: HashSet<String> },
+    /// A known file found at a new path; `from` is the version at the old one
+    Moved { result: HashResult, from: u64 },
+    Failed(ScanError),
+}
+
+pub struct ScanOptions {
+    /// Hash workers per disk
+    pub threads: usize,
+    /// Hash workers per rotational disk
+    pub hdd_threads: usize,
+    /// Threads reading directories; 1 walks with a single thread
+    pub walk_threads: usize,
+    /// Bytes per second all hash workers together may read
+    pub max_read_rate: Option<u64>,
+    /// Run the hash workers with idle I/O and CPU priority
+    pub idle: bool,
+    pub follow_symlinks: bool,
+    pub recursive: bool,
+    pub detect_deletes: bool,
+    /// Do not descend into directories on another device than their root
+    pub one_file_system: bool,
+    /// Keep running after the walk, indexing changes as they happen
+    pub watch: bool,
+    /// Only hash a file once another file of the same size exists
+    pub lazy: bool,
+    /// Index the members of zip and tar archives as virtual files
+    pub archives: bool,
+    pub size_policy: SizePolicy,
+    pub order: ScanOrder,
+    pub excludes: ExcludeRules,
+}
+
+/// How many changed files are sorted at a time with `--order` inode or physical.
+const ORDER_BATCH: usize = 4096;
+
+/// How long a result may wait in the writer's batch while watching.
+const WATCH_WRITE_DELAY: Duration = Duration::from_secs(1);
+/// How often roots that could not be fully watched are rescanned.
+const WATCH_RESCAN_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How often the watch loop checks for a stop request.
+const WATCH_POLL: Duration = Duration::from_millis(500);
+
+/// Walk progress, advanced by the walker and persisted by the writer as the
+/// scan checkpoint.
+#[derive(Debug, Default)]
+struct WalkState {
+    /// Roots that were fully walked, with missing-file detection done
+    roots_done: usize,
+    /// Last entry handled in the root being walked
+    frontier: Option<PathBuf>,
+    /// Enqueued for hashing, but not yet written to the DB
+    pending: HashSet<String>,
+}
+
+fn lock_state(state: &Mutex<WalkState>) -> MutexGuard<'_, WalkState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Size and prefix hash.
+type HeadKey = (u64, [u8; 20]);
+
+/// What a scan knows about the files with one size, prefix and tail hash.
+#[derive(Debug)]
+enum TierSeen {
+    /// Only this file, recorded without a full hash
+    Single(PathBuf),
+    /// More than one file, or one that already has a full hash; new files
+    /// with these hashes are hashed completely
+    Full,
+}
+
+/// What a scan knows about the files with one `HeadKey`.
+#[derive(Debug)]
+enum HeadSeen {
+    /// Only this file, recorded without its tail hash
+    Single(PathBuf),
+    /// More than one file, by tail hash
+    Tails(HashMap<[u8; 20], TierSeen>),
+}
+
+/// How a file compares with the others of its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tier {
+    /// No other file has its prefix hash, or its prefix and tail hashes
+    Unique,
+    /// Another file has the same prefix hash; the tail decides
+    SameHead,
+    /// Another file has the same prefix and tail hashes
+    SameTail,
+}
+
+/// Files recorded earlier with one `HeadKey` that need more of their hashes,
+/// now that another file matches them.
+#[derive(Debug)]
+struct TierWork {
+    key: HeadKey,
+    /// Same prefix hash; the tail was not read yet
+    tails: Vec<PathBuf>,
+    /// Same prefix and tail hashes; they need a full hash
+    full: Vec<PathBuf>,
+}
+
+impl TierWork {
+    fn new(key: HeadKey) -> Self {
+        Self {
+            key,
+            tails: Vec::new(),
+            full: Vec::new(),
+        }
+    }
+}
+
+/// The prefix and tail hashes of same-size files, shared by the walker
+/// (which adds the files already in the DB) and the workers (which add what
+/// they hash).
+#[derive(Debug, Default)]
+struct Tiers {
+    heads: Mutex<HashMap<HeadKey, HeadSeen>>,
+}
+
+impl Tiers {
+    fn lock(&self) -> MutexGuard<'_, HashMap<HeadKey, HeadSeen>> {
+        self.heads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add a file with the prefix hash in `work.key` and, once it was read,
+    /// its tail hash. `full` says the file has a full hash. Files recorded
+    /// earlier that now match it are added to `work`.
+    ///
+    /// `on_unique` is called with the keys locked if no other file matches,
+    /// so the partial result it sends is queued before any full result for
+    /// the same file.
+    fn add(
+        &self,
+        work: &mut TierWork,
+        tail: Option<[u8; 20]>,
+        full: bool,
+        path: &Path,
+        on_unique: impl FnOnce(),
+    ) -> Tier {
+        let new_tail = || if full { TierSeen::Full } else { TierSeen::Single(path.to_path_buf()) };
+
+        let mut heads = self.lock();
+        let seen = match heads.entry(work.key) {
+            Entry::Vacant(v) => {
+                v.insert(match tail {
+                    None => HeadSeen::Single(path.to_path_buf()),
+                    Some(tail) => HeadSeen::Tails(HashMap::from([(tail, new_tail())])),
+                });
+                on_unique();
+                return Tier::Unique;
+            }
+            Entry::Occupied(o) => o.into_mut(),
+        };
+
+        if let HeadSeen::Single(earlier) = seen {
+            if earlier == path && tail.is_none() {
+                on_unique();
+                return Tier::Unique;
+            }
+            if earlier != path {
+                work.tails.push(std::mem::take(earlier));
+            }
+            *seen = HeadSeen::Tails(HashMap::new());
+        }
+        let HeadSeen::Tails(tails) = seen else {
+            unreachable!("converted above");
+        };
+        let Some(tail) = tail else {
+            return Tier::SameHead;
+        };
+
+        match tails.entry(tail) {
+            Entry::Vacant(v) => {
+                v.insert(new_tail());
+                on_unique();
+                Tier::Unique
+            }
+            Entry::Occupied(mut o) => match o.get_mut() {
+                TierSeen::Full => Tier::SameTail,
+                TierSeen::Single(earlier) if earlier == path => {
+                    if full {
+                        o.insert(TierSeen::Full);
+                    }
+                    on_unique();
+                    Tier::Unique
+                }
+                TierSeen::Single(earlier) => {
+                    work.full.push(std::mem::take(earlier));
+                    o.insert(TierSeen::Full);
+                    Tier::SameTail
+                }
+            },
+        }
+    }
+
+    /// Add a file from the DB with the hashes it has, before any file of its
+    /// size is compared; fully hashed files must come first. Returns false if
+    /// it has no prefix hash, or another file has the same hashes, so it has
+    /// to be compared again.
+    fn seed(&self, size: u64, meta: &FileMeta, path: PathBuf) -> bool {
+        let Some(head) = meta.sha1prefix_4k else {
+            return false;
+        };
+        let tail_seen = || match meta.hash256 {
+            Some(_) => TierSeen::Full,
+            None => TierSeen::Single(path.clone()),
+        };
+
+        let mut heads = self.lock();
+        match (heads.entry((size, head)), meta.sha1tail) {
+            (Entry::Vacant(v), None) => {
+                v.insert(HeadSeen::Single(path.clone()));
+                true
+            }
+            (Entry::Vacant(v), Some(tail)) => {
+                v.insert(HeadSeen::Tails(HashMap::from([(tail, tail_seen())])));
+                true
+            }
+            (Entry::Occupied(mut o), Some(tail)) => match o.get_mut() {
+                HeadSeen::Tails(tails) => match tails.entry(tail) {
+                    Entry::Vacant(v) => {
+                        v.insert(tail_seen());
+                        true
+                    }
+                    Entry::Occupied(_) => meta.hash256.is_some(),
+                },
+                HeadSeen::Single(_) => meta.hash256.is_some(),
+            },
+            (Entry::Occupied(_), None) => meta.hash256.is_some(),
+        }
+    }
+}
+
 pub fn run_scan(
     db: DbHandle,               // <-- OWNED
     roots: Vec<PathBuf>,
-    threads: usize,
-    follow_symlinks: bool,
-    recursive: bool,
-    detect_deletes: bool
+    opts: ScanOptions,
 ) -> Result<()> {
-    let db = Arc::new(db);
     let norm_roots: Vec<String> = roots
         .iter()
         .map(|p| path_utils::normalize_path(p).map_err(anyhow::Error::from))
         .collect::<anyhow::Result<Vec<_>>>()?
-        .into_iter()
-        .map(|p| p.to_string_lossy().to_string())
+        .iter()
+        .map(|p| path_utils::path_to_string(p))
         .collect();
-    let db_for_writer = db.clone();
+
+    let (session, cp) = begin_session(&db, norm_roots, false, &opts)?;
+    execute_scan(db, session, opts, cp, None)
+}
+
+/// Scan the files in `list`, without walking any directories. Only listed
+/// files are taken as deleted when they are not there anymore.
+pub fn run_scan_files(db: DbHandle, list: FileList, opts: ScanOptions) -> Result<()> {
+    let (session, cp) = begin_session(&db, Vec::new(), true, &opts)?;
+    execute_scan(db, session, opts, cp, Some(list))
+}
+
+/// Record a new scan session, with a checkpoint to resume it from.
+fn begin_session(
+    db: &DbHandle,
+    norm_roots: Vec<String>,
+    file_list: bool,
+    opts: &ScanOptions,
+) -> Result<(ScanSession, ScanCheckpoint)> {
+    if let Some(cp) = db.load_scan_checkpoint()? {
+        tracing::warn!(
+            session = cp.session_id,
+            "discarding the checkpoint of an interrupted scan (use `scan --resume` to continue it)"
+        );
+        db.clear_scan_checkpoint()?;
+    }
+
+    // Errors from earlier scans of these roots are superseded by this one.
+    // Those of listed files are cleared as the list is read.
+    let cleared = db.clear_scan_errors_under(&norm_roots)?;
+    if cleared > 0 {
+        tracing::debug!(cleared, "cleared errors from previous scans");
+    }
+
+    let mut session = ScanSession {
+        id: 0,
+        roots: norm_roots,
+        follow_symlinks: opts.follow_symlinks,
+        recursive: opts.recursive,
+        detect_deletes: opts.detect_deletes,
+        one_file_system: opts.one_file_system,
+        watch: opts.watch,
+        lazy: opts.lazy,
+        archives: opts.archives,
+        file_list,
+        size_policy: opts.size_policy,
+        order: opts.order,
+        threads: opts.threads as u32,
+        hdd_threads: opts.hdd_threads as u32,
+        started: systemtime_to_unix_secs(SystemTime::now()),
+        finished: None,
+        counts: Default::default(),
+    };
+    session.id = db.begin_scan_session(&session)?;
+    tracing::debug!(session = session.id, "scan session started");
+
+    let cp = ScanCheckpoint {
+        session_id: session.id,
+        excludes: opts.excludes.spec().clone(),
+        ..Default::default()
+    };
+    // Resumable from the start, even if the first batch is never written
+    db.save_scan_checkpoint(&session, &cp)?;
+
+    Ok((session, cp))
+}
+
+/// Continue the scan recorded in the checkpoint, with its roots, options and
+/// exclude rules.
+pub fn resume_scan(
+    db: DbHandle,
+    threads: usize,
+    hdd_threads: usize,
+    walk_threads: usize,
+    max_read_rate: Option<u64>,
+    idle: bool,
+) -> Result<()> {
+    let cp = db
+        .load_scan_checkpoint()?
+        .ok_or_else(|| anyhow!("there is no interrupted scan to resume"))?;
+    let mut session = db
+        .get_scan_session(cp.session_id)?
+        .ok_or_else(|| anyhow!("checkpoint refers to unknown scan session {}", cp.session_id))?;
+    if session.file_list {
+        bail!(
+            "scan session {} read its files from a list, which cannot be resumed; run it again with the same list",
+            session.id
+        );
+    }
+
+    let opts = ScanOptions {
+        threads,
+        hdd_threads,
+        walk_threads,
+        max_read_rate,
+        idle,
+        follow_symlinks: session.follow_symlinks,
+        recursive: session.recursive,
+        detect_deletes: session.detect_deletes,
+        one_file_system: session.one_file_system,
+        // A resumed scan finishes the walk, but does not go on watching
+        watch: false,
+        lazy: session.lazy,
+        archives: session.archives,
+        size_policy: session.size_policy,
+        order: session.order,
+        excludes: ExcludeRules::new(cp.excludes.clone())?,
+    };
+    session.threads = threads as u32;
+    session.hdd_threads = hdd_threads as u32;
+
+    tracing::info!(
+        session = session.id,
+        roots_done = cp.roots_done,
+        roots = session.roots.len(),
+        pending = cp.pending.len(),
+        "resuming scan"
+    );
+
+    execute_scan(db, session, opts, cp, None)
+}
+
+fn execute_scan(
+    db: DbHandle,
+    mut session: ScanSession,
+    opts: ScanOptions,
+    cp: ScanCheckpoint,
+    files: Option<FileList>,
+) -> Result<()> {
+    let threads = opts.threads;
+    let db = Arc::new(db);
+    if let Some(rate) = opts.max_read_rate {
+        hashing::set_read_limit(rate);
+    }
+    let roots: Vec<PathBuf> = session.roots.iter().map(|r| path_utils::path_from_string(r)).collect();
+
+    // Set up before the walk, so changes made while walking are not lost
+    let watcher = if opts.watch {
+        Some(Watcher::new(roots.len())?)
+    } else {
+        None
+    };
+
+    // On resume, continue counting from where the interrupted scan stopped.
+    let counters = Arc::new(ScanCounters::from_counts(session.counts));
+    let progress = start_progress(counters.clone());
+    let state = Arc::new(Mutex::new(WalkState {
+        roots_done: cp.roots_done as usize,
+        frontier: cp.frontier.as_deref().map(path_utils::path_from_string),
+        pending: cp.pending.iter().cloned().collect(),
+    }));
+
+    let writer = Writer {
+        db: db.clone(),
+        session: session.clone(),
+        checkpoint: ScanCheckpoint {
+            session_id: session.id,
+            excludes: cp.excludes.clone(),
+            ..Default::default()
+        },
+        state: state.clone(),
+        counters: counters.clone(),
+        batch: Vec::new(),
+        errors: Vec::new(),
+        archives: Vec::new(),
+        moves: Vec::new(),
+        indexed: 0,
+        max_delay: if opts.watch {
+            WATCH_WRITE_DELAY
+        } else {
+            Writer::CHECKPOINT_INTERVAL
+        },
+    };
 
     const RESULT_QUEUE_PER_THREAD: usize = 8192;
-    let (res_tx, res_rx) = chan::bounded::<HashResult>(threads * RESULT_QUEUE_PER_THREAD);
-    let (job_tx, job_rx) = chan::bounded::<HashJob>(threads * 256);
-    let writer_handle = thread::spawn(move || writer_loop(db_for_writer, res_rx));
+    let (res_tx, res_rx) = chan::bounded::<WriterMsg>(threads * RESULT_QUEUE_PER_THREAD);
+    let writer_handle = thread::spawn(move || writer.run(res_rx));
 
-    // Spawn hash workers
-    let mut workers = Vec::new();
-    for _ in 0..threads {
-        let rx = job_rx.clone();
-        let tx = res_tx.clone();
-        workers.push(thread::spawn(move || worker_loop(rx, tx)));
+    // Hash workers are started per disk as files on it turn up
+    let tiers = Arc::new(Tiers::default());
+    let mut pools = WorkerPools {
+        ctx: WorkerContext {
+            tx: res_tx.clone(),
+            size_policy: opts.size_policy,
+            tiers: tiers.clone(),
+            state: state.clone(),
+            counters: counters.clone(),
+        },
+        threads,
+        hdd_threads: opts.hdd_threads,
+        idle: opts.idle,
+        disks: HashMap::new(),
+        queues: HashMap::new(),
+        workers: Vec::new(),
+    };
+
+    // Producer: walk filesystem and enqueue files
+    let mut producer = Producer {
+        db: &db,
+        opts: &opts,
+        pools: &mut pools,
+        res_tx: &res_tx,
+        counters: &counters,
+        state: &state,
+        seen: HashSet::new(),
+        missing: HashMap::new(),
+        sizes: HashMap::new(),
+        tiers: &tiers,
+        ordered: Vec::new(),
+        watcher,
+        plan: None,
+    };
+    match files {
+        Some(list) => producer.scan_files(list)?,
+        None => {
+            producer.requeue_pending(cp.pending)?;
+            producer.walk_roots(&roots, cp.roots_done as usize, cp.frontier.as_deref().map(path_utils::path_from_string))?;
+            producer.watch(&roots)?;
+        }
     }
 
     // Important: drop the extra sender in the main thread.
     // Only worker clones remain. Once workers exit, res_rx will close and writer will finish.
     drop(res_tx);
 
-    // Producer: walk filesystem and enqueue files
-    let seen =  walk_and_enqueue(db.clone(), roots, follow_symlinks, recursive, &job_tx)?;
-    drop(job_tx); // close channel so workers exit when queue is drained
-
     tracing::debug!("all jobs enqueued, waiting for workers");
 
-    // Wait for workers to finish
-    for h in workers {
-        let _ = h.join();
-    }
+    // Close the queues so workers exit when they are drained, and wait for them
+    pools.finish();
 
     tracing::debug!("all workers finished, waiting for writer");
 
     // Now res_tx clones in workers are dropped, so res_rx will close and writer ends.
-    let writer_result = writer_handle
+    let errors = writer_handle
         .join()
         .map_err(|_| anyhow::anyhow!("writer thread panicked"))??;
+    drop(progress);
+
+    session.counts = counters.snapshot();
+    session.finished = Some(systemtime_to_unix_secs(SystemTime::now()));
+    db.finish_scan_session(&session)?;
 
-    if detect_deletes {
-        tracing::debug!("Looking for deleted files...");
-        let marked = db.mark_missing_not_seen(&norm_roots, &seen)?;
-        tracing::info!(marked, "marked deleted files as Missing");
+    if errors.total() > 0 {
+        tracing::warn!(
+            errors = errors.total(),
+            permission = errors.permission,
+            vanished = errors.vanished,
+            io = errors.io,
+            other = errors.other,
+            "some files could not be scanned; run `deldupes errors` for details"
+        );
     }
 
     tracing::info!("scan complete");
 
-    Ok(writer_result)
+    Ok(())
 }
 
 
-fn writer_loop(db: Arc<DbHandle>, res_rx: chan::Receiver<HashResult>) -> Result<()> {
+/// Walk the roots like `run_scan()` would, and report what it would hash
+/// and mark as missing. Nothing is hashed, and the DB is not written to.
+pub fn plan_scan(db: DbHandle, roots: Vec<PathBuf>, opts: ScanOptions, explain: bool) -> Result<()> {
+    let roots: Vec<PathBuf> = roots
+        .iter()
+        .map(|p| path_utils::normalize_path(p))
+        .collect::<std::io::Result<_>>()?;
+
+    let counters = Arc::new(ScanCounters::default());
+    let state = Arc::new(Mutex::new(WalkState::default()));
+    let tiers = Arc::new(Tiers::default());
+    // Walk and stat errors end up here, rather than with a writer
+    let (res_tx, res_rx) = chan::unbounded::<WriterMsg>();
+    // Never sent to; nothing is hashed
+    let mut pools = WorkerPools {
+        ctx: WorkerContext {
+            tx: res_tx.clone(),
+            size_policy: opts.size_policy,
+            tiers: tiers.clone(),
+            state: state.clone(),
+            counters: counters.clone(),
+        },
+        threads: 0,
+        hdd_threads: 0,
+        idle: false,
+        disks: HashMap::new(),
+        queues: HashMap::new(),
+        workers: Vec::new(),
+    };
+
+    let mut producer = Producer {
+        db: &db,
+        opts: &opts,
+        pools: &mut pools,
+        res_tx: &res_tx,
+        counters: &counters,
+        state: &state,
+        seen: HashSet::new(),
+        missing: HashMap::new(),
+        sizes: HashMap::new(),
+        tiers: &tiers,
+        ordered: Vec::new(),
+        watcher: None,
+        plan: Some(ScanPlan::new(explain)),
+    };
+    producer.walk_roots(&roots, 0, None)?;
+    let mut plan = producer.plan.take().unwrap_or_default();
+    drop(res_tx);
+    pools.finish();
+
+    for msg in res_rx.try_iter() {
+        if let WriterMsg::Failed(e) = msg {
+            plan.add(PlanKind::Unreadable, &e.path, 0, &e.message);
+        }
+    }
+    plan.print();
+    Ok(())
+}
+
+/// Progress of this run; a resumed scan starts counting from zero.
+fn start_progress(counters: Arc<ScanCounters>) -> Progress {
+    let base = counters.snapshot();
+    Progress::start("scan", "hashed", move || {
+        let now = counters.snapshot();
+        ProgressSample {
+            found: now.files_walked - base.files_walked,
+            queued: counters.files_queued.load(Ordering::Relaxed),
+            queued_bytes: counters.bytes_queued.load(Ordering::Relaxed),
+            done: now.files_hashed - base.files_hashed + counters.files_compared.load(Ordering::Relaxed),
+            done_bytes: now.bytes_hashed - base.bytes_hashed + counters.bytes_compared.load(Ordering::Relaxed),
+            errors: now.errors - base.errors,
+            read_limit: hashing::read_limit(),
+        }
+    })
+}
+
+/// Owns the pending DB writes. Results are committed in batches, and after
+/// each commit (or at least every `CHECKPOINT_INTERVAL`) the walk state is
+/// saved so an interrupted scan can be resumed.
+struct Writer {
+    db: Arc<DbHandle>,
+    session: ScanSession,
+    checkpoint: ScanCheckpoint,
+    state: Arc<Mutex<WalkState>>,
+    counters: Arc<ScanCounters>,
+    batch: Vec<(String, FileMeta)>,
+    errors: Vec<ScanError>,
+    /// Archives whose members were all sent, with the member paths
+    archives: Vec<(String, HashSet<String>)>,
+    /// New paths in `batch` with the file_id they were moved from
+    moves: Vec<(String, u64)>,
+    indexed: u64,
+    /// Longest time a result is held back before it is written
+    max_delay: Duration,
+}
+
+impl Writer {
     const BATCH_SIZE: usize = 10_000;
+    const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+    fn run(mut self, res_rx: chan::Receiver<WriterMsg>) -> Result<ErrorCounts> {
+        let mut counts = ErrorCounts::default();
+        let mut last_checkpoint = Instant::now();
 
-    let mut indexed: u64 = 0;
-    let mut batch: Vec<(String, Vec<u8>, Hash256)> = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            match res_rx.recv_timeout(self.max_delay) {
+                Ok(WriterMsg::Hashed(r)) => {
+                    self.counters.files_hashed.fetch_add(1, Ordering::Relaxed);
+                    self.counters.bytes_hashed.fetch_add(r.meta.size, Ordering::Relaxed);
 
-    while let Ok(r) = res_rx.recv() {
-        // Prepare DB item
-        let blob = r.meta.encode();
-        batch.push((r.path, blob, r.meta.hash256));
+                    self.batch.push((r.path, r.meta));
+                }
+                Ok(WriterMsg::Recorded(r) | WriterMsg::Compared(r) | WriterMsg::Member(r)) => {
+                    self.batch.push((r.path, r.meta));
+                }
+                Ok(WriterMsg::ArchiveDone { archive, members }) => {
+                    self.archives.push((archive, members));
+                }
+                Ok(WriterMsg::Moved { result, from }) => {
+                    self.counters.files_moved.fetch_add(1, Ordering::Relaxed);
+                    self.moves.push((result.path.clone(), from));
+                    self.batch.push((result.path, result.meta));
+                }
+                Ok(WriterMsg::Failed(e)) => {
+                    tracing::debug!(path = %e.path, phase = e.phase.as_str(), "{}", e.message);
+                    counts.add(e.kind);
+                    self.counters.errors.fetch_add(1, Ordering::Relaxed);
+                    self.errors.push(e);
+                }
+                Err(chan::RecvTimeoutError::Timeout) => {}
+                Err(chan::RecvTimeoutError::Disconnected) => break,
+            }
+
+            let full = self.batch.len() >= Self::BATCH_SIZE || self.errors.len() >= Self::BATCH_SIZE;
+            let waiting = !self.batch.is_empty() || !self.errors.is_empty();
+            let since = last_checkpoint.elapsed();
+            if full || since >= Self::CHECKPOINT_INTERVAL || (waiting && since >= self.max_delay) {
+                self.flush()?;
+                self.save_checkpoint()?;
+                last_checkpoint = Instant::now();
+
+                if full {
+                    tracing::debug!(indexed = self.indexed, "scan progress");
+                }
+            }
+        }
+
+        // Flush remaining
+        self.flush()?;
+
+        tracing::info!(indexed = self.indexed, "scan finished");
+        Ok(counts)
+    }
 
-        if batch.len() >= BATCH_SIZE {
-            db.write_batch_versions(&batch)?;
-            indexed += batch.len() as u64;
-            batch.clear();
+    fn flush(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            self.db.write_batch_versions(self.session.id, &self.batch)?;
+            self.indexed += self.batch.len() as u64;
+        }
+        if !self.moves.is_empty() {
+            self.db.record_moves(&self.moves)?;
+            self.moves.clear();
+        }
+        if !self.errors.is_empty() {
+            self.db.write_scan_errors(&self.errors)?;
+        }
+        for (archive, members) in self.archives.drain(..) {
+            let gone: Vec<u64> = self
+                .db
+                .live_members(&archive)?
+                .into_iter()
+                .filter(|(path, _)| !members.contains(path))
+                .map(|(_, fid)| fid)
+                .collect();
+            if !gone.is_empty() {
+                self.db.mark_files_missing(&gone)?;
+                self.counters.files_missing.fetch_add(gone.len() as u64, Ordering::Relaxed);
+            }
+        }
 
-            tracing::info!(indexed, "scan progress");
+        // Only now are these files no longer pending
+        let mut st = lock_state(&self.state);
+        for (path, _) in self.batch.drain(..) {
+            st.pending.remove(&path);
+        }
+        for e in self.errors.drain(..) {
+            if e.phase == ErrorPhase::Hash {
+                st.pending.remove(&e.path);
+            }
         }
+        Ok(())
     }
 
-    // Flush remaining
-    if !batch.is_empty() {
-        db.write_batch_versions(&batch)?;
-        indexed += batch.len() as u64;
-        batch.clear();
+    fn save_checkpoint(&mut self) -> Result<()> {
+        {
+            let st = lock_state(&self.state);
+            self.checkpoint.roots_done = st.roots_done as u32;
+            self.checkpoint.frontier = st.frontier.as_ref().map(|p| path_utils::path_to_string(p));
+            self.checkpoint.pending = st.pending.iter().cloned().collect();
+            self.session.counts = self.counters.snapshot();
+        }
+        self.db.save_scan_checkpoint(&self.session, &self.checkpoint)
     }
+}
 
-    tracing::info!(indexed, "scan finished");
-    Ok(())
+
+/// Hash workers, with a queue and a pool of workers for each disk. A
+/// spinning disk gets few workers, so it is not made to seek between files
+/// read in parallel, while an SSD gets as many as there are CPUs to use.
+struct WorkerPools {
+    ctx: WorkerContext,
+    /// Workers per disk that is not rotational, or of unknown type
+    threads: usize,
+    /// Workers per rotational disk
+    hdd_threads: usize,
+    /// Lower the priority of the workers
+    idle: bool,
+    /// `st_dev` to disk
+    disks: HashMap<u64, DiskInfo>,
+    /// Disk to job queue
+    queues: HashMap<u64, chan::Sender<HashJob>>,
+    workers: Vec<thread::JoinHandle<()>>,
 }
 
+impl WorkerPools {
+    fn send(&mut self, job: HashJob) {
+        let info = *self
+            .disks
+            .entry(job.stat.dev)
+            .or_insert_with(|| device::disk_info(job.stat.dev));
 
-use std::time::{Duration, Instant};
+        let queue = self.queues.entry(info.disk).or_insert_with(|| {
+            let n = if info.rotational {
+                self.hdd_threads
+            } else {
+                self.threads
+            };
+            tracing::debug!(disk = info.name(), rotational = info.rotational, workers = n, "starting hash workers");
 
-fn worker_loop(rx: chan::Receiver<HashJob>, tx: chan::Sender<HashResult>) {
+            let (job_tx, job_rx) = chan::bounded::<HashJob>(n * 256);
+            for _ in 0..n {
+                let rx = job_rx.clone();
+                let ctx = self.ctx.clone();
+                let idle = self.idle;
+                self.workers.push(thread::spawn(move || {
+                    if idle {
+                        hashing::lower_thread_priority();
+                    }
+                    worker_loop(rx, ctx)
+                }));
+            }
+            job_tx
+        });
+        let _ = queue.send(job);
+    }
+
+    fn finish(self) {
+        drop(self.queues);
+        drop(self.ctx);
+        for h in self.workers {
+            let _ = h.join();
+        }
+    }
+}
+
+/// What the hash workers share with the rest of the scan.
+#[derive(Clone)]
+struct WorkerContext {
+    tx: chan::Sender<WriterMsg>,
+    /// For archive members
+    size_policy: SizePolicy,
+    tiers: Arc<Tiers>,
+    state: Arc<Mutex<WalkState>>,
+    counters: Arc<ScanCounters>,
+}
+
+fn worker_loop(rx: chan::Receiver<HashJob>, ctx: WorkerContext) {
     let mut job_count: u64 = 0;
     let mut bytes_processed: u64 = 0;
     let mut last_job_duration: Option<Duration> = None;
@@ -133,34 +860,84 @@ fn worker_loop(rx: chan::Receiver<HashJob>, tx: chan::Sender<HashResult>) {
     while let Ok(job) = rx.recv() {
         let path = job.path;
         let t0 = Instant::now();
+        // Files recorded earlier that match this one
+        let mut work = None;
 
-        let r: Result<HashResult> = (|| {
-            // optional: still validate it's a file
+        let r: Result<Option<HashResult>> = (|| {
+            // The file may have been replaced since it was enqueued
             let md = std::fs::metadata(&path)
             .with_context(|| format!("metadata {}", path.display()))?;
             if !md.is_file() {
-                return Err(anyhow::anyhow!("not a file"));
+                return Err(anyhow::anyhow!("no longer a regular file"));
+            }
+
+            if job.tiering == Tiering::Compare {
+                let (full, w) = ctx
+                    .compare_tiers(&path, &job.stat)
+                    .with_context(|| format!("hash {}", path.display()))?;
+                work = Some(w);
+                if !full {
+                    ctx.counters.files_compared.fetch_add(1, Ordering::Relaxed);
+                    ctx.counters.bytes_compared.fetch_add(job.stat.size, Ordering::Relaxed);
+                    return Ok(None);
+                }
             }
 
-            let meta = hashing::hash_file(&path, job.mtime, job.size)
+            let meta = hashing::hash_file(&path, &job.stat)
             .with_context(|| format!("hash {}", path.display()))?;
 
-            Ok(HashResult {
-                path: path.to_string_lossy().to_string(),
+            if job.tiering == Tiering::Record
+                && let Some((head, tail)) = meta.head_tail()
+            {
+                let mut w = TierWork::new((meta.size, head));
+                ctx.tiers.add(&mut w, Some(tail), true, &path, || {});
+                work = Some(w);
+            }
+
+            Ok(Some(HashResult {
+                path: path_utils::path_to_string(&path),
                meta,
-            })
+            }))
         })();
+        let from = job.moved_from.and_then(|(fid, hash256)| {
+            matches!(&r, Ok(Some(r)) if r.meta.hash256 == Some(hash256)).then_some(fid)
+        });
 
         let dt = t0.elapsed();
         last_job_duration = Some(dt);
 
-        if let Ok(r) = r {
-            job_count += 1;
-            bytes_processed += r.meta.size;
-
-            if tx.send(r).is_err() {
-                break;
+        let msg = match r {
+            Ok(Some(r)) => {
+                job_count += 1;
+                bytes_processed += r.meta.size;
+                Some(match from {
+                    Some(from) => WriterMsg::Moved { result: r, from },
+                    None => WriterMsg::Hashed(r),
+                })
             }
+            // Already sent by compare_tiers()
+            Ok(None) => None,
+            Err(e) => Some(WriterMsg::Failed(ScanError::from_anyhow(&path, ErrorPhase::Hash, &e))),
+        };
+
+        let hashed = matches!(msg, Some(WriterMsg::Hashed(_)));
+        if let Some(msg) = msg
+            && ctx.tx.send(msg).is_err()
+        {
+            break;
+        }
+
+        if hashed
+            && let Some(kind) = job.archive
+            && !ctx.hash_members(&path, kind, &job.stat)
+        {
+            break;
+        }
+
+        if let Some(work) = work
+            && !ctx.follow_up(work)
+        {
+            break;
         }
     }
 
@@ -186,99 +963,1250 @@ fn worker_loop(rx: chan::Receiver<HashJob>, tx: chan::Sender<HashResult>) {
 }
 
 
-fn walk_and_enqueue(
-    db: Arc<DbHandle>,
-    roots: Vec<PathBuf>,
-    follow_symlinks: bool,
-    recursive: bool,
-    job_tx: &chan::Sender<HashJob>,
-) -> anyhow::Result<HashSet<String>> {
-    let mut visited_dirs: HashSet<(u64, u64)> = HashSet::new();
-    let mut seen: HashSet<String> = HashSet::new();
-
-    for root in roots {
-        if recursive {
-            let walker = walkdir::WalkDir::new(&root)
-                .follow_links(follow_symlinks)
-                .into_iter()
-                .filter_entry(|e| filter_dir_entry(e, &mut visited_dirs));
+impl WorkerContext {
+    /// Decide from its prefix hash, and only if another file of its size
+    /// has the same prefix from its tail hash, whether a file needs a full
+    /// hash. If not, the hashes that were read are sent to the writer.
+    ///
+    /// Returns true if the file needs a full hash, with the files recorded
+    /// earlier that match it.
+    fn compare_tiers(&self, path: &Path, stat: &FileStat) -> Result<(bool, TierWork)> {
+        let head = hashing::hash_head(path)?;
+        let mut work = TierWork::new((stat.size, head));
+        let partial = |tail| FileMeta {
+            sha1prefix_4k: Some(head),
+            sha1tail: tail,
+            ..FileMeta::hash_pending(stat)
+        };
+
+        let tier = self.tiers.add(&mut work, None, false, path, || self.send_compared(path, partial(None)));
+        if tier != Tier::SameHead {
+            return Ok((tier == Tier::SameTail, work));
+        }
+
+        let tail = hashing::hash_tail(path, stat.size)?;
+        let tier = self.tiers.add(&mut work, Some(tail), false, path, || {
+            self.send_compared(path, partial(Some(tail)))
+        });
+        Ok((tier == Tier::SameTail, work))
+    }
+
+    fn send_compared(&self, path: &Path, meta: FileMeta) {
+        let _ = self.tx.send(WriterMsg::Compared(HashResult {
+            path: path_utils::path_to_string(path),
+            meta,
+        }));
+    }
+
+    /// Read the tails of files recorded earlier with the same prefix hash,
+    /// and fully hash those that match another file. Returns false if the
+    /// writer is gone.
+    fn follow_up(&self, mut work: TierWork) -> bool {
+        while let Some(path) = work.tails.pop() {
+            // If it is gone or changed, the walk (or a later scan) deals with it
+            let Ok(md) = std::fs::metadata(&path) else {
+                continue;
+            };
+            let stat = FileStat::from_metadata(&md);
+            if !md.is_file() || stat.size != work.key.0 {
+                continue;
+            }
+
+            let tail = match hashing::hash_tail(&path, stat.size).with_context(|| format!("hash {}", path.display())) {
+                Ok(tail) => tail,
+                Err(e) => {
+                    if self.tx.send(WriterMsg::Failed(ScanError::from_anyhow(&path, ErrorPhase::Hash, &e))).is_err() {
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            let partial = FileMeta {
+                sha1prefix_4k: Some(work.key.1),
+                sha1tail: Some(tail),
+                ..FileMeta::hash_pending(&stat)
+            };
+            if self.tiers.add(&mut work, Some(tail), false, &path, || self.send_compared(&path, partial)) == Tier::SameTail {
+                work.full.push(path);
+            }
+        }
+
+        work.full.iter().all(|path| self.hash_earlier(path))
+    }
+
+    /// Send the members of an archive to the writer, followed by the list of
+    /// them. Returns false if the writer is gone.
+    fn hash_members(&self, path: &Path, kind: ArchiveKind, stat: &FileStat) -> bool {
+        let archive_path = path_utils::path_to_string(path);
+        let mut members = HashSet::new();
+        let mut writer_gone = false;
+
+        let r = archive::hash_members(path, kind, |name, hashes| {
+            let member_path = archive::virtual_path(&archive_path, &name);
+            let msg = match hashes {
+                Ok(h) => {
+                    if !self.size_policy.allows(h.size) {
+                        return;
+                    }
+                    // Members have no inode of their own; the archive's would
+                    // make them look like hardlinks of each other
+                    let meta = FileMeta {
+                        size: h.size,
+                        inode: None,
+                        allocated: None,
+                        member: true,
+                        ..FileMeta::new(stat, h.hash256, h.sha1prefix, h.sha1tail)
+                    };
+                    members.insert(member_path.clone());
+                    WriterMsg::Member(HashResult { path: member_path, meta })
+                }
+                Err(e) => WriterMsg::Failed(ScanError::from_anyhow(&path_utils::path_from_string(&member_path), ErrorPhase::Archive, &e)),
+            };
+            writer_gone |= self.tx.send(msg).is_err();
+        });
+
+        let msg = match r {
+            Ok(()) => WriterMsg::ArchiveDone {
+                archive: archive_path,
+                members,
+            },
+            // Members that were read are kept; the rest stay as they were
+            Err(e) => WriterMsg::Failed(ScanError::from_anyhow(path, ErrorPhase::Archive, &e)),
+        };
+        !writer_gone && self.tx.send(msg).is_ok()
+    }
+
+    /// Fully hash a file that was recorded with only its prefix and tail.
+    /// Returns false if the writer is gone.
+    fn hash_earlier(&self, path: &Path) -> bool {
+        // If it is gone or no longer a file, the walk (or a later scan) deals with it
+        let Ok(md) = std::fs::metadata(path) else {
+            return true;
+        };
+        if !md.is_file() {
+            return true;
+        }
+        let stat = FileStat::from_metadata(&md);
+
+        lock_state(&self.state).pending.insert(path_utils::path_to_string(path));
+        self.counters.files_queued.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_queued.fetch_add(stat.size, Ordering::Relaxed);
+
+        let msg = match hashing::hash_file(path, &stat).with_context(|| format!("hash {}", path.display())) {
+            Ok(meta) => WriterMsg::Hashed(HashResult {
+                path: path_utils::path_to_string(path),
+                meta,
+            }),
+            Err(e) => WriterMsg::Failed(ScanError::from_anyhow(path, ErrorPhase::Hash, &e)),
+        };
+        self.tx.send(msg).is_ok()
+    }
+}
+
+
+/// The walking side of a scan: decides what to hash and tracks what was seen.
+struct Producer<'a> {
+    db: &'a DbHandle,
+    opts: &'a ScanOptions,
+    pools: &'a mut WorkerPools,
+    res_tx: &'a chan::Sender<WriterMsg>,
+    counters: &'a ScanCounters,
+    state: &'a Mutex<WalkState>,
+    seen: HashSet<String>,
+    /// Files this walk marked Missing, by size, as (file_id, path), so a
+    /// file moved into a root walked later is still taken as moved
+    missing: HashMap<u64, Vec<(u64, String)>>,
+    /// Sizes of the files sent on by this scan, for lazy hashing
+    sizes: HashMap<u64, SizeSeen>,
+    tiers: &'a Tiers,
+    /// Changed files waiting to be sorted by `--order`
+    ordered: Vec<HashJob>,
+    /// Watches the walked directories when scanning with `--watch`
+    watcher: Option<Watcher>,
+    /// With `scan --plan`, what would be done, instead of doing it
+    plan: Option<ScanPlan>,
+}
+
+/// What a scan knows about the files of one size.
+enum SizeSeen {
+    /// Only this file, recorded without a hash
+    Single(PathBuf),
+    /// More than one file; they are compared by prefix and tail hashes,
+    /// and only fully hashed if those match
+    Tiered,
+    /// More than one file; all of them are fully hashed
+    Full,
+}
+
+impl Producer<'_> {
+    /// Hash again whatever an interrupted scan had enqueued but not written.
+    fn requeue_pending(&mut self, pending: Vec<String>) -> Result<()> {
+        // Already counted, and still in the pending set until written.
+        // Versions that were written without a hash are hashed now, since
+        // a file of the same size may have been found after them.
+        for path in pending {
+            match self.check_candidate(&path_utils::path_from_string(&path), HashNeed::Full)? {
+                Candidate::Changed(job) => self.send_job(job),
+                Candidate::Moved(result, from, _) => {
+                    let _ = self.res_tx.send(WriterMsg::Moved { result, from });
+                }
+                _ => {
+                    lock_state(self.state).pending.remove(&path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk `roots[first..]`. `frontier` is where an interrupted walk of
+    /// `roots[first]` stopped.
+    ///
+    /// Missing-file detection for a root runs as soon as its walk is complete,
+    /// so it is never based on a partial walk.
+    fn walk_roots(&mut self, roots: &[PathBuf], first: usize, mut frontier: Option<PathBuf>) -> Result<()> {
+        let mut visited_dirs: HashSet<(u64, u64)> = HashSet::new();
+
+        for (i, root) in roots.iter().enumerate().skip(first) {
+            let skipped_dirs = self.walk_dir(i, root, root, frontier.as_deref(), &mut visited_dirs)?;
 
-            for entry in walker {
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(_) => continue, // later: report
+            if self.opts.detect_deletes {
+                self.mark_missing(roots, i, frontier.as_deref(), &skipped_dirs)?;
+            }
+
+            frontier = None;
+            let mut st = lock_state(self.state);
+            st.roots_done = i + 1;
+            st.frontier = None;
+        }
+        self.missing.clear();
+        Ok(())
+    }
+
+    /// Walk `dir`, which is root number `index` or a directory below it.
+    ///
+    /// Returns the directories that were skipped because they contain a
+    /// marker file, are on another filesystem or could not be read.
+    fn walk_dir(
+        &mut self,
+        index: usize,
+        root: &Path,
+        dir: &Path,
+        frontier: Option<&Path>,
+        visited_dirs: &mut HashSet<(u64, u64)>,
+    ) -> Result<Vec<PathBuf>> {
+        let opts = self.opts;
+        let mut skipped_dirs: Vec<PathBuf> = Vec::new();
+        // Kept apart from skipped_dirs, which the filter_entry closure borrows
+        let mut unreadable: Vec<PathBuf> = Vec::new();
+
+        // Entries are visited in sorted order, so everything up to the
+        // frontier is known to be done when resuming.
+        if opts.recursive {
+            let root_dev = if opts.one_file_system {
+                device_of(root)
+            } else {
+                None
+            };
+
+            if opts.walk_threads > 1 {
+                let skipped = Mutex::new(Vec::new());
+                // Run by the workers; which name of a directory seen twice is
+                // walked is decided below, in walk order
+                let accept = |path: &Path, is_dir: bool| {
+                    let mut skipped_here = Vec::new();
+                    let accepted = !frontier.is_some_and(|f| already_walked(path, f))
+                        && filter_dir_entry(path, is_dir, root_dev, &opts.excludes, &mut skipped_here, |_| true);
+                    if !skipped_here.is_empty() {
+                        skipped.lock().unwrap_or_else(PoisonError::into_inner).append(&mut skipped_here);
+                    }
+                    accepted
                 };
+                let walked = walker::walk_parallel(dir, opts.walk_threads, opts.follow_symlinks, &accept, |entry| {
+                    match entry {
+                        Ok(e) => {
+                            if e.id.is_some_and(|id| !visited_dirs.insert(id)) {
+                                return Ok(false);
+                            }
+                            self.walked_entry(index, e.path, e.file_type)?;
+                            Ok(true)
+                        }
+                        Err(e) => {
+                            let _ = self.res_tx.send(WriterMsg::Failed(ScanError::from_io(&e.path, ErrorPhase::Walk, &e.error)));
+                            unreadable.push(e.path);
+                            Ok(true)
+                        }
+                    }
+                });
+                skipped_dirs.append(&mut skipped.into_inner().unwrap_or_else(PoisonError::into_inner));
+                walked?;
+            } else {
+                let walker = walkdir::WalkDir::new(dir)
+                    .follow_links(opts.follow_symlinks)
+                    .sort_by_file_name()
+                    .into_iter()
+                    .filter_entry(|e| {
+                        !frontier.is_some_and(|f| already_walked(e.path(), f))
+                            && filter_dir_entry(
+                                e.path(),
+                                e.file_type().is_dir(),
+                                root_dev,
+                                &opts.excludes,
+                                &mut skipped_dirs,
+                                |key| visited_dirs.insert(key),
+                            )
+                    });
 
-                // WalkDir already knows the file type, but we still want the central logic.
-                if entry.file_type().is_file() {
-                    let _ = enqueue_if_candidate(&db, entry.into_path(), job_tx, &mut seen);
+                for entry in walker {
+                    match entry {
+                        Ok(e) => {
+                            let file_type = e.file_type();
+                            self.walked_entry(index, e.into_path(), file_type)?;
+                        }
+                        Err(e) => {
+                            if let Some(p) = e.path() {
+                                unreadable.push(p.to_path_buf());
+                            }
+                            let _ = self.res_tx.send(WriterMsg::Failed(ScanError::from_walkdir(ErrorPhase::Walk, &e)));
+                        }
+                    }
                 }
             }
         } else {
-            if let Ok(rd) = std::fs::read_dir(&root) {
-                for e in rd.flatten() {
-                    let p = e.path();
-                    let _ = enqueue_if_candidate(&db, p, job_tx, &mut seen);
+            if let Some(w) = self.watcher.as_mut() {
+                w.watch_dir(index, dir);
+            }
+            let rd = match std::fs::read_dir(dir) {
+                Ok(rd) => rd,
+                Err(e) => {
+                    unreadable.push(dir.to_path_buf());
+                    let _ = self.res_tx.send(WriterMsg::Failed(ScanError::from_io(dir, ErrorPhase::Walk, &e)));
+                    return Ok(unreadable);
+                }
+            };
+            let mut paths = Vec::new();
+            for e in rd {
+                match e {
+                    Ok(e) => paths.push(e.path()),
+                    Err(e) => {
+                        let _ = self.res_tx.send(WriterMsg::Failed(ScanError::from_io(dir, ErrorPhase::Walk, &e)));
+                    }
                 }
             }
+            paths.sort();
+
+            for p in paths {
+                if frontier.is_some_and(|f| already_walked(&p, f)) {
+                    continue;
+                }
+                if opts.excludes.is_excluded(&p, false) {
+                    continue;
+                }
+                self.enqueue_and_advance(p)?;
+            }
         }
+
+        self.flush_ordered()?;
+        skipped_dirs.append(&mut unreadable);
+        Ok(skipped_dirs)
     }
 
-    Ok(seen)
-}
+    /// Handle one entry of a recursive walk.
+    fn walked_entry(&mut self, index: usize, path: PathBuf, file_type: std::fs::FileType) -> Result<()> {
+        // Watched before its entries are read, so nothing added in between is missed
+        if file_type.is_dir()
+            && let Some(w) = self.watcher.as_mut()
+        {
+            w.watch_dir(index, &path);
+        }
 
-fn enqueue_if_candidate(db: &DbHandle, path: PathBuf,
-                        job_tx: &chan::Sender<HashJob>,
-                        seen: &mut HashSet<String>) -> Result<()> {
-    let norm = path_utils::normalize_path(&path)?;
-    let norm_str = norm.to_string_lossy().to_string();
+        // The walker already knows the file type, but we still want the central logic.
+        if file_type.is_file() {
+            self.enqueue_and_advance(path)?;
+        } else if !file_type.is_dir()
+            && let Some(plan) = self.plan.as_mut()
+        {
+            let what = if file_type.is_symlink() { "symlink" } else { "special file" };
+            plan.add(PlanKind::NotRegular, &path_utils::path_to_string(&path), 0, what);
+        }
+        Ok(())
+    }
 
-    // record seen BEFORE any early return
-    seen.insert(norm_str.clone());
+    /// After the walk, index changes reported by the watcher until asked to stop.
+    fn watch(&mut self, roots: &[PathBuf]) -> Result<()> {
+        let Some(dirs) = self.watcher.as_ref().map(Watcher::watched_dirs) else {
+            return Ok(());
+        };
+        // Only now, so Ctrl-C still interrupts the initial walk, which can be
+        // continued with `scan --resume` like any other scan.
+        watch::install_stop_handler();
+        tracing::info!(dirs, "watching for changes, press Ctrl-C to stop");
 
-    // Cheap checks first
-    let md = match std::fs::metadata(&path) {
-        Ok(m) => m,
-        Err(_) => return Ok(()),
-    };
-    if !md.is_file() || md.len() == 0 {
-        return Ok(());
+        // Only needed for missing-file detection after a walk
+        self.seen.clear();
+        let mut last_rescan = Instant::now();
+        // Deleted files and directories (path, is_dir), not yet marked as Missing
+        let mut removed: Vec<(PathBuf, bool)> = Vec::new();
+
+        while !watch::stop_requested() {
+            let Some(w) = self.watcher.as_mut() else {
+                break;
+            };
+            let events = w.wait(WATCH_POLL)?;
+
+            let mut rescan: Vec<usize> = Vec::new();
+            for (index, event) in events {
+                match event {
+                    WatchEvent::FileChanged(path) => {
+                        if self.opts.excludes.is_excluded(&path, false) {
+                            continue;
+                        }
+                        tracing::debug!(path = %path.display(), "changed");
+                        removed.retain(|(p, _)| *p != path);
+                        self.enqueue_and_advance(path)?;
+                    }
+                    WatchEvent::FileRemoved(path) => {
+                        tracing::debug!(path = %path.display(), "removed");
+                        removed.push((path, false));
+                    }
+                    WatchEvent::DirAdded(path) => {
+                        tracing::debug!(path = %path.display(), "directory added");
+                        removed.retain(|(p, _)| !p.starts_with(&path));
+                        self.walk_dir(index, &roots[index], &path, None, &mut HashSet::new())?;
+                    }
+                    WatchEvent::DirRemoved(path) => {
+                        tracing::debug!(path = %path.display(), "directory removed");
+                        if let Some(w) = self.watcher.as_mut() {
+                            w.unwatch_under(index, &path);
+                        }
+                        removed.push((path, true));
+                    }
+                    WatchEvent::Overflow => {
+                        tracing::warn!(root = %roots[index].display(), "inotify queue overflow, rescanning root");
+                        if !rescan.contains(&index) {
+                            rescan.push(index);
+                        }
+                    }
+                }
+            }
+            self.flush_ordered()?;
+
+            if self.opts.detect_deletes {
+                self.mark_removed(&mut removed)?;
+            } else {
+                removed.clear();
+            }
+
+            if last_rescan.elapsed() >= WATCH_RESCAN_INTERVAL {
+                last_rescan = Instant::now();
+                for i in 0..roots.len() {
+                    if self.watcher.as_ref().is_some_and(|w| w.is_incomplete(i)) && !rescan.contains(&i) {
+                        rescan.push(i);
+                    }
+                }
+            }
+            for i in rescan {
+                self.rescan_root(roots, i)?;
+            }
+
+            self.seen.clear();
+        }
+
+        tracing::info!("stopped watching");
+        Ok(())
     }
 
-    let size = md.len();
-    let mtime = match md.modified() {
-        Ok(t) => systemtime_to_unix_secs(t),
-        Err(_) => return Ok(()),
-    };
+    /// Send the files in `list` on like the walk would, without walking the
+    /// directories in it. Listed files that are gone are marked Missing.
+    fn scan_files(&mut self, mut list: FileList) -> Result<()> {
+        /// Listed files whose earlier scan errors are cleared at once
+        const CHUNK: usize = 4096;
+
+        let mut removed: Vec<(PathBuf, bool)> = Vec::new();
+        loop {
+            let mut chunk: Vec<PathBuf> = Vec::new();
+            for path in list.by_ref().take(CHUNK) {
+                chunk.push(path_utils::normalize_path(&path?)?);
+            }
+            if chunk.is_empty() {
+                break;
+            }
+
+            // Errors from earlier scans of these files are superseded by this
+            // one, so they go before any new ones are written.
+            let listed: HashSet<String> = chunk.iter().map(|p| path_utils::path_to_string(p)).collect();
+            let cleared = self.db.clear_scan_errors_for(&listed)?;
+            if cleared > 0 {
+                tracing::debug!(cleared, "cleared errors from previous scans");
+            }
+
+            for path in chunk {
+                if self.opts.excludes.is_excluded_or_under(&path) {
+                    tracing::debug!(path = %path.display(), "excluded");
+                    continue;
+                }
+                match std::fs::symlink_metadata(&path) {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        if self.opts.detect_deletes {
+                            removed.push((path, false));
+                        }
+                        continue;
+                    }
+                    Ok(md) if md.is_dir() => {
+                        tracing::debug!(path = %path.display(), "not walking listed directory");
+                        continue;
+                    }
+                    Ok(md) if md.is_symlink() && !self.opts.follow_symlinks => continue,
+                    // Other errors are reported by the candidate check
+                    _ => {}
+                }
+                self.enqueue_and_advance(path)?;
+            }
+        }
+        self.flush_ordered()?;
+
+        // Nothing was enqueued for these, so none are left waiting
+        self.mark_removed(&mut removed)
+    }
+
+    /// Walk a root again while watching, for when events were lost.
+    fn rescan_root(&mut self, roots: &[PathBuf], index: usize) -> Result<()> {
+        let root = &roots[index];
+        tracing::info!(root = %root.display(), "rescanning");
+
+        self.seen.clear();
+        let skipped_dirs = self.walk_dir(index, root, root, None, &mut HashSet::new())?;
+        if self.opts.detect_deletes {
+            self.mark_missing(roots, index, None, &skipped_dirs)?;
+        }
+        self.missing.clear();
+        Ok(())
+    }
 
-    // Preflight skip: if current meta matches size+mtime => assume unchanged
-    if let Some((cur_size, cur_mtime)) = db.get_current_size_mtime_by_path(&norm_str)? {
-        if cur_size == size && cur_mtime == mtime {
+    /// Mark files and directories reported as deleted by the watcher as
+    /// Missing. Paths with hash jobs still in flight are left in `removed`
+    /// until those are written, so the late write does not bring them back.
+    fn mark_removed(&self, removed: &mut Vec<(PathBuf, bool)>) -> Result<()> {
+        let ready: Vec<(PathBuf, bool)> = {
+            let st = lock_state(self.state);
+            let (waiting, ready) = removed
+                .drain(..)
+                .partition(|(r, _)| st.pending.iter().any(|p| path_utils::path_from_string(p).starts_with(r)));
+            *removed = waiting;
+            ready
+        };
+
+        let mut marked = 0u64;
+        let mut ids = Vec::new();
+        for (path, is_dir) in ready {
+            let path_str = path_utils::path_to_string(&path);
+            if is_dir {
+                marked += self.db.mark_missing_not_seen(&[path_str], &HashSet::new(), |p, _| {
+                    self.opts.excludes.is_excluded_or_under(&path_utils::path_from_string(p))
+                })?;
+            } else if let Some(cur) = self.db.get_current_by_path(&path_str)?
+                && cur.state == FileState::Live
+                && !self.opts.excludes.is_excluded_or_under(&path)
+            {
+                ids.push(cur.file_id);
+                // Files that were in a deleted archive are gone with it
+                if archive::kind_of(&path).is_some() {
+                    let members = self.db.live_members(&path_str)?;
+                    ids.extend(members.into_iter().map(|(_, fid)| fid));
+                }
+            }
+        }
+        if !ids.is_empty() {
+            self.db.mark_files_missing(&ids)?;
+            marked += ids.len() as u64;
+        }
+
+        if marked > 0 {
+            self.counters.files_missing.fetch_add(marked, Ordering::Relaxed);
+            tracing::info!(marked, "marked deleted files as Missing");
+        }
+        Ok(())
+    }
+
+    fn mark_missing(
+        &mut self,
+        roots: &[PathBuf],
+        index: usize,
+        frontier: Option<&Path>,
+        skipped_dirs: &[PathBuf],
+    ) -> Result<()> {
+        let root = &roots[index];
+        let later_roots = &roots[index + 1..];
+
+        tracing::debug!(root = %root.display(), "Looking for deleted files...");
+        // Files under excluded, marker-skipped, unreadable or other-filesystem
+        // directories were not looked at, so they must not be taken as deleted.
+        // Neither are files outside the size policy, files the interrupted part
+        // of a resumed scan already handled, nor files a later root will look at.
+        // Files in an archive that is still there are kept up to date by the writer.
+        let excluded = |path: &str, meta: &FileMeta| {
+            if meta.member && archive::split_virtual(path, |a| self.seen.contains(a)).is_some() {
+                return true;
+            }
+            let path = path_utils::path_from_string(path);
+            !self.opts.size_policy.allows(meta.size)
+                || self.opts.excludes.is_excluded_or_under(&path)
+                || skipped_dirs.iter().any(|d| path.starts_with(d))
+                || frontier.is_some_and(|f| already_walked(&path, f))
+                || later_roots.iter().any(|r| r != root && path.starts_with(r))
+        };
+
+        let root_str = path_utils::path_to_string(root);
+        if self.plan.is_some() {
+            let missing = self.db.find_missing_not_seen(&[root_str], &self.seen, excluded)?;
+            if let Some(plan) = self.plan.as_mut() {
+                for (_, path, size) in missing {
+                    plan.add(PlanKind::Missing, &path, size, "not found by the walk");
+                }
+            }
             return Ok(());
         }
+        let missing = self.db.find_missing_not_seen(&[root_str], &self.seen, excluded)?;
+        let ids: Vec<u64> = missing.iter().map(|(fid, _, _)| *fid).collect();
+        if !ids.is_empty() {
+            self.db.mark_files_missing(&ids)?;
+        }
+        for (fid, path, size) in missing {
+            self.missing.entry(size).or_default().push((fid, path));
+        }
+        let marked = ids.len() as u64;
+        self.counters.files_missing.fetch_add(marked, Ordering::Relaxed);
+        tracing::info!(root = %root.display(), marked, "marked deleted files as Missing");
+        Ok(())
     }
 
-    let _ = job_tx.send(HashJob { path: norm, mtime, size });
-    Ok(())
+    /// Count the file and advance the frontier past it. This happens under the
+    /// walk state lock, so a checkpoint sees the frontier, the pending set and
+    /// the counters in step.
+    fn enqueue_and_advance(&mut self, path: PathBuf) -> Result<()> {
+        let need = if self.opts.lazy { HashNeed::Any } else { HashNeed::Compared };
+        let candidate = self.check_candidate(&path, need)?;
+        if self.plan.is_some() {
+            return self.add_to_plan(&path, candidate);
+        }
+        {
+            let mut st = lock_state(self.state);
+            match &candidate {
+                Candidate::Ignored => {}
+                Candidate::Skipped => {
+                    self.counters.files_walked.fetch_add(1, Ordering::Relaxed);
+                }
+                Candidate::Unchanged => {
+                    self.counters.files_walked.fetch_add(1, Ordering::Relaxed);
+                    self.counters.files_unchanged.fetch_add(1, Ordering::Relaxed);
+                }
+                Candidate::Changed(job) => {
+                    self.counters.files_walked.fetch_add(1, Ordering::Relaxed);
+                    st.pending.insert(path_utils::path_to_string(&job.path));
+                }
+                Candidate::Moved(result, _, _) => {
+                    self.counters.files_walked.fetch_add(1, Ordering::Relaxed);
+                    st.pending.insert(result.path.clone());
+                }
+            }
+            st.frontier = Some(path);
+        }
+
+        if let Candidate::Moved(result, from, _) = candidate {
+            let _ = self.res_tx.send(WriterMsg::Moved { result, from });
+            return Ok(());
+        }
+
+        if let Candidate::Changed(job) = candidate {
+            if self.opts.order == ScanOrder::Walk {
+                self.dispatch(job)?;
+            } else {
+                self.ordered.push(job);
+                if self.ordered.len() >= ORDER_BATCH {
+                    self.flush_ordered()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record what the scan would do with `path` (`scan --plan`).
+    fn add_to_plan(&mut self, path: &Path, candidate: Candidate) -> Result<()> {
+        let db = self.db;
+        let policy = self.opts.size_policy;
+        let Some(plan) = self.plan.as_mut() else {
+            return Ok(());
+        };
+        let path_str = path_utils::path_to_string(path);
+
+        match candidate {
+            Candidate::Ignored => {
+                // Unreadable ones were reported as errors
+                if let Ok(md) = std::fs::symlink_metadata(path)
+                    && !md.is_dir()
+                {
+                    let what = if md.is_symlink() { "symlink" } else { "special file" };
+                    plan.add(PlanKind::NotRegular, &path_str, 0, what);
+                }
+            }
+            Candidate::Skipped => {
+                let size = std::fs::metadata(path).map_or(0, |md| md.len());
+                let reason = if size == 0 && !policy.index_empty {
+                    "empty"
+                } else if policy.min_size.is_some_and(|min| size < min) {
+                    "smaller than --min-size"
+                } else {
+                    "larger than --max-size"
+                };
+                plan.add(PlanKind::OutsidePolicy, &path_str, size, reason);
+            }
+            Candidate::Unchanged => {
+                let size = std::fs::metadata(path).map_or(0, |md| md.len());
+                plan.add(PlanKind::Unchanged, &path_str, size, "size, mtime and inode match");
+            }
+            Candidate::Changed(job) => {
+                let norm = path_utils::path_to_string(&job.path);
+                let size = job.stat.size;
+                match db.get_current_by_path(&norm)? {
+                    None if job.moved_from.is_some() => {
+                        plan.add(PlanKind::New, &norm, size, "maybe moved; hash to make sure")
+                    }
+                    None => plan.add(PlanKind::New, &norm, size, "not in the database"),
+                    Some(cur) => {
+                        let reason = scan_plan::change_reason(cur.state, &cur.meta, &job.stat);
+                        plan.add(PlanKind::Changed, &norm, size, reason);
+                    }
+                }
+            }
+            Candidate::Moved(result, _, from) => {
+                let reason = format!("from {from}, same inode");
+                plan.add(PlanKind::Moved, &result.path, result.meta.size, &reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Dispatch the files held back by `--order`, sorted by disk position.
+    /// They are already in the pending set, so holding them is safe.
+    fn flush_ordered(&mut self) -> Result<()> {
+        let order = self.opts.order;
+        let mut jobs: Vec<_> = self
+            .ordered
+            .drain(..)
+            .map(|job| {
+                let physical = match order {
+                    ScanOrder::Physical => device::first_extent(&job.path),
+                    _ => None,
+                };
+                // Files without a known position go after the others, by inode
+                let key = match physical {
+                    Some(pos) => (job.stat.dev, false, pos),
+                    None => (job.stat.dev, true, job.stat.ino),
+                };
+                (key, job)
+            })
+            .collect();
+        jobs.sort_by_key(|(key, _)| *key);
+
+        for (_, job) in jobs {
+            self.dispatch(job)?;
+        }
+        Ok(())
+    }
+
+    /// Send the file to be hashed, or with `--lazy`, record it without a
+    /// hash if no other file has its size. Files of a size that more than
+    /// one file has are compared by prefix and tail hashes first, including
+    /// those recorded without a hash before.
+    fn dispatch(&mut self, mut job: HashJob) -> Result<()> {
+        let size = job.stat.size;
+        match self.sizes.get(&size) {
+            Some(SizeSeen::Tiered | SizeSeen::Full) => {}
+            Some(SizeSeen::Single(first)) => {
+                let first = first.clone();
+                self.sizes.insert(size, self.shared_size(size));
+                self.hash_now(&first);
+            }
+            None => {
+                let path_str = path_utils::path_to_string(&job.path);
+                let mut others = self.db.lookup_live_by_size(size)?;
+                others.retain(|e| e.path != path_str);
+
+                if others.is_empty() && self.opts.lazy && job.archive.is_none() {
+                    self.sizes.insert(size, SizeSeen::Single(job.path.clone()));
+                    let meta = FileMeta::hash_pending(&job.stat);
+                    let _ = self.res_tx.send(WriterMsg::Recorded(HashResult { path: path_str, meta }));
+                    return Ok(());
+                }
+                if others.is_empty() {
+                    // It may stay the only file of its size, so it is hashed in
+                    // full, and files of its size found later are compared with it
+                    self.sizes.insert(size, self.shared_size(size));
+                    job.tiering = Tiering::Record;
+                    self.send_job(job);
+                    return Ok(());
+                }
+
+                let mut seen = self.shared_size(size);
+                // Hashed before prefix and tail were kept, so there is nothing to compare with
+                if others.iter().any(|e| e.meta.hash256.is_some() && e.meta.head_tail().is_none()) {
+                    seen = SizeSeen::Full;
+                }
+                let tiered = matches!(seen, SizeSeen::Tiered);
+                self.sizes.insert(size, seen);
+
+                // Fully hashed ones first, so a partly hashed file that matches
+                // one of them is compared again
+                others.sort_by_key(|e| e.meta.hash256.is_none());
+                for e in others {
+                    let path = path_utils::path_from_string(&e.path);
+                    let known = tiered && self.tiers.seed(size, &e.meta, path.clone());
+                    if e.meta.hash256.is_none() && !known {
+                        self.hash_now(&path);
+                    }
+                }
+            }
+        }
+
+        self.send_job(job);
+        Ok(())
+    }
+
+    /// How files of a size are hashed once there is more than one of them.
+    fn shared_size(&self, size: u64) -> SizeSeen {
+        if hashing::has_head_tail(size) {
+            SizeSeen::Tiered
+        } else {
+            SizeSeen::Full
+        }
+    }
+
+    /// Hash a file that was recorded without a hash.
+    fn hash_now(&mut self, path: &Path) {
+        // If it is gone or no longer a file, the walk (or a later scan) deals with it
+        let Ok(md) = std::fs::metadata(path) else {
+            return;
+        };
+        if !md.is_file() {
+            return;
+        }
+
+        lock_state(self.state).pending.insert(path_utils::path_to_string(path));
+        self.send_job(HashJob {
+            path: path.to_path_buf(),
+            stat: FileStat::from_metadata(&md),
+            tiering: Tiering::Off,
+            archive: self.archive_kind(path),
+            moved_from: None,
+        });
+    }
+
+    /// The kind of archive whose members should be hashed along with `path`.
+    fn archive_kind(&self, path: &Path) -> Option<ArchiveKind> {
+        self.opts.archives.then(|| archive::kind_of(path)).flatten()
+    }
+
+    fn send_job(&mut self, mut job: HashJob) {
+        job.tiering = if !matches!(self.sizes.get(&job.stat.size), Some(SizeSeen::Tiered)) {
+            Tiering::Off
+        } else if job.tiering == Tiering::Record || job.archive.is_some() || job.moved_from.is_some() {
+            // Hashed in full anyway: archives are read for their members, and
+            // a maybe-moved file to compare it with where it came from
+            Tiering::Record
+        } else {
+            Tiering::Compare
+        };
+        self.counters.files_queued.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_queued.fetch_add(job.stat.size, Ordering::Relaxed);
+        self.pools.send(job);
+    }
+
+    /// Filesystem problems are reported to the writer as scan errors;
+    /// only database errors are returned.
+    ///
+    /// `need` is how much of its hashes a version must have to count as
+    /// unchanged.
+    fn check_candidate(&mut self, path: &Path, need: HashNeed) -> Result<Candidate> {
+        let norm = path_utils::normalize_path(path)?;
+        let norm_str = path_utils::path_to_string(&norm);
+
+        // record seen BEFORE any early return
+        self.seen.insert(norm_str.clone());
+
+        // Cheap checks first
+        let md = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(e) => {
+                let _ = self.res_tx.send(WriterMsg::Failed(ScanError::from_io(&norm, ErrorPhase::Stat, &e)));
+                return Ok(Candidate::Ignored);
+            }
+        };
+        if !md.is_file() {
+            return Ok(Candidate::Ignored);
+        }
+        if !self.opts.size_policy.allows(md.len()) {
+            return Ok(Candidate::Skipped);
+        }
+
+        let stat = FileStat::from_metadata(&md);
+        let archive = self.archive_kind(&norm);
+
+        // Preflight skip: if the current version is Live and matches stat() => assume unchanged
+        if let Some(cur) = self.db.get_current_by_path(&norm_str)?
+            && cur.state == FileState::Live
+            && cur.meta.matches_stat(&stat)
+            && match need {
+                HashNeed::Any => true,
+                HashNeed::Compared => cur.meta.hash256.is_some() || cur.meta.sha1prefix_4k.is_some(),
+                HashNeed::Full => cur.meta.hash256.is_some(),
+            }
+        {
+            // An archive indexed before `--archives` was used still needs its members read.
+            // (An archive without any files in it is read again on each scan.)
+            if archive.is_none() || !self.db.live_members(&norm_str)?.is_empty() {
+                return Ok(Candidate::Unchanged);
+            }
+        } else if archive.is_none() && archive::kind_of(&norm).is_some() && self.plan.is_none() {
+            // A changed archive is not read without `--archives`, so what an
+            // earlier scan found in it can no longer be relied on
+            let stale: Vec<u64> = self
+                .db
+                .live_members(&norm_str)?
+                .into_iter()
+                .map(|(_, fid)| fid)
+                .collect();
+            if !stale.is_empty() {
+                self.db.mark_files_missing(&stale)?;
+                self.counters.files_missing.fetch_add(stale.len() as u64, Ordering::Relaxed);
+            }
+        }
+
+        // Moving an archive moves the files in it, which are indexed anew
+        let mut moved_from = None;
+        if archive.is_none() {
+            match self.find_moved_from(&norm_str, &stat)? {
+                Some(MovedFrom::Inode(old)) => {
+                    tracing::debug!(from = %old.path, to = %norm_str, "moved");
+                    self.seen.insert(old.path.clone());
+                    // Counted as a move instead
+                    if old.state == FileState::Missing {
+                        self.counters.files_missing.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    let meta = FileMeta {
+                        hash256: old.meta.hash256,
+                        sha1prefix_4k: old.meta.sha1prefix_4k,
+                        sha1tail: old.meta.sha1tail,
+                        ..FileMeta::new(&stat, [0u8; 32], None, None)
+                    };
+                    let result = HashResult { path: norm_str, meta };
+                    return Ok(Candidate::Moved(result, old.file_id, old.path));
+                }
+                // Left for the walk to mark Missing if the hashes differ
+                Some(MovedFrom::SizeAndMtime(old)) => {
+                    moved_from = old.meta.hash256.map(|h| (old.file_id, h));
+                }
+                None => {}
+            }
+        }
+
+        Ok(Candidate::Changed(HashJob {
+            path: norm,
+            stat,
+            tiering: Tiering::Off,
+            archive,
+            moved_from,
+        }))
+    }
+
+    /// A Live file that is no longer at its path, and that the file at
+    /// `path` may be after a move or rename: same size, mtime and inode.
+    /// Versions without an inode can only be matched by size and mtime, and
+    /// only if exactly one of them fits.
+    ///
+    /// Files this walk already marked Missing under an earlier root count
+    /// as Live here.
+    fn find_moved_from(&self, path: &str, stat: &FileStat) -> Result<Option<MovedFrom>> {
+        let mut candidates = self.db.lookup_live_by_size(stat.size)?;
+        for (fid, old_path) in self.missing.get(&stat.size).into_iter().flatten() {
+            if let Some(cur) = self.db.get_current_by_path(old_path)?
+                && cur.file_id == *fid
+            {
+                candidates.push(ShaEntry {
+                    file_id: cur.file_id,
+                    state: cur.state,
+                    path: old_path.clone(),
+                    meta: cur.meta,
+                });
+            }
+        }
+
+        let mut by_mtime: Vec<ShaEntry> = candidates
+            .into_iter()
+            .filter(|e| {
+                e.path != path
+                    && e.meta.mtime_secs == stat.mtime_secs
+                    && e.meta.inode.is_none_or(|i| {
+                        e.meta.mtime_nsec == stat.mtime_nsec && (i.dev, i.ino) == (stat.dev, stat.ino)
+                    })
+                    && !e.meta.member
+                    && !e.meta.catalog
+                    && !self.seen.contains(&e.path)
+            })
+            .collect();
+
+        // Still there: a hardlink or a copy, not a move
+        by_mtime.retain(|e| {
+            std::fs::symlink_metadata(path_utils::path_from_string(&e.path)).is_err_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+        });
+
+        if let Some(i) = by_mtime.iter().position(|e| e.meta.inode.is_some()) {
+            return Ok(Some(MovedFrom::Inode(by_mtime.swap_remove(i))));
+        }
+        Ok(match by_mtime.len() {
+            1 => by_mtime.pop().map(MovedFrom::SizeAndMtime),
+            _ => None,
+        })
+    }
+}
+
+/// How a file found at a new path was matched to where it was before.
+enum MovedFrom {
+    /// Same inode, so the content is known
+    Inode(ShaEntry),
+    /// Same size and mtime; the content must be read to be sure
+    SizeAndMtime(ShaEntry),
+}
+
+/// How much of its hashes a version needs to count as unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashNeed {
+    /// None; recorded is enough (`--lazy`)
+    Any,
+    /// Compared with the files of its size by prefix (and tail) hash, or
+    /// fully hashed
+    Compared,
+    /// A full hash
+    Full,
+}
+
+/// What the walker decided about one entry.
+enum Candidate {
+    /// Not a regular file, or it could not be inspected
+    Ignored,
+    /// Empty, or otherwise outside the size policy
+    Skipped,
+    Unchanged,
+    Changed(HashJob),
+    /// A known file at a new path, with the file_id and path it had before
+    Moved(HashResult, u64, String),
 }
 
+/// True if a walk that stopped at `frontier` already handled `path`.
+/// Directories on the way to the frontier are only partly done.
+fn already_walked(path: &Path, frontier: &Path) -> bool {
+    path == frontier || (path < frontier && !frontier.starts_with(path))
+}
+
+
+/// Whether the walk takes this entry and, for a directory, descends into it.
+/// `first_visit` records a directory's (dev, ino), and returns false if it
+/// was already seen, so loops and repeated bind mounts are walked once.
+fn filter_dir_entry(
+    path: &Path,
+    is_dir: bool,
+    root_dev: Option<u64>,
+    excludes: &ExcludeRules,
+    skipped_dirs: &mut Vec<PathBuf>,
+    first_visit: impl FnOnce((u64, u64)) -> bool,
+) -> bool {
+    if excludes.is_excluded(path, is_dir) {
+        tracing::debug!(path = %path.display(), "excluded");
+        return false;
+    }
+
+    if is_dir {
+        if exclude::has_skip_marker(path) {
+            tracing::debug!(path = %path.display(), "skipping directory with marker file");
+            skipped_dirs.push(path.to_path_buf());
+            return false;
+        }
 
-fn filter_dir_entry(e: &walkdir::DirEntry, visited_dirs: &mut HashSet<(u64, u64)>) -> bool {
-    if e.file_type().is_dir() {
         #[cfg(unix)]
         {
             use std::os::unix::fs::MetadataExt;
-            if let Ok(md) = e.metadata() {
-                let key = (md.dev(), md.ino());
-                if visited_dirs.contains(&key) {
+            if let Ok(md) = std::fs::metadata(path) {
+                if root_dev.is_some_and(|dev| dev != md.dev()) {
+                    tracing::debug!(path = %path.display(), "skipping directory on another filesystem");
+                    skipped_dirs.push(path.to_path_buf());
+                    return false;
+                }
+
+                if !first_visit((md.dev(), md.ino())) {
                     return false;
                 }
-                visited_dirs.insert(key);
             }
         }
     }
     true
 }
 
+/// The `st_dev` of `path`, used to stay on one filesystem.
+#[cfg(unix)]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|md| md.dev())
+}
 
+#[cfg(not(unix))]
+fn device_of(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, CurrentByPath};
+    use crate::util::TestDir;
+
+    struct Tree {
+        dir: TestDir,
+    }
+
+    impl Tree {
+        fn new() -> Self {
+            let dir = TestDir::new();
+            std::fs::create_dir(dir.path().join("t")).unwrap();
+            Self { dir }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join("t").join(name)
+        }
+
+        /// `len` bytes of `fill`, with the first and last byte given.
+        fn write(&self, name: &str, len: usize, fill: u8, first: u8, last: u8) {
+            let mut data = vec![fill; len];
+            data[0] = first;
+            data[len - 1] = last;
+            std::fs::write(self.path(name), data).unwrap();
+        }
 
+        fn scan(&self, lazy: bool) {
+            let opts = ScanOptions {
+                threads: 1,
+                hdd_threads: 1,
+                walk_threads: 1,
+                max_read_rate: None,
+                idle: false,
+                follow_symlinks: false,
+                recursive: true,
+                detect_deletes: true,
+                one_file_system: false,
+                watch: false,
+                lazy,
+                archives: false,
+                size_policy: SizePolicy::default(),
+                order: ScanOrder::Walk,
+                excludes: ExcludeRules::new(exclude::ExcludeSpec::default()).unwrap(),
+            };
+            let db = db::open(&self.dir.path().join("db")).unwrap();
+            run_scan(db, vec![self.dir.path().join("t")], opts).unwrap();
+        }
+
+        fn db(&self) -> DbHandle {
+            db::open(&self.dir.path().join("db")).unwrap()
+        }
+
+        fn current(&self, db: &DbHandle, name: &str) -> CurrentByPath {
+            let path = path_utils::path_to_string(&self.path(name));
+            db.get_current_by_path(&path).unwrap().unwrap()
+        }
+
+        /// Which of prefix, tail and full hash the version at `name` has.
+        fn hashes(&self, db: &DbHandle, name: &str) -> (bool, bool, bool) {
+            let m = self.current(db, name).meta;
+            (m.sha1prefix_4k.is_some(), m.sha1tail.is_some(), m.hash256.is_some())
+        }
+    }
+
+    /// Same-size files that differ at the start, at the end, and not at all.
+    fn tiered_tree() -> Tree {
+        let t = Tree::new();
+        t.write("alone", 50_000, 1, 0, 0);
+        t.write("head1", 100_000, 2, 1, 0);
+        t.write("head2", 100_000, 2, 2, 0);
+        t.write("tail1", 120_000, 3, 0, 1);
+        t.write("tail2", 120_000, 3, 0, 2);
+        t.write("same1", 140_000, 4, 0, 0);
+        t.write("same2", 140_000, 4, 0, 0);
+        t
+    }
+
+    #[test]
+    fn lazy_scan_hashes_only_as_far_as_needed() {
+        let t = tiered_tree();
+        t.scan(true);
+
+        let db = t.db();
+        assert_eq!(t.hashes(&db, "alone"), (false, false, false));
+        for name in ["head1", "head2"] {
+            assert_eq!(t.hashes(&db, name), (true, false, false), "{name}");
+        }
+        for name in ["tail1", "tail2"] {
+            assert_eq!(t.hashes(&db, name), (true, true, false), "{name}");
+        }
+        assert_eq!(t.hashes(&db, "same1"), (true, true, true));
+        assert_eq!(t.current(&db, "same1").meta.hash256, t.current(&db, "same2").meta.hash256);
+        drop(db);
+
+        // A second file of its size makes the first one compared
+        t.write("alone2", 50_000, 1, 0, 0);
+        t.scan(true);
+        let db = t.db();
+        assert_eq!(t.hashes(&db, "alone"), (true, true, true));
+        assert_eq!(t.current(&db, "alone").meta.hash256, t.current(&db, "alone2").meta.hash256);
+    }
+
+    #[test]
+    fn full_scan_hashes_the_first_of_each_size() {
+        let t = tiered_tree();
+        t.scan(false);
+
+        let db = t.db();
+        assert_eq!(t.hashes(&db, "alone"), (true, true, true));
+        for first in ["head1", "tail1", "same1", "same2"] {
+            assert_eq!(t.hashes(&db, first), (true, true, true), "{first}");
+        }
+        assert_eq!(t.hashes(&db, "head2"), (true, false, false));
+        assert_eq!(t.hashes(&db, "tail2"), (true, true, false));
+    }
+
+    #[test]
+    fn renamed_files_are_moves() {
+        let t = tiered_tree();
+        t.scan(false);
+        let db = t.db();
+        let before = t.current(&db, "same1");
+        let copy = t.current(&db, "same2");
+        drop(db);
+
+        std::fs::create_dir(t.path("sub")).unwrap();
+        std::fs::rename(t.path("same1"), t.path("sub/same1")).unwrap();
+        // Same content, but a new file: not a move
+        std::fs::remove_file(t.path("same2")).unwrap();
+        t.write("same3", 140_000, 4, 0, 0);
+        t.scan(false);
+
+        let db = t.db();
+        let after = t.current(&db, "sub/same1");
+        assert_ne!(after.file_id, before.file_id);
+        assert_eq!(after.meta.hash256, before.meta.hash256);
+        let (from, from_path) = db.moved_from(after.file_id).unwrap().unwrap();
+        assert_eq!((from, from_path), (before.file_id, path_utils::path_to_string(&t.path("same1"))));
+        assert_eq!(db.moved_to(before.file_id).unwrap().map(|(fid, _)| fid), Some(after.file_id));
+        assert_eq!(t.current(&db, "same1").state, FileState::Missing);
+
+        assert_eq!(t.current(&db, "same2").state, FileState::Missing);
+        assert!(db.moved_to(copy.file_id).unwrap().is_none());
+        assert!(db.moved_from(t.current(&db, "same3").file_id).unwrap().is_none());
+    }
+}
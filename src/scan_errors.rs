@@ -0,0 +1,256 @@
+use crate::codec::{str_pack_into, str_unpack, systemtime_to_unix_secs};
+use crate::db::DbHandle;
+use crate::path_filter::PathFilter;
+use crate::path_utils;
+use crate::util::format_timestamp;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Where in the scan pipeline an error happened.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPhase {
+    /// Reading a directory while walking
+    Walk = 0,
+    /// stat() of a file before deciding whether to hash it
+    Stat = 1,
+    /// Opening/reading a file while hashing
+    Hash = 2,
+    /// Reading the members of an archive (`scan --archives`)
+    Archive = 3,
+}
+
+impl ErrorPhase {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(ErrorPhase::Walk),
+            1 => Some(ErrorPhase::Stat),
+            2 => Some(ErrorPhase::Hash),
+            3 => Some(ErrorPhase::Archive),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorPhase::Walk => "walk",
+            ErrorPhase::Stat => "stat",
+            ErrorPhase::Hash => "hash",
+            ErrorPhase::Archive => "archive",
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorKind {
+    /// Permission denied
+    Permission = 0,
+    /// The file or directory disappeared during the scan
+    Vanished = 1,
+    /// Any other I/O error
+    Io = 2,
+    /// Not an I/O error (e.g. a symlink loop, or a file that changed type)
+    Other = 3,
+}
+
+impl ErrorKind {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(ErrorKind::Permission),
+            1 => Some(ErrorKind::Vanished),
+            2 => Some(ErrorKind::Io),
+            3 => Some(ErrorKind::Other),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Permission => "permission",
+            ErrorKind::Vanished => "vanished",
+            ErrorKind::Io => "io",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+            io::ErrorKind::NotFound => ErrorKind::Vanished,
+            _ => ErrorKind::Io,
+        }
+    }
+}
+
+/// A file or directory the scan could not process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanError {
+    pub time: u64,
+    pub phase: ErrorPhase,
+    pub kind: ErrorKind,
+    pub errno: Option<i32>,
+    pub path: String,
+    pub message: String,
+}
+
+impl ScanError {
+    pub fn from_io(path: &Path, phase: ErrorPhase, e: &io::Error) -> Self {
+        Self {
+            time: systemtime_to_unix_secs(SystemTime::now()),
+            phase,
+            kind: ErrorKind::from_io(e),
+            errno: e.raw_os_error(),
+            path: path_utils::path_to_string(path),
+            message: e.to_string(),
+        }
+    }
+
+    /// Classify by the first `io::Error` in the chain, if any.
+    pub fn from_anyhow(path: &Path, phase: ErrorPhase, e: &anyhow::Error) -> Self {
+        match e.chain().find_map(|c| c.downcast_ref::<io::Error>()) {
+            Some(io_err) => Self {
+                message: format!("{e:#}"),
+                ..Self::from_io(path, phase, io_err)
+            },
+            None => Self::other(path, phase, format!("{e:#}")),
+        }
+    }
+
+    pub fn from_walkdir(phase: ErrorPhase, e: &walkdir::Error) -> Self {
+        let path = e.path().unwrap_or(Path::new(""));
+        match e.io_error() {
+            Some(io_err) => Self::from_io(path, phase, io_err),
+            None => Self::other(path, phase, e.to_string()),
+        }
+    }
+
+    pub fn other(path: &Path, phase: ErrorPhase, message: String) -> Self {
+        Self {
+            time: systemtime_to_unix_secs(SystemTime::now()),
+            phase,
+            kind: ErrorKind::Other,
+            errno: None,
+            path: path_utils::path_to_string(path),
+            message,
+        }
+    }
+
+    /// Encode to a stable on-disk format.
+    ///
+    /// Format v1:
+    /// [0]      u8  version = 1
+    /// [1]      u8  phase
+    /// [2]      u8  kind
+    /// [3]      u8  flags (bit0 = has_errno)
+    /// [4..8]   i32 errno LE (0 if absent)
+    /// [8..16]  u64 time (unix secs) LE
+    /// [16..20] u32 path length LE, followed by the path bytes
+    /// then     u32 message length LE, followed by the message bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(24 + self.path.len() + self.message.len());
+        out.push(1u8);
+        out.push(self.phase as u8);
+        out.push(self.kind as u8);
+        out.push(u8::from(self.errno.is_some()));
+        out.extend_from_slice(&self.errno.unwrap_or(0).to_le_bytes());
+        out.extend_from_slice(&self.time.to_le_bytes());
+        str_pack_into(&mut out, &self.path);
+        str_pack_into(&mut out, &self.message);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 16 {
+            return Err(anyhow!("scan_error too short: {} bytes", bytes.len()));
+        }
+
+        let version = bytes[0];
+        if version != 1 {
+            return Err(anyhow!("unknown scan_error version: {}", version));
+        }
+
+        let phase = ErrorPhase::from_u8(bytes[1])
+            .ok_or_else(|| anyhow!("invalid scan_error phase: {}", bytes[1]))?;
+        let kind = ErrorKind::from_u8(bytes[2])
+            .ok_or_else(|| anyhow!("invalid scan_error kind: {}", bytes[2]))?;
+        let has_errno = (bytes[3] & 1) != 0;
+
+        let mut errno_arr = [0u8; 4];
+        errno_arr.copy_from_slice(&bytes[4..8]);
+        let errno = has_errno.then(|| i32::from_le_bytes(errno_arr));
+
+        let mut time_arr = [0u8; 8];
+        time_arr.copy_from_slice(&bytes[8..16]);
+        let time = u64::from_le_bytes(time_arr);
+
+        let mut pos = 16;
+        let path = str_unpack(bytes, &mut pos)?;
+        let message = str_unpack(bytes, &mut pos)?;
+
+        Ok(Self {
+            time,
+            phase,
+            kind,
+            errno,
+            path,
+            message,
+        })
+    }
+}
+
+/// Per-kind error counts, used for the end-of-scan summary.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ErrorCounts {
+    pub permission: u64,
+    pub vanished: u64,
+    pub io: u64,
+    pub other: u64,
+}
+
+impl ErrorCounts {
+    pub fn add(&mut self, kind: ErrorKind) {
+        match kind {
+            ErrorKind::Permission => self.permission += 1,
+            ErrorKind::Vanished => self.vanished += 1,
+            ErrorKind::Io => self.io += 1,
+            ErrorKind::Other => self.other += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.permission + self.vanished + self.io + self.other
+    }
+}
+
+pub fn run_errors(db: &DbHandle, filter: &PathFilter, kinds: &[ErrorKind]) -> Result<()> {
+    let mut errors = db.list_scan_errors()?;
+
+    errors.retain(|e| filter.matches(&e.path));
+    if !kinds.is_empty() {
+        errors.retain(|e| kinds.contains(&e.kind));
+    }
+
+    errors.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.time.cmp(&b.time)));
+
+    for e in &errors {
+        println!(
+            "{} {:<10} {:<5} {}: {}",
+            format_timestamp(e.time as i64),
+            e.kind.as_str(),
+            e.phase.as_str(),
+            e.path,
+            e.message
+        );
+    }
+
+    if !errors.is_empty() {
+        println!();
+    }
+    println!("{} error(s)", errors.len());
+
+    Ok(())
+}
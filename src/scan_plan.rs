@@ -0,0 +1,122 @@
+//! `scan --plan`: what a scan would do, found by walking the roots and
+//! running the same checks as a scan, without hashing or writing anything.
+
+use crate::file_meta::{FileMeta, FileStat, FileState};
+use crate::util::format_size;
+
+/// What a scan would do with one path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanKind {
+    New,
+    Changed,
+    Moved,
+    Unchanged,
+    OutsidePolicy,
+    NotRegular,
+    Unreadable,
+    Missing,
+}
+
+impl PlanKind {
+    const ALL: [PlanKind; 8] = [
+        PlanKind::New,
+        PlanKind::Changed,
+        PlanKind::Moved,
+        PlanKind::Unchanged,
+        PlanKind::OutsidePolicy,
+        PlanKind::NotRegular,
+        PlanKind::Unreadable,
+        PlanKind::Missing,
+    ];
+
+    /// Token used by `--explain`
+    fn token(self) -> &'static str {
+        match self {
+            PlanKind::New => "NEW",
+            PlanKind::Changed => "CHANGED",
+            PlanKind::Moved => "MOVED",
+            PlanKind::Unchanged => "UNCHANGED",
+            PlanKind::OutsidePolicy => "SKIP",
+            PlanKind::NotRegular => "IGNORE",
+            PlanKind::Unreadable => "ERROR",
+            PlanKind::Missing => "MISSING",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            PlanKind::New => "New files (hash):",
+            PlanKind::Changed => "Changed files (hash):",
+            PlanKind::Moved => "Moved files (no hash):",
+            PlanKind::Unchanged => "Unchanged files:",
+            PlanKind::OutsidePolicy => "Outside size limits:",
+            PlanKind::NotRegular => "Not regular files:",
+            PlanKind::Unreadable => "Unreadable:",
+            PlanKind::Missing => "Would be marked missing:",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScanPlan {
+    explain: bool,
+    /// (files, bytes) by `PlanKind::ALL` index
+    totals: [(u64, u64); PlanKind::ALL.len()],
+}
+
+impl ScanPlan {
+    pub fn new(explain: bool) -> Self {
+        Self {
+            explain,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, kind: PlanKind, path: &str, size: u64, reason: &str) {
+        let t = &mut self.totals[kind as usize];
+        t.0 += 1;
+        t.1 += size;
+        if self.explain {
+            println!("{} {path} ({reason})", kind.token());
+        }
+    }
+
+    pub fn print(&self) {
+        if self.explain {
+            println!();
+        }
+        for kind in PlanKind::ALL {
+            let (files, bytes) = self.totals[kind as usize];
+            match kind {
+                PlanKind::NotRegular | PlanKind::Unreadable => println!("{:<26}{files:>10}", kind.label()),
+                _ => println!("{:<26}{files:>10}  {:>12}", kind.label(), format_size(bytes)),
+            }
+        }
+
+        let (new, new_bytes) = self.totals[PlanKind::New as usize];
+        let (changed, changed_bytes) = self.totals[PlanKind::Changed as usize];
+        println!();
+        println!(
+            "Would hash {} files, {}.",
+            new + changed,
+            format_size(new_bytes + changed_bytes)
+        );
+    }
+}
+
+/// Why a file with a version in the DB would be hashed again.
+pub fn change_reason(state: FileState, cur: &FileMeta, stat: &FileStat) -> &'static str {
+    if state != FileState::Live {
+        "was gone"
+    } else if cur.size != stat.size {
+        "size changed"
+    } else if cur.mtime_secs != stat.mtime_secs || (cur.inode.is_some() && cur.mtime_nsec != stat.mtime_nsec) {
+        "mtime changed"
+    } else if !cur.matches_stat(stat) {
+        "inode or ctime changed"
+    } else if cur.hash256.is_none() {
+        "hash pending"
+    } else {
+        "archive members not indexed"
+    }
+}
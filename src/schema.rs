@@ -2,14 +2,26 @@ use redb::TableDefinition;
 use crate::types::Hash256;
 
 
-// path identity (stable)
-pub const PATH_TO_ID: TableDefinition<&str, u64> = TableDefinition::new("path_to_id");
-pub const ID_TO_PATH: TableDefinition<u64, &str> = TableDefinition::new("id_to_path");
+// path identity (stable), keyed by the raw bytes of the path
+pub const PATH_TO_ID: TableDefinition<&[u8], u64> = TableDefinition::new("path_bytes_to_id");
+pub const ID_TO_PATH: TableDefinition<u64, &[u8]> = TableDefinition::new("id_to_path_bytes");
+
+// path identity as UTF-8 strings, from before paths were stored as bytes;
+// only read to migrate old databases
+pub const LEGACY_PATH_TO_ID: TableDefinition<&str, u64> = TableDefinition::new("path_to_id");
+pub const LEGACY_ID_TO_PATH: TableDefinition<u64, &str> = TableDefinition::new("id_to_path");
 
 // counters
 pub const KV_U64: TableDefinition<&str, u64> = TableDefinition::new("kv_u64");
 pub const KEY_NEXT_PATH_ID: &str = "next_path_id";
 pub const KEY_NEXT_FILE_ID: &str = "next_file_id";
+pub const KEY_NEXT_ERROR_ID: &str = "next_error_id";
+pub const KEY_NEXT_SESSION_ID: &str = "next_session_id";
+/// Which one-time migrations the database has had (0 if none):
+/// 1 = size index built, 2 = archive members flagged in their file_meta,
+/// 3 = catalog entries without a size flagged and out of the size index
+pub const KEY_SCHEMA_VERSION: &str = "schema_version";
+pub const SCHEMA_VERSION: u64 = 3;
 
 // versioning
 // path_id -> current file_id
@@ -27,3 +39,25 @@ pub const FILE_STATE: TableDefinition<u64, u8> = TableDefinition::new("file_stat
 // blake3 hex -> packed list of file_id (u64 LE)
 pub const HASH256_TO_FILES: TableDefinition<Hash256, &[u8]> = TableDefinition::new("hash256_to_files");
 
+// file size -> packed list of file_id (u64 LE), including versions whose hash is pending
+pub const SIZE_TO_FILES: TableDefinition<u64, &[u8]> = TableDefinition::new("size_to_files");
+
+// scan error id -> ScanError blob (see scan_errors.rs)
+pub const SCAN_ERRORS: TableDefinition<u64, &[u8]> = TableDefinition::new("scan_errors");
+
+// session id -> ScanSession blob (see session.rs)
+pub const SCAN_SESSIONS: TableDefinition<u64, &[u8]> = TableDefinition::new("scan_sessions");
+
+// file_id -> id of the scan session that created this version
+pub const FILE_SESSION: TableDefinition<u64, u64> = TableDefinition::new("file_session");
+
+// file_id of a version found at a new path -> file_id of the version it was moved from, and back
+pub const FILE_MOVED_FROM: TableDefinition<u64, u64> = TableDefinition::new("file_moved_from");
+pub const FILE_MOVED_TO: TableDefinition<u64, u64> = TableDefinition::new("file_moved_to");
+
+// file_id -> unix time the content was last read back and found to match (`verify`)
+pub const FILE_VERIFIED: TableDefinition<u64, u64> = TableDefinition::new("file_verified");
+
+// KEY_SCAN_CHECKPOINT -> ScanCheckpoint blob (see checkpoint.rs), present while a scan is unfinished
+pub const SCAN_CHECKPOINT: TableDefinition<&str, &[u8]> = TableDefinition::new("scan_checkpoint");
+pub const KEY_SCAN_CHECKPOINT: &str = "current";
@@ -0,0 +1,532 @@
+use crate::codec::{str_pack_into, str_unpack};
+use crate::db::DbHandle;
+use crate::util::{format_size, format_timestamp, size_in_range};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const FLAG_FOLLOW_SYMLINKS: u8 = 1;
+const FLAG_RECURSIVE: u8 = 1 << 1;
+const FLAG_DETECT_DELETES: u8 = 1 << 2;
+const FLAG_ONE_FILE_SYSTEM: u8 = 1 << 3;
+const FLAG_WATCH: u8 = 1 << 4;
+const FLAG_INDEX_EMPTY: u8 = 1 << 5;
+const FLAG_HAS_MIN_SIZE: u8 = 1 << 6;
+const FLAG_HAS_MAX_SIZE: u8 = 1 << 7;
+// Second flags byte
+const FLAG2_LAZY: u8 = 1;
+const FLAG2_ORDER_INODE: u8 = 1 << 1;
+const FLAG2_ORDER_PHYSICAL: u8 = 1 << 2;
+const FLAG2_ARCHIVES: u8 = 1 << 3;
+const FLAG2_FILE_LIST: u8 = 1 << 4;
+
+/// Which files a scan indexes, by size. Files outside the policy are not
+/// hashed, and not taken as deleted when they are not seen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SizePolicy {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Index zero-length files, regardless of `min_size`
+    pub index_empty: bool,
+}
+
+impl SizePolicy {
+    pub fn allows(&self, size: u64) -> bool {
+        if size == 0 {
+            return self.index_empty;
+        }
+        size_in_range(size, self.min_size, self.max_size)
+    }
+}
+
+/// In which order changed files are handed to the hash workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ScanOrder {
+    /// As found by the walk
+    #[default]
+    Walk,
+    /// By inode number, which roughly follows the disk layout on most file systems
+    Inode,
+    /// By the position of the file's first block on disk (falls back to inode order)
+    Physical,
+}
+
+impl ScanOrder {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScanOrder::Walk => "walk",
+            ScanOrder::Inode => "inode",
+            ScanOrder::Physical => "physical",
+        }
+    }
+}
+
+/// Totals for one scan session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SessionCounts {
+    /// Regular files found by the walker
+    pub files_walked: u64,
+    /// Files skipped because (size, mtime) matched the DB
+    pub files_unchanged: u64,
+    pub files_hashed: u64,
+    pub bytes_hashed: u64,
+    pub files_missing: u64,
+    pub errors: u64,
+    /// New paths found to be a known file moved or renamed
+    pub files_moved: u64,
+}
+
+/// Live counters, shared by the walker, the hash workers and the writer.
+#[derive(Debug, Default)]
+pub struct ScanCounters {
+    pub files_walked: AtomicU64,
+    pub files_unchanged: AtomicU64,
+    pub files_hashed: AtomicU64,
+    pub bytes_hashed: AtomicU64,
+    pub files_missing: AtomicU64,
+    pub errors: AtomicU64,
+    pub files_moved: AtomicU64,
+    /// Files sent for hashing by this run, for progress reporting only
+    pub files_queued: AtomicU64,
+    pub bytes_queued: AtomicU64,
+    /// Files of those that only needed prefix (and tail) hashes
+    pub files_compared: AtomicU64,
+    pub bytes_compared: AtomicU64,
+}
+
+impl ScanCounters {
+    pub fn from_counts(c: SessionCounts) -> Self {
+        Self {
+            files_walked: AtomicU64::new(c.files_walked),
+            files_unchanged: AtomicU64::new(c.files_unchanged),
+            files_hashed: AtomicU64::new(c.files_hashed),
+            bytes_hashed: AtomicU64::new(c.bytes_hashed),
+            files_missing: AtomicU64::new(c.files_missing),
+            errors: AtomicU64::new(c.errors),
+            files_moved: AtomicU64::new(c.files_moved),
+            ..Default::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionCounts {
+        SessionCounts {
+            files_walked: self.files_walked.load(Ordering::Relaxed),
+            files_unchanged: self.files_unchanged.load(Ordering::Relaxed),
+            files_hashed: self.files_hashed.load(Ordering::Relaxed),
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+            files_missing: self.files_missing.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            files_moved: self.files_moved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// One invocation of `scan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanSession {
+    pub id: u64,
+    pub roots: Vec<String>,
+    pub follow_symlinks: bool,
+    pub recursive: bool,
+    pub detect_deletes: bool,
+    pub one_file_system: bool,
+    /// Kept indexing changes after the walk (`scan --watch`)
+    pub watch: bool,
+    /// Hashed files only once another file had the same size (`scan --lazy`)
+    pub lazy: bool,
+    /// Indexed the members of zip and tar archives (`scan --archives`)
+    pub archives: bool,
+    /// Scanned the files in a list instead of walking `roots` (`scan --files-from`)
+    pub file_list: bool,
+    pub size_policy: SizePolicy,
+    pub order: ScanOrder,
+    /// Hash workers per disk
+    pub threads: u32,
+    /// Hash workers per rotational disk
+    pub hdd_threads: u32,
+    pub started: u64,
+    /// None while running, or if the scan was interrupted
+    pub finished: Option<u64>,
+    pub counts: SessionCounts,
+}
+
+impl ScanSession {
+    /// Encode to a stable on-disk format.
+    ///
+    /// Format v1:
+    /// [0]      u8  version = 1
+    /// [1]      u8  flags (bit0 = follow_symlinks, bit1 = recursive, bit2 = detect_deletes,
+    ///          bit3 = one_file_system, bit4 = watch, bit5 = index_empty,
+    ///          bit6 = has_min_size, bit7 = has_max_size)
+    /// [2]      u8  flags2 (bit0 = lazy, bit1 = order inode, bit2 = order physical, bit3 = archives,
+    ///          bit4 = file_list)
+    /// [3..7]   u32 threads LE
+    /// [7..11]  u32 hdd_threads LE
+    /// [11..19] u64 started (unix secs) LE
+    /// [19..27] u64 finished (unix secs) LE, 0 = not finished
+    /// [27..83] u64 x 7 counters LE: walked, unchanged, hashed, bytes_hashed, missing, errors, moved
+    /// [83..87] u32 number of roots LE, followed by length-prefixed root paths
+    /// then     u64 min_size LE (only if has_min_size)
+    /// then     u64 max_size LE (only if has_max_size)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(103 + self.roots.iter().map(|r| r.len() + 4).sum::<usize>());
+        out.push(1u8);
+
+        let mut flags = 0u8;
+        if self.follow_symlinks {
+            flags |= FLAG_FOLLOW_SYMLINKS;
+        }
+        if self.recursive {
+            flags |= FLAG_RECURSIVE;
+        }
+        if self.detect_deletes {
+            flags |= FLAG_DETECT_DELETES;
+        }
+        if self.one_file_system {
+            flags |= FLAG_ONE_FILE_SYSTEM;
+        }
+        if self.watch {
+            flags |= FLAG_WATCH;
+        }
+        if self.size_policy.index_empty {
+            flags |= FLAG_INDEX_EMPTY;
+        }
+        if self.size_policy.min_size.is_some() {
+            flags |= FLAG_HAS_MIN_SIZE;
+        }
+        if self.size_policy.max_size.is_some() {
+            flags |= FLAG_HAS_MAX_SIZE;
+        }
+        out.push(flags);
+
+        let mut flags2 = 0u8;
+        if self.lazy {
+            flags2 |= FLAG2_LAZY;
+        }
+        if self.archives {
+            flags2 |= FLAG2_ARCHIVES;
+        }
+        if self.file_list {
+            flags2 |= FLAG2_FILE_LIST;
+        }
+        match self.order {
+            ScanOrder::Walk => {}
+            ScanOrder::Inode => flags2 |= FLAG2_ORDER_INODE,
+            ScanOrder::Physical => flags2 |= FLAG2_ORDER_PHYSICAL,
+        }
+        out.push(flags2);
+
+        out.extend_from_slice(&self.threads.to_le_bytes());
+        out.extend_from_slice(&self.hdd_threads.to_le_bytes());
+        out.extend_from_slice(&self.started.to_le_bytes());
+        out.extend_from_slice(&self.finished.unwrap_or(0).to_le_bytes());
+
+        let c = &self.counts;
+        for v in [
+            c.files_walked,
+            c.files_unchanged,
+            c.files_hashed,
+            c.bytes_hashed,
+            c.files_missing,
+            c.errors,
+            c.files_moved,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.roots.len() as u32).to_le_bytes());
+        for r in &self.roots {
+            str_pack_into(&mut out, r);
+        }
+
+        if let Some(min) = self.size_policy.min_size {
+            out.extend_from_slice(&min.to_le_bytes());
+        }
+        if let Some(max) = self.size_policy.max_size {
+            out.extend_from_slice(&max.to_le_bytes());
+        }
+
+        out
+    }
+
+    pub fn decode(id: u64, bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 87 {
+            return Err(anyhow!("scan_session too short: {} bytes", bytes.len()));
+        }
+
+        let version = bytes[0];
+        if version != 1 {
+            return Err(anyhow!("unknown scan_session version: {}", version));
+        }
+
+        let flags = bytes[1];
+        let flags2 = bytes[2];
+
+        let u64_at = |pos: usize| {
+            let mut arr = [0u8; 8];
+            arr.copy_from_slice(&bytes[pos..pos + 8]);
+            u64::from_le_bytes(arr)
+        };
+        let u32_at = |pos: usize| {
+            let mut arr = [0u8; 4];
+            arr.copy_from_slice(&bytes[pos..pos + 4]);
+            u32::from_le_bytes(arr)
+        };
+
+        let threads = u32_at(3);
+        let hdd_threads = u32_at(7);
+        let started = u64_at(11);
+        let finished = match u64_at(19) {
+            0 => None,
+            t => Some(t),
+        };
+
+        let counts = SessionCounts {
+            files_walked: u64_at(27),
+            files_unchanged: u64_at(35),
+            files_hashed: u64_at(43),
+            bytes_hashed: u64_at(51),
+            files_missing: u64_at(59),
+            errors: u64_at(67),
+            files_moved: u64_at(75),
+        };
+
+        let n_roots = u32_at(83);
+        let mut pos = 87;
+        let mut roots = Vec::with_capacity(n_roots as usize);
+        for _ in 0..n_roots {
+            roots.push(str_unpack(bytes, &mut pos)?);
+        }
+
+        let mut optional_u64 = |present: bool| -> Result<Option<u64>> {
+            if !present {
+                return Ok(None);
+            }
+            if bytes.len() < pos + 8 {
+                return Err(anyhow!("scan_session size policy truncated"));
+            }
+            let v = u64_at(pos);
+            pos += 8;
+            Ok(Some(v))
+        };
+        let size_policy = SizePolicy {
+            min_size: optional_u64((flags & FLAG_HAS_MIN_SIZE) != 0)?,
+            max_size: optional_u64((flags & FLAG_HAS_MAX_SIZE) != 0)?,
+            index_empty: (flags & FLAG_INDEX_EMPTY) != 0,
+        };
+
+        Ok(Self {
+            id,
+            roots,
+            follow_symlinks: (flags & FLAG_FOLLOW_SYMLINKS) != 0,
+            recursive: (flags & FLAG_RECURSIVE) != 0,
+            detect_deletes: (flags & FLAG_DETECT_DELETES) != 0,
+            one_file_system: (flags & FLAG_ONE_FILE_SYSTEM) != 0,
+            watch: (flags & FLAG_WATCH) != 0,
+            lazy: (flags2 & FLAG2_LAZY) != 0,
+            archives: (flags2 & FLAG2_ARCHIVES) != 0,
+            file_list: (flags2 & FLAG2_FILE_LIST) != 0,
+            order: if (flags2 & FLAG2_ORDER_PHYSICAL) != 0 {
+                ScanOrder::Physical
+            } else if (flags2 & FLAG2_ORDER_INODE) != 0 {
+                ScanOrder::Inode
+            } else {
+                ScanOrder::Walk
+            },
+            size_policy,
+            threads,
+            hdd_threads,
+            started,
+            finished,
+            counts,
+        })
+    }
+
+    fn describe_roots(&self) -> String {
+        if self.file_list {
+            "(files from a list)".to_string()
+        } else {
+            self.roots.join(" ")
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        // The DB is locked while a scan runs, so an unfinished session seen
+        // from another command did not complete.
+        if self.finished.is_some() {
+            "complete"
+        } else {
+            "interrupted"
+        }
+    }
+}
+
+pub fn run_history(db: &DbHandle, id: Option<u64>) -> Result<()> {
+    match id {
+        Some(id) => {
+            let s = db
+                .get_scan_session(id)?
+                .ok_or_else(|| anyhow!("no scan session with id {id}"))?;
+            print_session(&s);
+        }
+        None => {
+            let sessions = db.list_scan_sessions()?;
+            for s in &sessions {
+                println!(
+                    "{:>5}  {}  {:>9}  {:<11}  {:>9} files  {:>9} hashed  {:>5} errors  {}",
+                    s.id,
+                    format_timestamp(s.started as i64),
+                    format_duration(s),
+                    s.status(),
+                    s.counts.files_walked,
+                    s.counts.files_hashed,
+                    s.counts.errors,
+                    s.describe_roots()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_session(s: &ScanSession) {
+    println!("Session:                   {}", s.id);
+    println!("Status:                    {}", s.status());
+    if s.file_list {
+        println!("Roots:                     (files from a list)");
+    } else {
+        println!("Roots:");
+        for r in &s.roots {
+            println!("  {r}");
+        }
+    }
+    println!("Started:                   {}", format_timestamp(s.started as i64));
+    match s.finished {
+        Some(t) => println!("Finished:                  {}", format_timestamp(t as i64)),
+        None => println!("Finished:                  -"),
+    }
+    println!("Duration:                  {}", format_duration(s));
+    println!();
+
+    println!("Options:");
+    println!("  threads:                 {}", s.threads);
+    println!("  threads per HDD:         {}", s.hdd_threads);
+    println!("  follow symlinks:         {}", s.follow_symlinks);
+    println!("  recursive:               {}", s.recursive);
+    println!("  detect deletes:          {}", s.detect_deletes);
+    println!("  one file system:         {}", s.one_file_system);
+    println!("  watch:                   {}", s.watch);
+    println!("  lazy:                    {}", s.lazy);
+    println!("  archives:                {}", s.archives);
+    println!("  order:                   {}", s.order.as_str());
+    let p = &s.size_policy;
+    println!("  min size:                {}", p.min_size.map_or("-".to_string(), format_size));
+    println!("  max size:                {}", p.max_size.map_or("-".to_string(), format_size));
+    println!("  index empty files:       {}", p.index_empty);
+    println!();
+
+    let c = &s.counts;
+    println!("Files walked:              {}", c.files_walked);
+    println!("Files unchanged (skipped): {}", c.files_unchanged);
+    println!("Files hashed:              {}", c.files_hashed);
+    println!("Bytes hashed:              {}", format_size(c.bytes_hashed));
+    println!("Files moved or renamed:    {}", c.files_moved);
+    println!("Files marked missing:      {}", c.files_missing);
+    println!("Errors:                    {}", c.errors);
+}
+
+fn format_duration(s: &ScanSession) -> String {
+    let Some(finished) = s.finished else {
+        return "-".to_string();
+    };
+    let secs = finished.saturating_sub(s.started);
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> ScanSession {
+        ScanSession {
+            id: 7,
+            roots: vec!["/a".to_string(), "/b\\xff c".to_string()],
+            follow_symlinks: true,
+            recursive: true,
+            detect_deletes: false,
+            one_file_system: true,
+            watch: false,
+            lazy: true,
+            archives: true,
+            file_list: false,
+            size_policy: SizePolicy {
+                min_size: Some(1),
+                max_size: Some(1 << 40),
+                index_empty: true,
+            },
+            order: ScanOrder::Physical,
+            threads: 4,
+            hdd_threads: 1,
+            started: 1_700_000_000,
+            finished: Some(1_700_000_100),
+            counts: SessionCounts {
+                files_walked: 1,
+                files_unchanged: 2,
+                files_hashed: 3,
+                bytes_hashed: 4,
+                files_missing: 5,
+                errors: 6,
+                files_moved: 7,
+            },
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trips() {
+        let s = session();
+        assert_eq!(ScanSession::decode(7, &s.encode()).unwrap(), s);
+
+        let s = ScanSession {
+            roots: Vec::new(),
+            follow_symlinks: false,
+            lazy: false,
+            archives: false,
+            file_list: true,
+            size_policy: SizePolicy {
+                min_size: None,
+                max_size: Some(10),
+                index_empty: false,
+            },
+            order: ScanOrder::Inode,
+            finished: None,
+            ..session()
+        };
+        assert_eq!(ScanSession::decode(7, &s.encode()).unwrap(), s);
+    }
+
+    #[test]
+    fn decode_rejects_bad_records() {
+        let bytes = session().encode();
+        assert!(ScanSession::decode(7, &bytes[..86]).is_err());
+        // Cut off in the size policy
+        assert!(ScanSession::decode(7, &bytes[..bytes.len() - 4]).is_err());
+        let mut bytes = bytes;
+        bytes[0] = 2;
+        assert!(ScanSession::decode(7, &bytes).is_err());
+    }
+
+    #[test]
+    fn size_policy() {
+        let p = SizePolicy {
+            min_size: Some(10),
+            max_size: Some(20),
+            index_empty: false,
+        };
+        assert!(!p.allows(0));
+        assert!(!p.allows(9));
+        assert!(p.allows(10));
+        assert!(p.allows(20));
+        assert!(!p.allows(21));
+        assert!(SizePolicy { index_empty: true, ..p }.allows(0));
+    }
+}
@@ -0,0 +1,202 @@
+//! A redb storage backend that reads a database file without changing it.
+//!
+//! redb has no read-only open: opening a database writes to its header, and
+//! repairs it if it was not closed cleanly. Here such writes are kept in
+//! memory, page by page, on top of the file, which is only ever read. Nor
+//! is the file locked, so any number of readers can use it at once; the
+//! caller keeps writers away with the database lock file.
+
+use redb::StorageBackend;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{PoisonError, RwLock};
+
+const PAGE: u64 = 4096;
+
+#[derive(Debug)]
+pub struct SnapshotBackend {
+    file: File,
+    state: RwLock<Overlay>,
+}
+
+#[derive(Debug)]
+struct Overlay {
+    /// Length as redb sees it
+    len: u64,
+    /// How much of that is still read from the file
+    file_len: u64,
+    /// Pages written to, by page number
+    pages: HashMap<u64, Box<[u8]>>,
+}
+
+impl SnapshotBackend {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            file,
+            state: RwLock::new(Overlay {
+                len,
+                file_len: len,
+                pages: HashMap::new(),
+            }),
+        })
+    }
+
+    /// `buf.len()` bytes at `offset` of the file, as far as it is still
+    /// used, and zeros after that.
+    fn read_file(&self, file_len: u64, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = file_len.min(offset + buf.len() as u64);
+        let (from_file, zeros) = buf.split_at_mut(end.saturating_sub(offset) as usize);
+        self.file.read_exact_at(from_file, offset)?;
+        zeros.fill(0);
+        Ok(())
+    }
+}
+
+fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Index out-of-range.")
+}
+
+/// The pages in `offset..offset + len`, each with the part of it that is in
+/// the range (as offsets within the page) and where that part starts in the range.
+fn pages(offset: u64, len: usize) -> impl Iterator<Item = (u64, std::ops::Range<usize>, usize)> {
+    let end = offset + len as u64;
+    let first = offset / PAGE;
+    let last = end.div_ceil(PAGE);
+    (first..last).map(move |p| {
+        let start = (p * PAGE).max(offset);
+        let stop = ((p + 1) * PAGE).min(end);
+        let in_page = (start - p * PAGE) as usize..(stop - p * PAGE) as usize;
+        (p, in_page, (start - offset) as usize)
+    })
+}
+
+impl StorageBackend for SnapshotBackend {
+    fn len(&self) -> Result<u64, io::Error> {
+        Ok(self.state.read().unwrap_or_else(PoisonError::into_inner).len)
+    }
+
+    fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>, io::Error> {
+        let st = self.state.read().unwrap_or_else(PoisonError::into_inner);
+        if offset + len as u64 > st.len {
+            return Err(out_of_range());
+        }
+        let mut buf = vec![0u8; len];
+        self.read_file(st.file_len, offset, &mut buf)?;
+        if !st.pages.is_empty() {
+            for (p, in_page, at) in pages(offset, len) {
+                if let Some(page) = st.pages.get(&p) {
+                    buf[at..at + in_page.len()].copy_from_slice(&page[in_page]);
+                }
+            }
+        }
+        Ok(buf)
+    }
+
+    fn set_len(&self, len: u64) -> Result<(), io::Error> {
+        let mut st = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        if len < st.len {
+            // What is cut off reads as zeros if the length grows again
+            st.file_len = st.file_len.min(len);
+            st.pages.retain(|p, _| p * PAGE < len);
+            if let Some(page) = st.pages.get_mut(&(len / PAGE)) {
+                page[(len % PAGE) as usize..].fill(0);
+            }
+        }
+        st.len = len;
+        Ok(())
+    }
+
+    fn sync_data(&self, _: bool) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let mut st = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        if offset + data.len() as u64 > st.len {
+            return Err(out_of_range());
+        }
+        let file_len = st.file_len;
+        for (p, in_page, at) in pages(offset, data.len()) {
+            let page = match st.pages.entry(p) {
+                std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    let mut page = vec![0u8; PAGE as usize].into_boxed_slice();
+                    self.read_file(file_len, p * PAGE, &mut page)?;
+                    e.insert(page)
+                }
+            };
+            page[in_page.clone()].copy_from_slice(&data[at..at + in_page.len()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::TestDir;
+
+    fn backend(content: &[u8]) -> (TestDir, SnapshotBackend) {
+        let dir = TestDir::new();
+        let path = dir.path().join("f");
+        std::fs::write(&path, content).unwrap();
+        let b = SnapshotBackend::open(&path).unwrap();
+        (dir, b)
+    }
+
+    #[test]
+    fn writes_stay_in_memory() {
+        let content: Vec<u8> = (0..3 * PAGE as usize).map(|i| i as u8).collect();
+        let (dir, b) = backend(&content);
+
+        b.write(PAGE - 2, &[0xAA; 4]).unwrap();
+        let got = b.read(PAGE - 4, 8).unwrap();
+        assert_eq!(got, [content[4092], content[4093], 0xAA, 0xAA, 0xAA, 0xAA, content[4098], content[4099]]);
+        assert_eq!(std::fs::read(dir.path().join("f")).unwrap(), content);
+    }
+
+    #[test]
+    fn set_len_cuts_off_and_zero_fills() {
+        let content = vec![7u8; 2 * PAGE as usize];
+        let (_dir, b) = backend(&content);
+
+        b.write(10, &[1, 2, 3]).unwrap();
+        b.set_len(11).unwrap();
+        b.set_len(PAGE + 5).unwrap();
+        assert_eq!(b.len().unwrap(), PAGE + 5);
+        let got = b.read(8, 6).unwrap();
+        assert_eq!(got, [7, 7, 1, 0, 0, 0]);
+        assert_eq!(b.read(PAGE, 5).unwrap(), [0; 5]);
+        assert!(b.read(PAGE, 6).is_err());
+    }
+
+    #[test]
+    fn redb_database_opens_without_changing_the_file() {
+        let dir = TestDir::new();
+        let path = dir.path().join("db.redb");
+        const T: redb::TableDefinition<u64, u64> = redb::TableDefinition::new("t");
+        {
+            let db = redb::Database::create(&path).unwrap();
+            let tx = db.begin_write().unwrap();
+            tx.open_table(T).unwrap().insert(1, 2).unwrap();
+            tx.commit().unwrap();
+        }
+        let before = std::fs::read(&path).unwrap();
+
+        let db = redb::Database::builder()
+            .create_with_backend(SnapshotBackend::open(&path).unwrap())
+            .unwrap();
+        let tx = db.begin_read().unwrap();
+        assert_eq!(tx.open_table(T).unwrap().get(1).unwrap().unwrap().value(), 2);
+        drop(tx);
+        drop(db);
+
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+}
@@ -4,19 +4,31 @@ use crate::file_meta::{FileMeta, FileState};
 use crate::path_filter::PathFilter;
 use anyhow::{Context, Result};
 use redb::ReadableTable;
+use std::collections::HashSet;
 
 #[derive(Debug, Default, Clone)]
 pub struct Stats {
     // "Current" / listable files (Live)
     pub live_files: u64,
-    pub live_bytes: u64,
+    pub live_bytes: u64, // each physical file counted once
+    pub live_extra_links: u64, // Live names that are further hardlinks to another Live name
+    pub live_hash_pending: u64, // Live files recorded by a lazy scan without a hash
+    // Files with less allocated on disk than their size: sparse, compressed
+    // or stored inline
+    pub compact_files: u64,
+    pub compact_bytes: u64,     // their apparent size
+    pub compact_allocated: u64, // what they take up on disk
+    pub catalog_files: u64, // imported from manifests; not in the counts above
+    pub catalog_bytes: u64,
+    pub member_files: u64, // in archives (`scan --archives`); not in the live counts either
+    pub member_bytes: u64,
 
     // History counts (optional, but useful)
     pub total_versions: u64,
     pub replaced_versions: u64,
     pub missing_versions: u64,
 
-    // Exact duplicates among Live files
+    // Exact duplicates among Live files, counted by physical file (hardlinks collapsed)
     pub dupe_groups: u64,
     pub dupe_extra_files: u64, // sum(n-1) over groups
     pub dupe_bytes: u64,       // sum((n-1)*size) over groups
@@ -32,6 +44,8 @@ pub fn compute(db: &DbHandle) -> Result<Stats> {
         let file_meta = tx.open_table(crate::schema::FILE_META)?;
         let file_state = tx.open_table(crate::schema::FILE_STATE)?;
 
+        let mut seen_inodes: HashSet<(u64, u64)> = HashSet::new();
+
         for item in file_state.iter()? {
             let (k, v) = item?;
             let file_id = k.value();
@@ -48,8 +62,32 @@ pub fn compute(db: &DbHandle) -> Result<Stats> {
                     if let Some(blob) = file_meta.get(file_id)? {
                         let fm = FileMeta::decode(blob.value())
                         .with_context(|| format!("decode file_meta for file_id={file_id}"))?;
+                        if fm.catalog {
+                            out.catalog_files += 1;
+                            out.catalog_bytes = out.catalog_bytes.saturating_add(fm.size);
+                            continue;
+                        }
+                        if fm.member {
+                            out.member_files += 1;
+                            out.member_bytes = out.member_bytes.saturating_add(fm.size);
+                            continue;
+                        }
                         out.live_files += 1;
+                        if fm.hash256.is_none() {
+                            out.live_hash_pending += 1;
+                        }
+                        if let Some(i) = fm.inode
+                            && !seen_inodes.insert((i.dev, i.ino))
+                        {
+                            out.live_extra_links += 1;
+                            continue;
+                        }
                         out.live_bytes = out.live_bytes.saturating_add(fm.size);
+                        if let Some(a) = fm.allocated {
+                            out.compact_files += 1;
+                            out.compact_bytes = out.compact_bytes.saturating_add(fm.size);
+                            out.compact_allocated = out.compact_allocated.saturating_add(a);
+                        }
                     }
                 }
                 FileState::Replaced => out.replaced_versions += 1,
@@ -62,19 +100,25 @@ pub fn compute(db: &DbHandle) -> Result<Stats> {
     let filter = PathFilter::new(&[])?; // empty = match all
     let groups = dupe_groups::load_live_dupe_groups(db, &filter)?;
 
-    out.dupe_groups = groups.len() as u64;
-
     for g in &groups {
-        // entries are Live and >= 2 by construction
-        let n = g.entries.len() as u64;
-
-        // all entries in a hash256 group should have same size; take first
-        let size = g.entries.first().map(|e| e.size).unwrap_or(0);
+        // physical files are Live and >= 2 by construction; removing a
+        // hardlink frees nothing, so only whole files count. Copies in the
+        // catalog or in archives cannot be deleted, and do not let the last
+        // one go.
+        let n = g
+            .files
+            .iter()
+            .filter(|f| !f.links[0].catalog && !f.links[0].member)
+            .count() as u64;
+        if n < 2 {
+            continue;
+        }
 
+        out.dupe_groups += 1;
         out.dupe_extra_files += n - 1;
         out.dupe_bytes = out
         .dupe_bytes
-        .saturating_add((n - 1).saturating_mul(size));
+        .saturating_add((n - 1).saturating_mul(g.size));
     }
 
     Ok(out)
@@ -84,8 +128,32 @@ pub fn print(s: &Stats) {
     let unique_bytes = s.live_bytes.saturating_sub(s.dupe_bytes);
 
     println!("Current (live) files:      {}", s.live_files);
+    println!("Hardlinked names:          {}", s.live_extra_links);
+    println!("Hash pending:              {}", s.live_hash_pending);
     println!("Current total size:        {}", format_size(s.live_bytes));
     println!("Current unique size:       {}", format_size(unique_bytes));
+    if s.compact_files > 0 {
+        println!(
+            "Allocated below size:      {} ({} apparent, {} allocated)",
+            s.compact_files,
+            format_size(s.compact_bytes),
+            format_size(s.compact_allocated)
+        );
+    }
+    if s.catalog_files > 0 {
+        println!(
+            "Catalog entries:           {} ({})",
+            s.catalog_files,
+            format_size(s.catalog_bytes)
+        );
+    }
+    if s.member_files > 0 {
+        println!(
+            "Archive members:           {} ({})",
+            s.member_files,
+            format_size(s.member_bytes)
+        );
+    }
     println!();
 
     println!("Exact duplicate groups:    {}", s.dupe_groups);
@@ -1,3 +1,5 @@
+use chrono::{DateTime, Local, TimeZone};
+
 
 
 // Simple human-readable size (binary units)
@@ -85,15 +87,56 @@ pub fn parse_size(s: &str) -> Result<u64, String> {
 
 // Helper for range checks (inclusive bounds)
 pub fn size_in_range(size: u64, min: Option<u64>, max: Option<u64>) -> bool {
-    if let Some(minv) = min {
-        if size < minv {
-            return false;
-        }
+    if min.is_some_and(|minv| size < minv) {
+        return false;
     }
-    if let Some(maxv) = max {
-        if size > maxv {
-            return false;
-        }
+    if max.is_some_and(|maxv| size > maxv) {
+        return false;
     }
     true
 }
+
+/// Format seconds since the Unix epoch as local time.
+pub fn format_timestamp(secs: i64) -> String {
+    let epoch = Local
+        .timestamp_opt(0, 0)
+        .single()
+        .expect("Local epoch timestamp should be valid");
+
+    let dt: DateTime<Local> = Local
+        .timestamp_opt(secs, 0)
+        .single()
+        .unwrap_or(epoch);
+
+    dt.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// A directory under the system temp dir for one test, removed when dropped.
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new() -> Self {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "deldupes-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("create test dir");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
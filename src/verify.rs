@@ -0,0 +1,150 @@
+//! `verify`: read files back and compare them with the hash stored when
+//! they were scanned, to find content that changed while stat() did not
+//! (bit rot, bad sectors, firmware bugs).
+
+use crate::codec::systemtime_to_unix_secs;
+use crate::db::DbHandle;
+use crate::file_meta::FileStat;
+use crate::hashing;
+use crate::path_filter::PathFilter;
+use crate::path_utils;
+use crate::progress::{self, Progress, ProgressSample};
+use crate::util::{format_size, format_timestamp};
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Verified file_ids are written in batches of this many, so an interrupted
+/// run keeps most of its work.
+const VERIFIED_BATCH: usize = 1000;
+
+pub fn run_verify(db: &DbHandle, filter: &PathFilter, budget: Option<u64>) -> Result<()> {
+    // Files in archives are verified with their archive; files still
+    // waiting for a hash have nothing to compare with, and catalog entries
+    // have no file.
+    let mut files: Vec<_> = db
+        .live_by_last_verified()?
+        .into_iter()
+        .filter(|(_, e)| {
+            e.meta.hash256.is_some() && !e.meta.catalog && !e.meta.member && filter.matches(&e.path)
+        })
+        .collect();
+
+    if let Some(budget) = budget {
+        let mut total = 0u64;
+        let n = files
+            .iter()
+            .take_while(|(_, e)| {
+                let more = total < budget;
+                total = total.saturating_add(e.meta.size);
+                more
+            })
+            .count();
+        files.truncate(n);
+    }
+
+    let total_bytes: u64 = files.iter().map(|(_, e)| e.meta.size).sum();
+    let done = Arc::new(AtomicU64::new(0));
+    let done_bytes = Arc::new(AtomicU64::new(0));
+    let problems = Arc::new(AtomicU64::new(0));
+    let progress = {
+        let (done, done_bytes, problems) = (done.clone(), done_bytes.clone(), problems.clone());
+        let count = files.len() as u64;
+        Progress::start("verify", "verified", move || ProgressSample {
+            found: count,
+            queued: count,
+            queued_bytes: total_bytes,
+            done: done.load(Ordering::Relaxed),
+            done_bytes: done_bytes.load(Ordering::Relaxed),
+            errors: problems.load(Ordering::Relaxed),
+            read_limit: None,
+        })
+    };
+
+    let now = systemtime_to_unix_secs(SystemTime::now());
+    let mut ok_ids: Vec<u64> = Vec::new();
+    // Hardlinks are read once; (dev, ino) -> the hash read
+    let mut read_inodes: HashMap<(u64, u64), [u8; 32]> = HashMap::new();
+    let (mut verified, mut corrupt, mut changed, mut failed) = (0u64, 0u64, 0u64, 0u64);
+
+    for (last, e) in &files {
+        let path = path_utils::path_from_string(&e.path);
+        let expected = e.meta.hash256.unwrap_or_default();
+
+        let md = match std::fs::metadata(&path) {
+            Ok(md) => md,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                progress::suspend(|| println!("CHANGED {} (deleted since the last scan)", e.path));
+                changed += 1;
+                continue;
+            }
+            Err(err) => {
+                progress::suspend(|| println!("ERROR {}: {err}", e.path));
+                failed += 1;
+                problems.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let stat = FileStat::from_metadata(&md);
+        // Corruption changes nothing stat() can see
+        if !e.meta.matches_size_mtime(&stat) {
+            // A normal change; the next scan picks it up
+            progress::suspend(|| println!("CHANGED {} (modified since the last scan)", e.path));
+            changed += 1;
+            continue;
+        }
+
+        let actual = match read_inodes.get(&(stat.dev, stat.ino)) {
+            Some(h) => *h,
+            None => match hashing::hash_full_hash256(&path) {
+                Ok(h) => {
+                    read_inodes.insert((stat.dev, stat.ino), h);
+                    done_bytes.fetch_add(stat.size, Ordering::Relaxed);
+                    h
+                }
+                Err(err) => {
+                    progress::suspend(|| println!("ERROR {}: {err:#}", e.path));
+                    failed += 1;
+                    problems.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+            },
+        };
+        done.fetch_add(1, Ordering::Relaxed);
+
+        if actual == expected {
+            verified += 1;
+            ok_ids.push(e.file_id);
+            if ok_ids.len() >= VERIFIED_BATCH {
+                db.set_verified(&ok_ids, now)?;
+                ok_ids.clear();
+            }
+        } else {
+            progress::suspend(|| {
+                println!("CORRUPT {}", e.path);
+                println!("  expected {}", hex::encode(expected));
+                println!("  found    {}", hex::encode(actual));
+                let last = if *last == 0 { "never".to_string() } else { format_timestamp(*last as i64) };
+                println!("  last verified {last}");
+            });
+            corrupt += 1;
+            problems.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    if !ok_ids.is_empty() {
+        db.set_verified(&ok_ids, now)?;
+    }
+    drop(progress);
+
+    println!(
+        "Verified {verified} files ({}); {corrupt} corrupt, {changed} changed since the last scan, {failed} unreadable.",
+        format_size(done_bytes.load(Ordering::Relaxed))
+    );
+
+    if corrupt > 0 {
+        bail!("{corrupt} file(s) no longer match their hash");
+    }
+    Ok(())
+}
@@ -0,0 +1,502 @@
+//! Parallel directory traversal for `scan --walk-threads`.
+//!
+//! Worker threads read directories ahead of the scan, taking the
+//! directories found from a work-stealing queue. The entries are still
+//! handed to the scan one at a time, in the same order as a sequential
+//! walk sorted by file name, so checkpoints and resuming work as before.
+//! Only reading directories is done in parallel; on high-latency
+//! filesystems that is what the walk waits for.
+
+use anyhow::Result;
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std::fs::FileType;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// How many entries the workers may read before the scan gets to them.
+const READ_AHEAD: usize = 100_000;
+
+pub struct WalkEntry {
+    pub path: PathBuf,
+    pub file_type: FileType,
+    /// (dev, ino) of a directory
+    pub id: Option<(u64, u64)>,
+}
+
+/// A directory or entry that could not be read.
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+enum Node {
+    /// An entry, with the job that reads it if it is a directory to descend into
+    Entry(WalkEntry, Option<Arc<DirJob>>),
+    Error(WalkError),
+}
+
+/// Reading one directory. Whoever claims it first, a worker or the scan
+/// itself when it needs the entries, reads it.
+struct DirJob {
+    path: PathBuf,
+    /// The directory this one is in, for finding symlink loops
+    parent: Option<Arc<DirJob>>,
+    /// (dev, ino), when following symlinks
+    id: Option<(u64, u64)>,
+    claimed: AtomicBool,
+    nodes: Mutex<Option<Vec<Node>>>,
+    ready: Condvar,
+}
+
+impl DirJob {
+    fn new(path: PathBuf, parent: Option<Arc<DirJob>>, id: Option<(u64, u64)>) -> Arc<Self> {
+        Arc::new(Self {
+            path,
+            parent,
+            id,
+            claimed: AtomicBool::new(false),
+            nodes: Mutex::new(None),
+            ready: Condvar::new(),
+        })
+    }
+
+    /// This directory or one it is in, if it has the given (dev, ino).
+    fn ancestor_with(&self, id: (u64, u64)) -> Option<&Path> {
+        let mut job = Some(self);
+        while let Some(j) = job {
+            if j.id == Some(id) {
+                return Some(&j.path);
+            }
+            job = j.parent.as_deref();
+        }
+        None
+    }
+}
+
+struct Shared<'a, A> {
+    injector: Injector<Arc<DirJob>>,
+    stealers: Vec<Stealer<Arc<DirJob>>>,
+    follow_symlinks: bool,
+    accept: &'a A,
+    /// Entries read by the workers that the scan has not taken yet
+    ahead: Mutex<usize>,
+    room: Condvar,
+    /// How many times jobs were queued, for idle workers to wait on
+    pushes: Mutex<u64>,
+    pushed: Condvar,
+    stop: AtomicBool,
+}
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Walk `root` with `threads` workers reading directories, and pass each
+/// entry to `visit`, in the order of a depth-first walk sorted by file name.
+///
+/// `accept` is called from the workers for each entry with its path and
+/// whether it is a directory. Entries it returns false for are left out,
+/// and so is everything below them. It should only look at the entry
+/// itself, as the workers get to entries in no particular order. Anything
+/// that depends on what was walked before, like skipping a directory seen
+/// under another name, belongs in `visit`: it returns false to leave out
+/// everything below a directory. Symlinks are followed with
+/// `follow_symlinks`; `root` always is.
+///
+/// An error returned by `visit` stops the walk and is returned.
+pub fn walk_parallel<A>(
+    root: &Path,
+    threads: usize,
+    follow_symlinks: bool,
+    accept: &A,
+    mut visit: impl FnMut(Result<WalkEntry, WalkError>) -> Result<bool>,
+) -> Result<()>
+where
+    A: Fn(&Path, bool) -> bool + Sync,
+{
+    let workers: Vec<Worker<Arc<DirJob>>> = (0..threads.max(1)).map(|_| Worker::new_lifo()).collect();
+    let shared = Shared {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        follow_symlinks,
+        accept,
+        ahead: Mutex::new(0),
+        room: Condvar::new(),
+        pushes: Mutex::new(0),
+        pushed: Condvar::new(),
+        stop: AtomicBool::new(false),
+    };
+
+    let root_nodes = match std::fs::metadata(root) {
+        Ok(md) => {
+            let id = Some((md.dev(), md.ino()));
+            shared.entry_node(root.to_path_buf(), md.file_type(), None, id, |job| shared.injector.push(job))
+        }
+        Err(error) => Some(Node::Error(WalkError {
+            path: root.to_path_buf(),
+            error,
+        })),
+    };
+
+    thread::scope(|s| {
+        for worker in workers {
+            let shared = &shared;
+            s.spawn(move || shared.work(&worker));
+        }
+        let result = shared.consume(root_nodes.into_iter().collect(), &mut visit);
+        shared.finish();
+        result
+    })
+}
+
+impl<A> Shared<'_, A>
+where
+    A: Fn(&Path, bool) -> bool + Sync,
+{
+    /// Hand the entries to `visit` in walk order, waiting for directories
+    /// that are being read, and reading those nobody has started on.
+    fn consume(
+        &self,
+        root_nodes: Vec<Node>,
+        visit: &mut impl FnMut(Result<WalkEntry, WalkError>) -> Result<bool>,
+    ) -> Result<()> {
+        let mut stack = vec![root_nodes.into_iter()];
+        while let Some(top) = stack.last_mut() {
+            let Some(node) = top.next() else {
+                stack.pop();
+                continue;
+            };
+            match node {
+                Node::Error(e) => {
+                    visit(Err(e))?;
+                }
+                Node::Entry(entry, job) => {
+                    let descend = visit(Ok(entry))?;
+                    match job {
+                        Some(job) if descend => stack.push(self.take(&job).into_iter()),
+                        Some(job) => self.discard(job),
+                        None => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn take(&self, job: &Arc<DirJob>) -> Vec<Node> {
+        if !job.claimed.swap(true, Ordering::AcqRel) {
+            let mut spawned = false;
+            let nodes = self.read_dir(job, |child| {
+                self.injector.push(child);
+                spawned = true;
+            });
+            if spawned {
+                self.wake_workers();
+            }
+            return nodes;
+        }
+        self.wait_for(job)
+    }
+
+    /// Drop a directory the scan does not descend into, with what the
+    /// workers already read below it, so that does not count as read ahead.
+    fn discard(&self, job: Arc<DirJob>) {
+        let mut jobs = vec![job];
+        while let Some(job) = jobs.pop() {
+            // Not started, so the workers will pass it by
+            if !job.claimed.swap(true, Ordering::AcqRel) {
+                continue;
+            }
+            jobs.extend(self.wait_for(&job).into_iter().filter_map(|node| match node {
+                Node::Entry(_, job) => job,
+                Node::Error(_) => None,
+            }));
+        }
+    }
+
+    /// The entries of a directory a worker claimed, once it has read them.
+    fn wait_for(&self, job: &DirJob) -> Vec<Node> {
+        let mut nodes = lock(&job.nodes);
+        loop {
+            if let Some(nodes) = nodes.take() {
+                *lock(&self.ahead) -= nodes.len();
+                self.room.notify_all();
+                return nodes;
+            }
+            nodes = job.ready.wait(nodes).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Make the workers return. Jobs still queued are dropped.
+    fn finish(&self) {
+        // With the locks held, so a worker cannot miss it between checking and waiting
+        let _ahead = lock(&self.ahead);
+        let _pushes = lock(&self.pushes);
+        self.stop.store(true, Ordering::Relaxed);
+        self.room.notify_all();
+        self.pushed.notify_all();
+    }
+
+    /// Tell idle workers that jobs were queued.
+    fn wake_workers(&self) {
+        *lock(&self.pushes) += 1;
+        self.pushed.notify_all();
+    }
+
+    fn work(&self, local: &Worker<Arc<DirJob>>) {
+        while !self.stop.load(Ordering::Relaxed) {
+            let seen = *lock(&self.pushes);
+            let Some(job) = self.find_job(local) else {
+                // Jobs queued since `seen` may have been missed; otherwise
+                // sleep until there are some
+                let mut pushes = lock(&self.pushes);
+                while *pushes == seen && !self.stop.load(Ordering::Relaxed) {
+                    pushes = self.pushed.wait(pushes).unwrap_or_else(PoisonError::into_inner);
+                }
+                continue;
+            };
+            // Wait before claiming it, so the scan can read it itself if it
+            // needs it while the workers are too far ahead
+            if !self.wait_for_room() {
+                return;
+            }
+            if job.claimed.swap(true, Ordering::AcqRel) {
+                continue;
+            }
+
+            // The first entry is wanted first, so it goes on top
+            let mut children = Vec::new();
+            let nodes = self.read_dir(&job, |child| children.push(child));
+            if !children.is_empty() {
+                for child in children.into_iter().rev() {
+                    local.push(child);
+                }
+                self.wake_workers();
+            }
+
+            *lock(&self.ahead) += nodes.len();
+            *lock(&job.nodes) = Some(nodes);
+            job.ready.notify_all();
+        }
+    }
+
+    fn find_job(&self, local: &Worker<Arc<DirJob>>) -> Option<Arc<DirJob>> {
+        local.pop().or_else(|| {
+            std::iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect::<Steal<_>>())
+            })
+            .find(|s| !s.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    /// False if the walk is stopping.
+    fn wait_for_room(&self) -> bool {
+        let mut ahead = lock(&self.ahead);
+        while *ahead >= READ_AHEAD && !self.stop.load(Ordering::Relaxed) {
+            ahead = self.room.wait(ahead).unwrap_or_else(PoisonError::into_inner);
+        }
+        !self.stop.load(Ordering::Relaxed)
+    }
+
+    /// The accepted entries of `dir`, sorted by file name. Directories to
+    /// descend into are passed to `spawn` as new jobs.
+    fn read_dir(&self, job: &Arc<DirJob>, mut spawn: impl FnMut(Arc<DirJob>)) -> Vec<Node> {
+        let dir = &job.path;
+        let rd = match std::fs::read_dir(dir) {
+            Ok(rd) => rd,
+            Err(error) => {
+                return vec![Node::Error(WalkError {
+                    path: dir.to_path_buf(),
+                    error,
+                })];
+            }
+        };
+
+        let mut nodes = Vec::new();
+        let mut entries = Vec::new();
+        for e in rd {
+            match e {
+                Ok(e) => entries.push(e),
+                Err(error) => nodes.push(Node::Error(WalkError {
+                    path: dir.to_path_buf(),
+                    error,
+                })),
+            }
+        }
+        entries.sort_by_key(|e| e.file_name());
+
+        for e in entries {
+            let path = e.path();
+            // Directories are identified, for the scan and, following
+            // symlinks, to find loops
+            let stat = match e.file_type() {
+                Ok(ft) if ft.is_dir() || (self.follow_symlinks && ft.is_symlink()) => {
+                    std::fs::metadata(&path).map(|md| (md.file_type(), Some((md.dev(), md.ino()))))
+                }
+                other => other.map(|ft| (ft, None)),
+            };
+            let node = match stat {
+                Ok((ft, id)) => match id
+                    .filter(|_| self.follow_symlinks && ft.is_dir())
+                    .and_then(|id| job.ancestor_with(id))
+                {
+                    Some(ancestor) => {
+                        let error = io::Error::other(format!(
+                            "File system loop found: {} points to an ancestor {}",
+                            path.display(),
+                            ancestor.display()
+                        ));
+                        Some(Node::Error(WalkError { path, error }))
+                    }
+                    None => self.entry_node(path, ft, Some(job), id, &mut spawn),
+                },
+                Err(error) => Some(Node::Error(WalkError { path, error })),
+            };
+            nodes.extend(node);
+        }
+        nodes
+    }
+
+    fn entry_node(
+        &self,
+        path: PathBuf,
+        file_type: FileType,
+        parent: Option<&Arc<DirJob>>,
+        id: Option<(u64, u64)>,
+        spawn: impl FnOnce(Arc<DirJob>),
+    ) -> Option<Node> {
+        if !(self.accept)(&path, file_type.is_dir()) {
+            return None;
+        }
+        let id = id.filter(|_| file_type.is_dir());
+        let job = file_type.is_dir().then(|| {
+            let job = DirJob::new(path.clone(), parent.cloned(), id.filter(|_| self.follow_symlinks));
+            spawn(job.clone());
+            job
+        });
+        Some(Node::Entry(WalkEntry { path, file_type, id }, job))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use std::os::unix::fs::symlink;
+
+    /// What a walk yields: entry paths, and errors as `!` and their path.
+    fn walk(
+        root: &Path,
+        threads: usize,
+        follow_symlinks: bool,
+        accept: &(impl Fn(&Path, bool) -> bool + Sync),
+    ) -> Vec<String> {
+        let mut out = Vec::new();
+        walk_parallel(root, threads, follow_symlinks, accept, |r| {
+            match r {
+                Ok(e) => out.push(e.path.display().to_string()),
+                Err(e) => out.push(format!("!{}", e.path.display())),
+            }
+            Ok(true)
+        })
+        .unwrap();
+        out
+    }
+
+    fn walkdir_walk(root: &Path, follow_symlinks: bool) -> Vec<String> {
+        walkdir::WalkDir::new(root)
+            .follow_links(follow_symlinks)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|r| match r {
+                Ok(e) => e.path().display().to_string(),
+                Err(e) => format!("!{}", e.path().unwrap().display()),
+            })
+            .collect()
+    }
+
+    fn tree() -> TestDir {
+        let dir = TestDir::new();
+        for d in 0..6 {
+            for s in 0..4 {
+                let sub = dir.path().join(format!("d{d}/s{s}"));
+                std::fs::create_dir_all(&sub).unwrap();
+                for f in 0..5 {
+                    std::fs::write(sub.join(format!("f{f}")), b"x").unwrap();
+                }
+            }
+            std::fs::write(dir.path().join(format!("d{d}/top")), b"x").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn same_order_as_a_sorted_sequential_walk() {
+        let dir = tree();
+        let expected = walkdir_walk(dir.path(), false);
+        assert_eq!(expected.len(), 1 + 6 * (1 + 1 + 4 * 6) + 1);
+        for threads in [1, 2, 8] {
+            assert_eq!(walk(dir.path(), threads, false, &|_, _| true), expected);
+        }
+    }
+
+    #[test]
+    fn accept_and_visit_leave_out_what_is_below() {
+        let dir = tree();
+        let accept = |p: &Path, _: bool| !p.ends_with("d1");
+        let mut seen = Vec::new();
+        walk_parallel(dir.path(), 4, false, &accept, |r| {
+            let Ok(WalkEntry { path, .. }) = r else {
+                panic!("walk error");
+            };
+            let descend = !path.ends_with("s2");
+            seen.push(path);
+            Ok(descend)
+        })
+        .unwrap();
+        assert!(!seen.iter().any(|p| p.starts_with(dir.path().join("d1"))));
+        assert!(seen.contains(&dir.path().join("d0/s2")));
+        assert!(!seen.contains(&dir.path().join("d0/s2/f0")));
+        assert!(seen.contains(&dir.path().join("d0/s3/f0")));
+    }
+
+    #[test]
+    fn symlink_loops_are_errors() {
+        let dir = tree();
+        symlink(dir.path(), dir.path().join("d2/s1/up")).unwrap();
+        symlink(dir.path().join("d3"), dir.path().join("d4/d3")).unwrap();
+
+        let expected = walkdir_walk(dir.path(), true);
+        assert!(expected.contains(&format!("!{}", dir.path().join("d2/s1/up").display())));
+        for threads in [1, 4] {
+            assert_eq!(walk(dir.path(), threads, true, &|_, _| true), expected);
+        }
+        // Not followed, a symlink is just an entry
+        let expected = walkdir_walk(dir.path(), false);
+        assert!(expected.iter().all(|p| !p.starts_with('!')));
+        assert_eq!(walk(dir.path(), 4, false, &|_, _| true), expected);
+    }
+
+    #[test]
+    fn an_error_from_visit_stops_the_walk() {
+        let dir = tree();
+        let mut n = 0;
+        let result = walk_parallel(dir.path(), 4, false, &|_, _| true, |_| {
+            n += 1;
+            if n == 10 {
+                anyhow::bail!("stop");
+            }
+            Ok(true)
+        });
+        assert!(result.is_err());
+        assert_eq!(n, 10);
+    }
+}
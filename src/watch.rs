@@ -0,0 +1,265 @@
+//! Change notifications for `scan --watch`.
+//!
+//! Each scan root gets its own inotify instance, so a queue overflow only
+//! affects (and only forces a rescan of) the root it happened in.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What happened below a watched root.
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// A file was written, moved in or hardlinked in
+    FileChanged(PathBuf),
+    /// A file was deleted or moved away
+    FileRemoved(PathBuf),
+    /// A directory was created or moved in
+    DirAdded(PathBuf),
+    /// A directory was deleted or moved away
+    DirRemoved(PathBuf),
+    /// Events were lost; the whole root must be rescanned
+    Overflow,
+}
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_stop_signal(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+/// Make SIGINT and SIGTERM end watching cleanly instead of killing the process.
+pub fn install_stop_handler() {
+    let handler = on_stop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::Watcher;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::WatchEvent;
+    use anyhow::{Context, Result};
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// Files are picked up when closed after writing, not on every write.
+    const DIR_MASK: WatchMask = WatchMask::CLOSE_WRITE
+        .union(WatchMask::CREATE)
+        .union(WatchMask::DELETE)
+        .union(WatchMask::MOVED_FROM)
+        .union(WatchMask::MOVED_TO)
+        .union(WatchMask::ONLYDIR)
+        .union(WatchMask::DONT_FOLLOW)
+        .union(WatchMask::EXCL_UNLINK);
+
+    struct RootWatch {
+        inotify: Inotify,
+        dirs: HashMap<WatchDescriptor, PathBuf>,
+        /// Some directories could not be watched because of the watch limit
+        incomplete: bool,
+    }
+
+    /// inotify watches on the directories of the scan roots.
+    pub struct Watcher {
+        roots: Vec<RootWatch>,
+        buffer: Vec<u8>,
+    }
+
+    impl Watcher {
+        pub fn new(root_count: usize) -> Result<Self> {
+            let roots = (0..root_count)
+                .map(|_| {
+                    Ok(RootWatch {
+                        inotify: Inotify::init().context("inotify_init failed")?,
+                        dirs: HashMap::new(),
+                        incomplete: false,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(Self {
+                roots,
+                buffer: vec![0u8; 64 * 1024],
+            })
+        }
+
+        /// Start watching `dir`, which belongs to root number `root`.
+        pub fn watch_dir(&mut self, root: usize, dir: &Path) {
+            let rw = &mut self.roots[root];
+            match rw.inotify.watches().add(dir, DIR_MASK) {
+                Ok(wd) => {
+                    rw.dirs.insert(wd, dir.to_path_buf());
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    if !rw.incomplete {
+                        tracing::warn!(
+                            dir = %dir.display(),
+                            "inotify watch limit reached; changes under this root will be found by periodic rescans \
+                             (raise fs.inotify.max_user_watches to avoid this)"
+                        );
+                        rw.incomplete = true;
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!(dir = %dir.display(), "cannot watch directory: {e}");
+                }
+            }
+        }
+
+        /// Stop watching `dir` and everything below it.
+        pub fn unwatch_under(&mut self, root: usize, dir: &Path) {
+            let rw = &mut self.roots[root];
+            let gone: Vec<WatchDescriptor> = rw
+                .dirs
+                .iter()
+                .filter(|(_, p)| p.starts_with(dir))
+                .map(|(wd, _)| wd.clone())
+                .collect();
+
+            for wd in gone {
+                rw.dirs.remove(&wd);
+                // Fails if the kernel already dropped the watch
+                let _ = rw.inotify.watches().remove(wd);
+            }
+        }
+
+        /// True if the root has directories that are not watched.
+        pub fn is_incomplete(&self, root: usize) -> bool {
+            self.roots[root].incomplete
+        }
+
+        pub fn watched_dirs(&self) -> usize {
+            self.roots.iter().map(|r| r.dirs.len()).sum()
+        }
+
+        /// Wait up to `timeout` for changes. Returns (root, event) pairs.
+        pub fn wait(&mut self, timeout: Duration) -> Result<Vec<(usize, WatchEvent)>> {
+            let mut fds: Vec<libc::pollfd> = self
+                .roots
+                .iter()
+                .map(|r| libc::pollfd {
+                    fd: r.inotify.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                })
+                .collect();
+
+            // SAFETY: `fds` is a valid array of pollfd for the duration of the call.
+            let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    return Ok(Vec::new());
+                }
+                return Err(e).context("poll on inotify failed");
+            }
+
+            let mut out = Vec::new();
+            for (i, pfd) in fds.iter().enumerate() {
+                if pfd.revents & libc::POLLIN != 0 {
+                    self.read_root(i, &mut out)?;
+                }
+            }
+            Ok(out)
+        }
+
+        fn read_root(&mut self, root: usize, out: &mut Vec<(usize, WatchEvent)>) -> Result<()> {
+            let rw = &mut self.roots[root];
+            loop {
+                let events = match rw.inotify.read_events(&mut self.buffer) {
+                    Ok(ev) => ev,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e).context("reading inotify events failed"),
+                };
+
+                for ev in events {
+                    if ev.mask.contains(EventMask::Q_OVERFLOW) {
+                        out.push((root, WatchEvent::Overflow));
+                        continue;
+                    }
+                    if ev.mask.contains(EventMask::IGNORED) {
+                        rw.dirs.remove(&ev.wd);
+                        continue;
+                    }
+
+                    let (Some(dir), Some(name)) = (rw.dirs.get(&ev.wd), ev.name) else {
+                        continue;
+                    };
+                    let path = dir.join(name);
+                    let is_dir = ev.mask.contains(EventMask::ISDIR);
+
+                    let event = if ev.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                        if is_dir {
+                            WatchEvent::DirRemoved(path)
+                        } else {
+                            WatchEvent::FileRemoved(path)
+                        }
+                    } else if is_dir {
+                        WatchEvent::DirAdded(path)
+                    } else if ev.mask.contains(EventMask::CREATE) {
+                        // A new file is picked up when it is closed, unless
+                        // it is a new name for an existing file.
+                        match std::fs::symlink_metadata(&path) {
+                            Ok(md) if md.is_file() && md.nlink() > 1 => WatchEvent::FileChanged(path),
+                            _ => continue,
+                        }
+                    } else {
+                        WatchEvent::FileChanged(path)
+                    };
+                    out.push((root, event));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use other::Watcher;
+
+/// Placeholder for platforms without inotify.
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use super::WatchEvent;
+    use anyhow::{anyhow, Result};
+    use std::path::Path;
+    use std::time::Duration;
+
+    pub struct Watcher;
+
+    impl Watcher {
+        pub fn new(_root_count: usize) -> Result<Self> {
+            Err(anyhow!("scan --watch is only supported on Linux"))
+        }
+
+        pub fn watch_dir(&mut self, _root: usize, _dir: &Path) {}
+
+        pub fn unwatch_under(&mut self, _root: usize, _dir: &Path) {}
+
+        pub fn is_incomplete(&self, _root: usize) -> bool {
+            false
+        }
+
+        pub fn watched_dirs(&self) -> usize {
+            0
+        }
+
+        pub fn wait(&mut self, timeout: Duration) -> Result<Vec<(usize, WatchEvent)>> {
+            std::thread::sleep(timeout);
+            Ok(Vec::new())
+        }
+    }
+}